@@ -0,0 +1,6 @@
+-- Keep the spotify track id, a start offset for videos with an intro
+-- and whether the video was picked by hand
+ALTER TABLE songs
+    ADD COLUMN track_id varchar(255),
+    ADD COLUMN start_offset integer not null default 0,
+    ADD COLUMN manual_override boolean not null default false;
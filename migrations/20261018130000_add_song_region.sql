@@ -0,0 +1,4 @@
+-- Videos that youtube restricts to some regions are cached per region,
+-- rows without a region are played everywhere
+ALTER TABLE songs
+    ADD COLUMN region varchar(2);
@@ -0,0 +1,10 @@
+-- Videos are cached once per preference profile, `default` for users without preferences
+ALTER TABLE songs
+    ADD COLUMN profile varchar(64) not null default 'default';
+
+-- The kinds of videos each Spotify user prefers or excludes
+create table user_preferences (
+    spotify_user varchar(255) primary key,
+    prefer varchar(32),
+    exclude varchar(32)[] not null default '{}'
+);
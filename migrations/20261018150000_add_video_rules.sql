@@ -0,0 +1,16 @@
+-- The channel of the cached video, so songs can be purged when their channel is blocked
+ALTER TABLE songs
+    ADD COLUMN channel_id varchar(64);
+
+-- Youtube channels and videos blocked or allowed by an admin
+create table video_rules (
+    id uuid default uuid_generate_v4() primary key,
+    -- `channel` or `video`
+    kind varchar(16) not null,
+    -- the channel or video id
+    target varchar(64) not null,
+    -- `block` or `allow`
+    action varchar(8) not null,
+    note text,
+    unique (kind, target)
+);
@@ -0,0 +1,17 @@
+-- Every track played by a Spotify user, with the video that was sent for it
+create table history (
+    id uuid default uuid_generate_v4() primary key,
+    spotify_user varchar(255) not null,
+    track_id varchar(255),
+    title varchar(255) not null,
+    artist varchar(255) not null,
+    -- null when no video was found
+    youtube_id varchar(255),
+    started_at timestamptz not null default now(),
+    -- seconds the track was playing before the next one started
+    listened_secs integer not null default 0,
+    -- whether the user replaced the video that was sent
+    corrected boolean not null default false
+);
+
+create index history_user_started_at on history (spotify_user, started_at desc);
@@ -0,0 +1,4 @@
+-- Where the video of a play was found: `cache`, `youtube` or the url of a fallback instance.
+-- Null when no video was found, and for plays recorded before it was stored
+ALTER TABLE history
+    ADD COLUMN source varchar(255);
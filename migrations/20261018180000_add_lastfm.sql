@@ -0,0 +1,24 @@
+-- The Last.fm account of each Spotify user who allowed scrobbling
+create table lastfm_sessions (
+    spotify_user varchar(255) primary key,
+    username varchar(255) not null,
+    -- the session key encrypted with `lastfm.encryption_key`, prefixed by its nonce
+    session_key bytea not null
+);
+
+-- Scrobbles that failed and are retried later
+create table scrobble_queue (
+    id uuid default uuid_generate_v4() primary key,
+    spotify_user varchar(255) not null references lastfm_sessions on delete cascade,
+    artist varchar(255) not null,
+    track varchar(255) not null,
+    album varchar(255),
+    -- seconds
+    duration integer,
+    played_at timestamptz not null,
+    attempts integer not null default 1,
+    next_attempt_at timestamptz not null,
+    last_error text
+);
+
+create index scrobble_queue_next_attempt_at on scrobble_queue (next_attempt_at);
@@ -0,0 +1,78 @@
+//! Liveness, readiness and metrics endpoints, these don't need the admin token.
+
+use std::{sync::Arc, time::Duration};
+
+use serde_json::json;
+use sqlx::PgPool;
+use tracing::warn;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+use crate::{
+    db::config::Config,
+    metrics::{self, ServiceHealth, SPOTIFY_HEALTH, YOUTUBE_HEALTH},
+};
+
+/// Returns the `/healthz`, `/readyz` and `/metrics` routes.
+pub fn routes(
+    pool: Arc<PgPool>,
+    config: &Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let window = Duration::from_secs(config.server.readiness_window_secs);
+
+    let healthz = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| reply::json(&json!({ "status": "ok" })));
+    let readyz = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(move |pool| readiness(pool, window));
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| {
+            reply::with_header(
+                metrics::gather(),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        });
+
+    healthz.or(readyz).or(metrics)
+}
+
+/// Ready when the database answers and no video provider has answered a search
+/// for longer than `server.readiness_window_secs`, a used up quota is not a failure.
+/// Spotify is only reported, its calls are made with the token of each listener
+/// so one failing account says nothing about the others.
+async fn readiness(pool: Arc<PgPool>, window: Duration) -> Result<impl Reply, Rejection> {
+    let database = match sqlx::query("SELECT 1").execute(&*pool).await {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Readiness check failed to query the database: {e}");
+            false
+        }
+    };
+    let ready = database && YOUTUBE_HEALTH.is_healthy(window) != Some(false);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = json!({
+        "ready": ready,
+        "database": if database { "ok" } else { "failing" },
+        "spotify": describe(&SPOTIFY_HEALTH, window),
+        "youtube": describe(&YOUTUBE_HEALTH, window),
+    });
+    Ok(reply::with_status(reply::json(&body), status))
+}
+
+fn describe(health: &ServiceHealth, window: Duration) -> &'static str {
+    match health.is_healthy(window) {
+        None => "unknown",
+        Some(true) => "ok",
+        Some(false) => "failing",
+    }
+}
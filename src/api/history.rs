@@ -0,0 +1,66 @@
+//! `/api/history` route listing the tracks played by each user,
+//! behind the same admin token as `/api/songs`.
+
+use serde::{Deserialize, Serialize};
+use warp::{
+    reply::{self, Reply},
+    Filter, Rejection,
+};
+
+use super::{authorized, internal, Pagination};
+use crate::db::{
+    config::Config,
+    history::{HistoryRepository, Play},
+};
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// The Spotify user id, every user's plays are listed when unset
+    user: Option<String>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlayPage {
+    items: Vec<Play>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+/// Returns the `/api/history` route.
+pub fn routes(
+    repo: HistoryRepository,
+    config: &Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(authorized(config.server.admin_token.clone()))
+        .and(warp::any().map(move || repo.clone()))
+        .and(warp::query::<HistoryQuery>())
+        .and_then(list_plays)
+}
+
+async fn list_plays(repo: HistoryRepository, query: HistoryQuery) -> Result<impl Reply, Rejection> {
+    let Pagination {
+        page,
+        per_page,
+        offset,
+    } = Pagination::new(query.page, query.per_page)?;
+    let user = query.user.as_deref();
+
+    let items = repo
+        .recent(user, per_page, offset)
+        .await
+        .map_err(internal)?;
+    let total = repo.count(user).await.map_err(internal)?;
+    Ok(reply::json(&PlayPage {
+        items,
+        page,
+        per_page,
+        total,
+    }))
+}
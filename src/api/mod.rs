@@ -0,0 +1,285 @@
+//! HTTP endpoints served next to the websocket.
+//!
+//! The `/api/songs` routes for browsing and editing the song cache, the `/api/rules` routes,
+//! and the `/api/history` and `/api/stats` routes expect an
+//! `Authorization: Bearer <server.admin_token>` header,
+//! they reject all requests while no admin token is configured.
+
+pub mod health;
+pub mod history;
+pub mod rules;
+pub mod stats;
+
+use std::{convert::Infallible, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, warn};
+use uuid::Uuid;
+use warp::{
+    http::StatusCode,
+    reject::{self, Reject},
+    reply::{self, Reply},
+    Filter, Rejection,
+};
+
+use crate::db::{
+    config::{Config, Secret},
+    songs::{SongFilter, SongRepository},
+    Songs,
+};
+
+const DEFAULT_PER_PAGE: i64 = 50;
+const MAX_PER_PAGE: i64 = 500;
+
+#[derive(Debug)]
+struct Unauthorized;
+
+#[derive(Debug)]
+struct NotFound;
+
+/// A request that failed because of the database.
+#[derive(Debug)]
+struct Internal;
+
+/// A page too far for its offset to be counted, answered with 400 by [`handle_rejection`]
+#[derive(Debug)]
+struct PageOutOfRange;
+
+impl Reject for Unauthorized {}
+impl Reject for NotFound {}
+impl Reject for Internal {}
+impl Reject for PageOutOfRange {}
+
+/// A page of a listing, from the `page` and `per_page` query parameters.
+#[derive(Debug, PartialEq, Eq)]
+struct Pagination {
+    /// Starts at 1
+    page: i64,
+    per_page: i64,
+    /// Rows skipped before the page
+    offset: i64,
+}
+
+impl Pagination {
+    /// Pages before the first one are the first one, `per_page` is kept within `1..=MAX_PER_PAGE`.
+    /// # Errors
+    /// Rejects a page whose offset overflows with [`PageOutOfRange`].
+    fn new(page: Option<i64>, per_page: Option<i64>) -> Result<Self, Rejection> {
+        let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+        let page = page.unwrap_or(1).max(1);
+        let offset = (page - 1)
+            .checked_mul(per_page)
+            .ok_or_else(|| reject::custom(PageOutOfRange))?;
+        Ok(Self {
+            page,
+            per_page,
+            offset,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    page: Option<i64>,
+    per_page: Option<i64>,
+    #[serde(flatten)]
+    filter: SongFilter,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteQuery {
+    artist: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplaceVideo {
+    youtube_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SongPage {
+    items: Vec<Songs>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+/// Returns the `/api/songs` routes.
+pub fn routes(
+    repo: SongRepository,
+    config: &Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let songs = warp::path("api")
+        .and(warp::path("songs"))
+        .and(authorized(config.server.admin_token.clone()))
+        .and(warp::any().map(move || repo.clone()));
+
+    let list = songs
+        .clone()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<ListQuery>())
+        .and_then(list_songs);
+    let get = songs
+        .clone()
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_song);
+    let replace = songs
+        .clone()
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json::<ReplaceVideo>())
+        .and_then(replace_video);
+    let delete = songs
+        .clone()
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and_then(delete_song);
+    let delete_artist = songs
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(warp::query::<DeleteQuery>())
+        .and_then(delete_artist);
+
+    list.or(get).or(replace).or(delete).or(delete_artist)
+}
+
+/// Rejects requests that don't carry the admin token.
+fn authorized(token: Secret) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let token = Arc::new(token);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let given = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+                match given {
+                    Some(given) if !token.is_empty() && given == token.expose() => Ok(()),
+                    _ => Err(reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn list_songs(repo: SongRepository, query: ListQuery) -> Result<impl Reply, Rejection> {
+    let Pagination {
+        page,
+        per_page,
+        offset,
+    } = Pagination::new(query.page, query.per_page)?;
+
+    let items = repo
+        .search(&query.filter, per_page, offset)
+        .await
+        .map_err(internal)?;
+    let total = repo.count(&query.filter).await.map_err(internal)?;
+    Ok(reply::json(&SongPage {
+        items,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+async fn get_song(repo: SongRepository, id: Uuid) -> Result<impl Reply, Rejection> {
+    let song = repo.find(id).await.map_err(internal)?;
+    song.map_or_else(
+        || Err(reject::custom(NotFound)),
+        |song| Ok(reply::json(&song)),
+    )
+}
+
+async fn replace_video(
+    repo: SongRepository,
+    id: Uuid,
+    body: ReplaceVideo,
+) -> Result<impl Reply, Rejection> {
+    let song = repo
+        .set_youtube_id(id, body.youtube_id.trim())
+        .await
+        .map_err(internal)?;
+    song.map_or_else(
+        || Err(reject::custom(NotFound)),
+        |song| Ok(reply::json(&song)),
+    )
+}
+
+async fn delete_song(repo: SongRepository, id: Uuid) -> Result<impl Reply, Rejection> {
+    if repo.delete(id).await.map_err(internal)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(reject::custom(NotFound))
+    }
+}
+
+async fn delete_artist(repo: SongRepository, query: DeleteQuery) -> Result<impl Reply, Rejection> {
+    let deleted = repo
+        .delete_by_artist(&query.artist)
+        .await
+        .map_err(internal)?;
+    Ok(reply::json(&json!({ "deleted": deleted })))
+}
+
+fn internal(e: spotify_music_vid::Error) -> Rejection {
+    error!("Admin api request failed: {e}");
+    reject::custom(Internal)
+}
+
+/// Turns the api rejections into JSON error responses.
+/// # Errors
+/// Never fails, the [`Infallible`] error is required by [`warp::Filter::recover`].
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, message) = if err.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "Missing or invalid admin token")
+    } else if err.is_not_found() || err.find::<NotFound>().is_some() {
+        (StatusCode::NOT_FOUND, "Not found")
+    } else if err.find::<PageOutOfRange>().is_some() {
+        (StatusCode::BAD_REQUEST, "Page out of range")
+    } else if err.find::<Internal>().is_some() {
+        (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    } else if err.find::<reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+    } else {
+        warn!("Rejected request: {err:?}");
+        (StatusCode::BAD_REQUEST, "Bad request")
+    };
+    let body = reply::json(&json!({ "error": message }));
+    Ok(reply::with_status(body, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_start_at_one_and_are_clamped() {
+        assert_eq!(
+            Pagination::new(None, None).unwrap(),
+            Pagination {
+                page: 1,
+                per_page: DEFAULT_PER_PAGE,
+                offset: 0
+            }
+        );
+        assert_eq!(
+            Pagination::new(Some(-3), Some(10_000)).unwrap(),
+            Pagination {
+                page: 1,
+                per_page: MAX_PER_PAGE,
+                offset: 0
+            }
+        );
+        assert_eq!(Pagination::new(Some(3), Some(20)).unwrap().offset, 40);
+    }
+
+    #[test]
+    fn pages_past_the_last_offset_are_rejected() {
+        let err = Pagination::new(Some(i64::MAX), Some(2)).unwrap_err();
+        assert!(err.find::<PageOutOfRange>().is_some());
+    }
+}
@@ -0,0 +1,141 @@
+//! `/api/rules` routes for blocking and allowing youtube channels and videos,
+//! behind the same admin token as `/api/songs`.
+
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use warp::{
+    http::StatusCode,
+    reject::{self, Reject},
+    reply::{self, Reply},
+    Filter, Rejection,
+};
+
+use super::{authorized, internal, NotFound};
+use crate::{
+    db::{
+        config::Config,
+        rules::{RuleAction, RuleKind, RuleRepository},
+        songs::SongRepository,
+    },
+    youtube_client::YoutubeClient,
+};
+
+/// A rule without a target, answered with 400 by [`super::handle_rejection`]
+#[derive(Debug)]
+struct EmptyTarget;
+
+impl Reject for EmptyTarget {}
+
+#[derive(Debug, Deserialize)]
+struct NewRule {
+    kind: RuleKind,
+    target: String,
+    action: RuleAction,
+    note: Option<String>,
+    /// Also delete the cached songs pointing to a blocked channel or video
+    #[serde(default)]
+    purge: bool,
+}
+
+/// The repositories and the client whose rules are reloaded after every change.
+#[derive(Clone)]
+struct State {
+    rules: RuleRepository,
+    songs: SongRepository,
+    yt_client: YoutubeClient,
+}
+
+/// Returns the `/api/rules` routes.
+pub fn routes(
+    rules: RuleRepository,
+    songs: SongRepository,
+    yt_client: YoutubeClient,
+    config: &Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let state = State {
+        rules,
+        songs,
+        yt_client,
+    };
+    let rules = warp::path("api")
+        .and(warp::path("rules"))
+        .and(authorized(config.server.admin_token.clone()))
+        .and(warp::any().map(move || state.clone()));
+
+    let list = rules
+        .clone()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(list_rules);
+    let create = rules
+        .clone()
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json::<NewRule>())
+        .and_then(create_rule);
+    let delete = rules
+        .and(warp::path::param::<Uuid>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and_then(delete_rule);
+
+    list.or(create).or(delete)
+}
+
+async fn list_rules(state: State) -> Result<impl Reply, Rejection> {
+    let rules = state.rules.list().await.map_err(internal)?;
+    Ok(reply::json(&rules))
+}
+
+async fn create_rule(state: State, body: NewRule) -> Result<impl Reply, Rejection> {
+    let target = body.target.trim();
+    if target.is_empty() {
+        return Err(reject::custom(EmptyTarget));
+    }
+    let rule = state
+        .rules
+        .set(body.kind, target, body.action, body.note.as_deref())
+        .await
+        .map_err(internal)?;
+    let purged = if body.purge && body.action == RuleAction::Block {
+        if body.kind == RuleKind::Channel {
+            resolve_channels(&state).await;
+        }
+        state
+            .songs
+            .delete_by_rule(body.kind, target)
+            .await
+            .map_err(internal)?
+    } else {
+        0
+    };
+    info!(?rule, purged, "Rule set");
+    reload(&state).await;
+    let body = reply::json(&json!({ "rule": rule, "purged": purged }));
+    Ok(reply::with_status(body, StatusCode::CREATED))
+}
+
+async fn delete_rule(state: State, id: Uuid) -> Result<impl Reply, Rejection> {
+    if !state.rules.delete(id).await.map_err(internal)? {
+        return Err(reject::custom(NotFound));
+    }
+    reload(&state).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Looks up the channel of the cached videos whose channel is unknown before a channel is purged,
+/// the ones that can't be looked up are kept.
+async fn resolve_channels(state: &State) {
+    if let Err(e) = state.yt_client.resolve_channels(&state.songs).await {
+        warn!("Failed to resolve the channels of cached videos, some may not be purged: {e}");
+    }
+}
+
+/// Applies the changed rules to the next searches, the periodic reload retries on failure.
+async fn reload(state: &State) {
+    if let Err(e) = state.yt_client.reload_rules(&state.rules).await {
+        error!("Failed to reload the rules: {e}");
+    }
+}
@@ -0,0 +1,46 @@
+//! `/api/stats` route reporting what users listen to and how well their videos are matched,
+//! behind the same admin token as `/api/songs`.
+
+use serde::Deserialize;
+use warp::{
+    reply::{self, Reply},
+    Filter, Rejection,
+};
+
+use super::{authorized, internal};
+use crate::db::{config::Config, history::HistoryRepository};
+
+const DEFAULT_LIMIT: i64 = 10;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct StatsQuery {
+    /// The Spotify user id, the statistics of every user are returned when unset
+    user: Option<String>,
+    /// The length of the rankings
+    limit: Option<i64>,
+}
+
+/// Returns the `/api/stats` route.
+pub fn routes(
+    repo: HistoryRepository,
+    config: &Config,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("api")
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(authorized(config.server.admin_token.clone()))
+        .and(warp::any().map(move || repo.clone()))
+        .and(warp::query::<StatsQuery>())
+        .and_then(get_stats)
+}
+
+async fn get_stats(repo: HistoryRepository, query: StatsQuery) -> Result<impl Reply, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let stats = repo
+        .stats(query.user.as_deref(), limit)
+        .await
+        .map_err(internal)?;
+    Ok(reply::json(&stats))
+}
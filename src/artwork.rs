@@ -0,0 +1,188 @@
+//! Colours of the album artwork, sent to clients when there is no video to show.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use image::{imageops::FilterType, DynamicImage};
+use reqwest::Client;
+use rspotify::model::Image;
+use spotify_music_vid::protocol::Palette;
+use tracing::{instrument, warn};
+
+/// Artwork is scaled down to this many pixels per side before its colours are counted
+const SAMPLE_SIZE: u32 = 32;
+
+/// The download happens before the client is told there is no video, so it can't take long
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Larger artwork is not downloaded, the smallest Spotify covers are a few kilobytes
+const MAX_ARTWORK_BYTES: usize = 1024 * 1024;
+
+/// Returns the client to download artwork with, giving up after [`DOWNLOAD_TIMEOUT`].
+pub fn client() -> Client {
+    Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Returns the colours of the smallest image of `artwork`.
+/// Returns `None` if there is no artwork, or if it can not be downloaded in time or decoded,
+/// or is larger than [`MAX_ARTWORK_BYTES`].
+#[instrument(skip_all)]
+pub async fn palette(client: &Client, artwork: &[Image]) -> Option<Palette> {
+    let image = artwork
+        .iter()
+        .min_by_key(|image| image.width.unwrap_or(u32::MAX))?;
+    let bytes = match download(client, &image.url).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => {
+            warn!("The artwork is larger than {MAX_ARTWORK_BYTES} bytes, not downloading it");
+            return None;
+        }
+        Err(e) => {
+            warn!("Failed to download the artwork: {e}");
+            return None;
+        }
+    };
+    match image::load_from_memory(&bytes) {
+        Ok(image) => Some(extract(&image)),
+        Err(e) => {
+            warn!("Failed to decode the artwork: {e}");
+            None
+        }
+    }
+}
+
+/// Downloads `url`, returning `None` as soon as it turns out larger than [`MAX_ARTWORK_BYTES`].
+/// # Errors
+/// This function will return an error if the request fails or times out.
+async fn download(client: &Client, url: &str) -> reqwest::Result<Option<Vec<u8>>> {
+    let mut res = client.get(url).send().await?.error_for_status()?;
+    let announced = res.content_length().unwrap_or_default();
+    if usize::try_from(announced).map_or(true, |length| length > MAX_ARTWORK_BYTES) {
+        return Ok(None);
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if bytes.len() + chunk.len() > MAX_ARTWORK_BYTES {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+/// Counts the colours of an image, similar shades are counted as one colour.
+fn extract(image: &DynamicImage) -> Palette {
+    let sample = image
+        .resize_exact(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle)
+        .to_rgb8();
+    // pixels by their 3 most significant bits per channel, with the sum of their channels
+    let mut shades: BTreeMap<[u8; 3], (u64, [u64; 3])> = BTreeMap::new();
+    let mut total = [0; 3];
+    for pixel in sample.pixels() {
+        let (count, sums) = shades
+            .entry(pixel.0.map(|channel| channel >> 5))
+            .or_default();
+        *count += 1;
+        for (i, channel) in pixel.0.into_iter().enumerate() {
+            sums[i] += u64::from(channel);
+            total[i] += u64::from(channel);
+        }
+    }
+
+    let pixels = u64::from(SAMPLE_SIZE * SAMPLE_SIZE);
+    let average = total.map(|sum| sum / pixels);
+    let dominant = shades
+        .values()
+        .max_by_key(|(count, _)| *count)
+        .map_or(average, |(count, sums)| sums.map(|sum| sum / count));
+    Palette {
+        dominant: hex(dominant),
+        average: hex(average),
+        text: text_color(dominant).to_string(),
+    }
+}
+
+fn hex([r, g, b]: [u64; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Returns black on light colours and white on dark ones.
+fn text_color([r, g, b]: [u64; 3]) -> &'static str {
+    let luma = (299 * r + 587 * g + 114 * b) / 1000;
+    if luma > 150 {
+        "#000000"
+    } else {
+        "#ffffff"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+    use warp::Filter;
+
+    use super::*;
+
+    fn split_image(top: [u8; 3], bottom: [u8; 3], top_rows: u32) -> DynamicImage {
+        let image = RgbImage::from_fn(64, 64, |_, y| Rgb(if y < top_rows { top } else { bottom }));
+        DynamicImage::ImageRgb8(image)
+    }
+
+    /// Serves `body` on a local port and returns the artwork pointing to it.
+    fn serve(body: Vec<u8>) -> Vec<Image> {
+        let route = warp::path("cover").map(move || body.clone());
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        vec![Image {
+            url: format!("http://{addr}/cover"),
+            width: Some(64),
+            height: Some(64),
+        }]
+    }
+
+    #[test]
+    fn a_plain_image_is_its_only_colour() {
+        let palette = extract(&split_image([0x1d, 0x3b, 0x5a], [0x1d, 0x3b, 0x5a], 0));
+        assert_eq!(palette.dominant, "#1d3b5a");
+        assert_eq!(palette.average, "#1d3b5a");
+        assert_eq!(palette.text, "#ffffff");
+    }
+
+    #[test]
+    fn the_most_common_shade_dominates() {
+        // three quarters red, one quarter white
+        let palette = extract(&split_image([255, 255, 255], [200, 0, 0], 16));
+        assert_eq!(palette.dominant, "#c80000");
+        assert_eq!(palette.average, "#d53f3f");
+        assert_eq!(palette.text, "#ffffff");
+    }
+
+    #[test]
+    fn text_is_readable_on_the_dominant_colour() {
+        assert_eq!(text_color([255, 255, 255]), "#000000");
+        assert_eq!(text_color([255, 255, 0]), "#000000");
+        assert_eq!(text_color([0, 0, 0]), "#ffffff");
+        assert_eq!(text_color([0, 0, 255]), "#ffffff");
+    }
+
+    #[tokio::test]
+    async fn downloads_and_reads_the_artwork() {
+        let mut png = Vec::new();
+        split_image([255, 255, 255], [255, 255, 255], 0)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+        let palette = palette(&client(), &serve(png)).await.unwrap();
+        assert_eq!(palette.dominant, "#ffffff");
+        assert_eq!(palette.text, "#000000");
+    }
+
+    #[tokio::test]
+    async fn large_artwork_is_not_downloaded() {
+        let artwork = serve(vec![0; MAX_ARTWORK_BYTES + 1]);
+        assert_eq!(palette(&client(), &artwork).await, None);
+    }
+}
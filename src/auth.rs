@@ -0,0 +1,218 @@
+//! The Spotify clients talking to a configurable accounts service.
+//!
+//! rspotify only requests tokens from the production accounts service, so [`SpotifyAuth`]
+//! wraps its [`AuthCodeSpotify`] and sends the authorization, the code exchange and the token
+//! refreshes to the configured one instead, as [`SpotifyCreds`] does for the client credentials
+//! flow of [`ClientCredsSpotify`]. Every other request goes through rspotify as is.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::Url;
+use rspotify::{
+    http::{Form, Headers, HttpClient},
+    prelude::{BaseClient, OAuthClient},
+    sync::Mutex,
+    AuthCodeSpotify, ClientCredsSpotify, ClientResult, Config, Credentials, OAuth, Token,
+};
+
+/// An [`AuthCodeSpotify`] whose tokens come from the accounts service at `accounts_url`.
+#[derive(Clone, Debug, Default)]
+pub struct SpotifyAuth {
+    inner: AuthCodeSpotify,
+    /// Root of the accounts service, without a trailing slash
+    accounts_url: String,
+}
+
+impl SpotifyAuth {
+    /// Creates the client, users authorize the app on the accounts service at `accounts_url`.
+    #[must_use]
+    pub fn new(creds: Credentials, oauth: OAuth, config: Config, accounts_url: &str) -> Self {
+        Self {
+            inner: AuthCodeSpotify::with_config(creds, oauth, config),
+            accounts_url: accounts_url.trim().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns the url where the user authorizes the app, always asking them to confirm.
+    /// # Errors
+    /// Returns an error if the accounts url is not a valid url.
+    pub fn authorize_url(&self) -> ClientResult<String> {
+        let scopes = self.scopes();
+        let params = [
+            ("client_id", self.inner.creds.id.as_str()),
+            ("response_type", "code"),
+            ("redirect_uri", self.inner.oauth.redirect_uri.as_str()),
+            ("scope", scopes.as_str()),
+            ("state", self.inner.oauth.state.as_str()),
+            ("show_dialog", "true"),
+        ];
+        let url = Url::parse_with_params(&format!("{}/authorize", self.accounts_url), params)?;
+        Ok(url.into())
+    }
+
+    fn scopes(&self) -> String {
+        let mut scopes: Vec<&str> = self.inner.oauth.scopes.iter().map(String::as_str).collect();
+        scopes.sort_unstable();
+        scopes.join(" ")
+    }
+}
+
+#[async_trait]
+impl BaseClient for SpotifyAuth {
+    fn get_config(&self) -> &Config {
+        self.inner.get_config()
+    }
+
+    fn get_http(&self) -> &HttpClient {
+        self.inner.get_http()
+    }
+
+    fn get_creds(&self) -> &Credentials {
+        self.inner.get_creds()
+    }
+
+    fn get_token(&self) -> Arc<Mutex<Option<Token>>> {
+        self.inner.get_token()
+    }
+
+    /// Exchanges the refresh token for a new access token, `None` without a refresh token.
+    async fn refetch_token(&self) -> ClientResult<Option<Token>> {
+        let token = self.get_token();
+        let refresh_token = match token.lock().await {
+            Ok(token) => token.as_ref().and_then(|token| token.refresh_token.clone()),
+            Err(_) => None,
+        };
+        let Some(refresh_token) = refresh_token else {
+            return Ok(None);
+        };
+        let data = HashMap::from([
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+        ]);
+        let headers = self.get_creds().auth_headers();
+        let mut token = self.fetch_access_token(&data, headers.as_ref()).await?;
+        token.refresh_token = Some(refresh_token);
+        Ok(Some(token))
+    }
+
+    /// Requests a token from the configured accounts service.
+    async fn fetch_access_token(
+        &self,
+        payload: &Form<'_>,
+        headers: Option<&Headers>,
+    ) -> ClientResult<Token> {
+        fetch_token(self, &self.accounts_url, payload, headers).await
+    }
+}
+
+#[async_trait]
+impl OAuthClient for SpotifyAuth {
+    fn get_oauth(&self) -> &OAuth {
+        &self.inner.oauth
+    }
+
+    /// Exchanges the code the user was redirected with for a token, kept by the client.
+    async fn request_token(&self, code: &str) -> ClientResult<()> {
+        let scopes = self.scopes();
+        let data = HashMap::from([
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", self.inner.oauth.redirect_uri.as_str()),
+            ("code", code),
+            ("scope", scopes.as_str()),
+            ("state", self.inner.oauth.state.as_str()),
+        ]);
+        let headers = self.get_creds().auth_headers();
+        let token = self.fetch_access_token(&data, headers.as_ref()).await?;
+        // the lock of the async client can't be poisoned
+        if let Ok(mut current) = self.get_token().lock().await {
+            *current = Some(token);
+        }
+        self.write_token_cache().await
+    }
+}
+
+/// A [`ClientCredsSpotify`] whose tokens come from the accounts service at `accounts_url`.
+#[derive(Clone, Debug, Default)]
+pub struct SpotifyCreds {
+    inner: ClientCredsSpotify,
+    /// Root of the accounts service, without a trailing slash
+    accounts_url: String,
+}
+
+impl SpotifyCreds {
+    /// Creates the client, the app authenticates on the accounts service at `accounts_url`.
+    #[must_use]
+    pub fn new(creds: Credentials, config: Config, accounts_url: &str) -> Self {
+        Self {
+            inner: ClientCredsSpotify::with_config(creds, config),
+            accounts_url: accounts_url.trim().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Requests a token for the app, kept by the client.
+    /// # Errors
+    /// Returns an error if the accounts service rejects the credentials or can't be reached.
+    pub async fn request_token(&self) -> ClientResult<()> {
+        let token = self.client_token().await?;
+        // the lock of the async client can't be poisoned
+        if let Ok(mut current) = self.get_token().lock().await {
+            *current = Some(token);
+        }
+        self.write_token_cache().await
+    }
+
+    async fn client_token(&self) -> ClientResult<Token> {
+        let data = HashMap::from([("grant_type", "client_credentials")]);
+        let headers = self.get_creds().auth_headers();
+        self.fetch_access_token(&data, headers.as_ref()).await
+    }
+}
+
+#[async_trait]
+impl BaseClient for SpotifyCreds {
+    fn get_config(&self) -> &Config {
+        self.inner.get_config()
+    }
+
+    fn get_http(&self) -> &HttpClient {
+        self.inner.get_http()
+    }
+
+    fn get_creds(&self) -> &Credentials {
+        self.inner.get_creds()
+    }
+
+    fn get_token(&self) -> Arc<Mutex<Option<Token>>> {
+        self.inner.get_token()
+    }
+
+    /// Requests a new token, the client credentials flow has no refresh tokens.
+    async fn refetch_token(&self) -> ClientResult<Option<Token>> {
+        self.client_token().await.map(Some)
+    }
+
+    /// Requests a token from the configured accounts service.
+    async fn fetch_access_token(
+        &self,
+        payload: &Form<'_>,
+        headers: Option<&Headers>,
+    ) -> ClientResult<Token> {
+        fetch_token(self, &self.accounts_url, payload, headers).await
+    }
+}
+
+/// Posts `payload` to the token endpoint of the accounts service at `accounts_url`.
+async fn fetch_token(
+    client: &(impl BaseClient + ?Sized),
+    accounts_url: &str,
+    payload: &Form<'_>,
+    headers: Option<&Headers>,
+) -> ClientResult<Token> {
+    let url = format!("{accounts_url}/api/token");
+    let response = client.post_form(&url, headers, payload).await?;
+    let mut token: Token = serde_json::from_str(&response)?;
+    token.expires_at = Utc::now().checked_add_signed(token.expires_in);
+    Ok(token)
+}
@@ -0,0 +1,71 @@
+//! Chapters listed in the description of a podcast episode or a video, e.g. `12:34 Interview`.
+//!
+//! Video uploads of an episode often cut or add sponsor reads, so the same moment is at a
+//! different time in the video. When both descriptions list the same chapter, the position
+//! is carried over from the start of that chapter instead.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::normalize::fold;
+
+/// A chapter starting `start` seconds into the episode or video.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub start: i64,
+    pub title: String,
+}
+
+/// `0:00 Intro`, `(1:02:03) - Questions`, `12:34 | Interview`
+static CHAPTER_LINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*[(\[]?(?:(\d{1,2}):)?(\d{1,2}):(\d{2})[)\]]?\s*[-–—|:]?\s*(.+?)\s*$")
+        .expect("valid regex")
+});
+
+/// Returns the chapters listed in `description`, one per line.
+/// Like youtube, a list is only taken for chapters when it starts at `0:00`
+/// and has at least two chapters in order, otherwise no chapters are returned.
+#[must_use]
+pub fn parse(description: &str) -> Vec<Chapter> {
+    let chapters: Vec<Chapter> = description
+        .lines()
+        .filter_map(|line| {
+            let captures = CHAPTER_LINE.captures(line)?;
+            let number = |index| {
+                captures
+                    .get(index)
+                    .map_or(Some(0), |value| value.as_str().parse::<i64>().ok())
+            };
+            let (hours, minutes, seconds) = (number(1)?, number(2)?, number(3)?);
+            if seconds >= 60 || (captures.get(1).is_some() && minutes >= 60) {
+                return None;
+            }
+            Some(Chapter {
+                start: hours * 60 * 60 + minutes * 60 + seconds,
+                title: captures[4].to_string(),
+            })
+        })
+        .collect();
+
+    let in_order = chapters
+        .windows(2)
+        .all(|pair| pair[0].start < pair[1].start);
+    if chapters.len() < 2 || chapters[0].start != 0 || !in_order {
+        return Vec::new();
+    }
+    chapters
+}
+
+/// Returns the position in the video matching `progress` seconds into the episode,
+/// from the chapter playing in the episode and the video chapter of the same title.
+/// Returns `None` if the chapter playing is not listed in the video.
+#[must_use]
+pub fn position(episode: &[Chapter], video: &[Chapter], progress: i64) -> Option<i64> {
+    let playing = episode
+        .iter()
+        .take_while(|chapter| chapter.start <= progress)
+        .last()?;
+    let title = fold(&playing.title);
+    let matching = video.iter().find(|chapter| fold(&chapter.title) == title)?;
+    Some(matching.start + progress - playing.start)
+}
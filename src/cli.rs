@@ -0,0 +1,476 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result};
+use spotify_music_vid::{clock::SystemClock, get_client_creds, protocol::WarmSource, Song};
+use sqlx::PgPool;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        config::{Config, Scope},
+        history::HistoryRepository,
+        rules::{RuleAction, RuleKind, RuleRepository},
+        songs::SongRepository,
+        transfer::{self, ConflictPolicy, Format},
+    },
+    warm::{collect_songs, Warmer},
+    youtube_client::YoutubeClient,
+};
+
+/// Streams music videos for the song playing on Spotify and manages the video cache.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the WebSocket server (the default when no subcommand is given)
+    Serve,
+    /// Apply the pending database migrations
+    Migrate,
+    /// Look up a song on youtube and print the ranked candidates
+    Resolve {
+        /// The song to look up, formatted as "<artist> - <title>"
+        query: String,
+    },
+    /// Inspect and edit the cached videos
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Block or allow youtube channels and videos
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+    /// List the most recent plays
+    History {
+        /// Only list the plays of this Spotify user id
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+    },
+    /// Print the listening statistics and how well the videos are matched
+    Stats {
+        /// Only count the plays of this Spotify user id
+        #[arg(long)]
+        user: Option<String>,
+        /// The length of the rankings
+        #[arg(long, default_value_t = 10)]
+        limit: u32,
+    },
+    /// Resolve and cache the videos of every track in a playlist or album
+    #[command(group = clap::ArgGroup::new("source").required(true))]
+    Warm {
+        /// Spotify id or URI of a public playlist
+        #[arg(long, group = "source")]
+        playlist: Option<String>,
+        /// Spotify id or URI of an album
+        #[arg(long, group = "source")]
+        album: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// List the cached songs
+    List {
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+    },
+    /// Show a single cached song
+    Show { id: Uuid },
+    /// Remove a song from the cache so it is looked up again next time
+    Delete { id: Uuid },
+    /// Write every cached song to a file, or stdout
+    Export {
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Merge the songs of an export into the cache
+    Import {
+        input: PathBuf,
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        /// What to do with songs that are already cached
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::Skip)]
+        policy: ConflictPolicy,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RulesCommand {
+    /// List the blocked and allowed channels and videos
+    List,
+    /// Never play the videos of a channel, or a single video
+    #[command(group = clap::ArgGroup::new("target").required(true))]
+    Block {
+        /// Youtube channel id, such as `UC...`
+        #[arg(long, group = "target")]
+        channel: Option<String>,
+        /// Youtube video id
+        #[arg(long, group = "target")]
+        video: Option<String>,
+        #[arg(long)]
+        note: Option<String>,
+        /// Also delete the cached songs pointing to the channel or video
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Rank the videos of a channel, or a single video, above the others
+    #[command(group = clap::ArgGroup::new("target").required(true))]
+    Allow {
+        /// Youtube channel id, such as `UC...`
+        #[arg(long, group = "target")]
+        channel: Option<String>,
+        /// Youtube video id
+        #[arg(long, group = "target")]
+        video: Option<String>,
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Remove a rule
+    Remove { id: Uuid },
+}
+
+impl Command {
+    /// Returns the settings the command needs.
+    pub const fn scope(&self) -> Scope {
+        match self {
+            Self::Serve | Self::Warm { .. } => Scope::Full,
+            Self::Resolve { .. } => Scope::Youtube,
+            // the cached songs of a channel are found by looking their videos up on youtube
+            Self::Rules {
+                command:
+                    RulesCommand::Block {
+                        channel: Some(_),
+                        purge: true,
+                        ..
+                    },
+            } => Scope::DatabaseAndYoutube,
+            Self::Migrate
+            | Self::Cache { .. }
+            | Self::Rules { .. }
+            | Self::History { .. }
+            | Self::Stats { .. } => Scope::Database,
+        }
+    }
+}
+
+/// Runs the given command, connecting to the database if it needs it.
+/// # Errors
+/// Returns an error if the command fails, the caller is expected to report it and exit.
+pub async fn run(command: Command, config: Arc<Config>) -> Result<()> {
+    match command {
+        Command::Serve => {
+            let pool = connect(&config).await?;
+            crate::serve(config, pool).await
+        }
+        Command::Migrate => migrate(&*connect(&config).await?).await,
+        Command::Resolve { query } => resolve(&config, &query).await,
+        Command::Cache { command } => {
+            cache(command, SongRepository::new(connect(&config).await?)).await
+        }
+        Command::Rules { command } => rules(command, &config, connect(&config).await?).await,
+        Command::History {
+            user,
+            limit,
+            offset,
+        } => {
+            let repo = HistoryRepository::new(connect(&config).await?);
+            history(repo, user.as_deref(), limit, offset).await
+        }
+        Command::Stats { user, limit } => {
+            let repo = HistoryRepository::new(connect(&config).await?);
+            stats(repo, user.as_deref(), limit).await
+        }
+        Command::Warm { playlist, album } => {
+            let source = match (playlist, album) {
+                (Some(id), _) => WarmSource::Playlist(id),
+                (_, Some(id)) => WarmSource::Album(id),
+                (None, None) => unreachable!("clap requires one of --playlist or --album"),
+            };
+            warm(&config, connect(&config).await?, &source).await
+        }
+    }
+}
+
+async fn connect(config: &Config) -> Result<Arc<PgPool>> {
+    Ok(Arc::new(config.create_db_pool().await?))
+}
+
+async fn migrate(pool: &PgPool) -> Result<()> {
+    info!("Running database migrations");
+    sqlx::migrate!("./migrations").run(pool).await?;
+    println!("Database is up to date");
+    Ok(())
+}
+
+/// Prints the ranked candidates of a song, the rules are applied when a database is configured.
+async fn resolve(config: &Config, query: &str) -> Result<()> {
+    let (artist, title) = query
+        .split_once(" - ")
+        .ok_or_else(|| eyre!("Expected \"<artist> - <title>\", got {query:?}"))?;
+    let song = Song::new(title.trim().to_string(), artist.trim().to_string(), 0);
+
+    let yt_client = YoutubeClient::new(&config.youtube, SystemClock::shared())
+        .with_region(config.spotify.market);
+    if !config.database.url.is_empty() {
+        let rules = RuleRepository::new(connect(config).await?);
+        yt_client.reload_rules(&rules).await?;
+    }
+    let candidates = yt_client.search(&song).await?;
+    match candidates.first() {
+        Some(best) => println!("Found on {}", best.provider),
+        None => println!("No videos found for {song}"),
+    }
+    for (rank, candidate) in candidates.iter().enumerate() {
+        println!(
+            "{:>2}. [{:>3}] {} - {} ({}, {})",
+            rank + 1,
+            candidate.score,
+            candidate.video_id,
+            candidate.title,
+            candidate.channel_title,
+            candidate.channel_id.as_deref().unwrap_or("unknown channel")
+        );
+    }
+    Ok(())
+}
+
+async fn cache(command: CacheCommand, repo: SongRepository) -> Result<()> {
+    match command {
+        CacheCommand::List { limit, offset } => {
+            for song in repo.list(limit.into(), offset.into()).await? {
+                println!(
+                    "{}  {} - {}  {}",
+                    song.id, song.artist, song.title, song.youtube_id
+                );
+            }
+        }
+        CacheCommand::Show { id } => {
+            let song = repo
+                .find(id)
+                .await?
+                .ok_or_else(|| eyre!("No cached song with id {id}"))?;
+            println!("id:         {}", song.id);
+            println!("title:      {}", song.title);
+            println!("artist:     {}", song.artist);
+            println!("youtube id: {}", song.youtube_id);
+            println!("video:      {}", Song::get_embed_url(&song.youtube_id));
+        }
+        CacheCommand::Delete { id } => {
+            if !repo.delete(id).await? {
+                return Err(eyre!("No cached song with id {id}"));
+            }
+            println!("Deleted {id}");
+        }
+        CacheCommand::Export { format, output } => {
+            let count = match output {
+                Some(path) => transfer::export(&repo, format, File::create(path)?).await?,
+                None => transfer::export(&repo, format, io::stdout().lock()).await?,
+            };
+            eprintln!("Exported {count} songs");
+        }
+        CacheCommand::Import {
+            input,
+            format,
+            policy,
+        } => {
+            let input = BufReader::new(File::open(input)?);
+            let summary = transfer::import(&repo, format, policy, input).await?;
+            println!(
+                "Imported songs: {} added, {} updated, {} skipped",
+                summary.inserted, summary.updated, summary.skipped
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn rules(command: RulesCommand, config: &Config, pool: Arc<PgPool>) -> Result<()> {
+    let repo = RuleRepository::new(pool.clone());
+    let (kind, target, action, note, purge) = match command {
+        RulesCommand::List => {
+            for rule in repo.list().await? {
+                println!(
+                    "{}  {:?} {:?} {}  {}",
+                    rule.id,
+                    rule.action,
+                    rule.kind,
+                    rule.target,
+                    rule.note.unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+        RulesCommand::Remove { id } => {
+            if !repo.delete(id).await? {
+                return Err(eyre!("No rule with id {id}"));
+            }
+            println!("Removed {id}");
+            return Ok(());
+        }
+        RulesCommand::Block {
+            channel,
+            video,
+            note,
+            purge,
+        } => {
+            let (kind, target) = rule_target(channel, video);
+            (kind, target, RuleAction::Block, note, purge)
+        }
+        RulesCommand::Allow {
+            channel,
+            video,
+            note,
+        } => {
+            let (kind, target) = rule_target(channel, video);
+            (kind, target, RuleAction::Allow, note, false)
+        }
+    };
+
+    let rule = repo.set(kind, &target, action, note.as_deref()).await?;
+    println!(
+        "{:?} {:?} {} ({})",
+        rule.action, rule.kind, rule.target, rule.id
+    );
+    if purge {
+        let songs = SongRepository::new(pool);
+        if kind == RuleKind::Channel {
+            // the channel of videos cached before channels were stored, or picked by hand
+            let yt_client = YoutubeClient::new(&config.youtube, SystemClock::shared());
+            let resolved = yt_client.resolve_channels(&songs).await?;
+            println!("Resolved the channel of {resolved} cached songs");
+        }
+        let purged = songs.delete_by_rule(kind, &target).await?;
+        println!("Purged {purged} cached songs");
+    }
+    Ok(())
+}
+
+async fn history(
+    repo: HistoryRepository,
+    user: Option<&str>,
+    limit: u32,
+    offset: u32,
+) -> Result<()> {
+    for play in repo.recent(user, limit.into(), offset.into()).await? {
+        println!(
+            "{}  {}  {} - {}  {}  {}s{}",
+            play.started_at.format("%Y-%m-%d %H:%M:%S"),
+            play.spotify_user,
+            play.artist,
+            play.title,
+            play.youtube_id.as_deref().unwrap_or("no video"),
+            play.listened_secs,
+            if play.corrected { "  corrected" } else { "" }
+        );
+    }
+    Ok(())
+}
+
+async fn stats(repo: HistoryRepository, user: Option<&str>, limit: u32) -> Result<()> {
+    let stats = repo.stats(user, limit.into()).await?;
+    println!("plays:          {}", stats.plays);
+    println!("listeners:      {}", stats.listeners);
+    println!(
+        "listened:       {}h {:02}m",
+        stats.listened_secs / 3600,
+        stats.listened_secs % 3600 / 60
+    );
+    println!("cache hit rate: {}", percent(stats.cache_hit_rate));
+    println!("no video:       {}", percent(stats.no_video_rate));
+
+    println!("\nTop artists");
+    for artist in stats.top_artists {
+        println!("{:>6}  {}", artist.count, artist.artist);
+    }
+    println!("\nTop tracks");
+    for track in stats.top_tracks {
+        println!("{:>6}  {} - {}", track.count, track.artist, track.title);
+    }
+    println!("\nMost corrected");
+    for track in stats.most_corrected {
+        println!("{:>6}  {} - {}", track.count, track.artist, track.title);
+    }
+    println!("\nVideos found by");
+    for source in stats.sources {
+        println!("{:>6}  {}", source.count, source.source);
+    }
+    println!("\nProvider success rates");
+    for provider in stats.providers {
+        println!(
+            "{:>6}  {}",
+            percent(provider.success_rate),
+            provider.provider
+        );
+    }
+    Ok(())
+}
+
+fn percent(rate: Option<f64>) -> String {
+    rate.map_or_else(|| "-".to_string(), |rate| format!("{:.1}%", rate * 100.0))
+}
+
+fn rule_target(channel: Option<String>, video: Option<String>) -> (RuleKind, String) {
+    match (channel, video) {
+        (Some(channel), _) => (RuleKind::Channel, channel),
+        (_, Some(video)) => (RuleKind::Video, video),
+        (None, None) => unreachable!("clap requires one of --channel or --video"),
+    }
+}
+
+/// Resolves every track of a playlist or album that is not cached yet.
+/// Uses the client credentials flow, so only public playlists can be read.
+async fn warm(config: &Config, pool: Arc<PgPool>, source: &WarmSource) -> Result<()> {
+    let spotify = get_client_creds(
+        &config.spotify.client_id,
+        config.spotify.client_secret.expose(),
+        &config.spotify.api_url,
+        &config.spotify.accounts_url,
+    );
+    spotify.request_token().await?;
+    let songs = collect_songs(&spotify, source).await?;
+
+    let clock = SystemClock::shared();
+    let yt_client =
+        YoutubeClient::new(&config.youtube, clock.clone()).with_region(config.spotify.market);
+    yt_client
+        .reload_rules(&RuleRepository::new(pool.clone()))
+        .await?;
+    let warmer = Warmer::new(
+        yt_client,
+        SongRepository::new(pool),
+        config.cache.warm.clone(),
+        clock,
+    );
+    let progress = warmer
+        .run(songs, |progress| {
+            eprint!("\r{}/{} tracks", progress.done(), progress.total);
+        })
+        .await;
+    eprintln!();
+    println!(
+        "Warmed {} tracks: {} added, {} already cached, {} failed, {} skipped to preserve the quota",
+        progress.total, progress.added, progress.cached, progress.failed, progress.skipped
+    );
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! The time seen by the poller, the retries and the plays.
+//!
+//! Everything that waits or measures time goes through a [`Clock`], the server uses the
+//! [`SystemClock`] and tests a [`ManualClock`] moved by hand, so timing is deterministic.
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::future::{self, BoxFuture, FutureExt};
+use tokio::{sync::oneshot, time::Instant};
+
+/// A source of time, and of sleeps ending at a time.
+pub trait Clock: Send + Sync + Debug {
+    /// Returns the time since the origin of the clock, it never goes back.
+    fn now(&self) -> Duration;
+
+    /// Returns the current date.
+    fn utc(&self) -> DateTime<Utc>;
+
+    /// Returns a future completing once [`Clock::now`] reaches `deadline`.
+    fn sleep_until(&self, deadline: Duration) -> BoxFuture<'static, ()>;
+
+    /// Returns a future completing once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.sleep_until(self.now().saturating_add(duration))
+    }
+}
+
+/// A clock shared by everything of a session.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The time of the tokio runtime, with the origin at its creation.
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+
+    #[must_use]
+    pub fn shared() -> SharedClock {
+        Arc::new(Self::new())
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep_until(&self, deadline: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(self.origin + deadline).boxed()
+    }
+}
+
+#[derive(Debug)]
+struct ManualState {
+    now: Duration,
+    start: DateTime<Utc>,
+    /// Sleeps waiting for the clock to reach their deadline
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+/// A clock only moved by hand, sleeps end when it is moved past their deadline.
+/// Clones share the same time, so a test keeps one to move the time of the code it drives.
+#[derive(Debug, Clone)]
+pub struct ManualClock(Arc<Mutex<ManualState>>);
+
+impl ManualClock {
+    /// Creates a [`ManualClock`] at its origin, where [`Clock::utc`] is `start`.
+    #[must_use]
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self(Arc::new(Mutex::new(ManualState {
+            now: Duration::ZERO,
+            start,
+            sleepers: Vec::new(),
+        })))
+    }
+
+    /// Returns a clone of this clock as a [`SharedClock`].
+    #[must_use]
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+
+    /// Moves the time forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.lock();
+        let at = state.now.saturating_add(duration);
+        Self::move_to(&mut state, at);
+    }
+
+    /// Moves the time to `at` since the origin, the time never goes back.
+    pub fn set(&self, at: Duration) {
+        let mut state = self.lock();
+        let at = at.max(state.now);
+        Self::move_to(&mut state, at);
+    }
+
+    /// Returns the earliest deadline of the pending sleeps.
+    #[must_use]
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.lock()
+            .sleepers
+            .iter()
+            .filter(|(_, waker)| !waker.is_closed())
+            .map(|(deadline, _)| *deadline)
+            .min()
+    }
+
+    fn move_to(state: &mut ManualState, at: Duration) {
+        state.now = at;
+        let (due, pending) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= at);
+        state.sleepers = pending;
+        for (_, waker) in due {
+            // the sleep may have been dropped
+            let _ = waker.send(());
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ManualState> {
+        // the state is always valid, even if a thread panicked while holding the lock
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for ManualClock {
+    /// A clock whose origin is the Unix epoch.
+    fn default() -> Self {
+        Self::new(Utc.timestamp_opt(0, 0).unwrap())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        self.lock().now
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        let state = self.lock();
+        let elapsed =
+            chrono::Duration::from_std(state.now).unwrap_or_else(|_| chrono::Duration::max_value());
+        state
+            .start
+            .checked_add_signed(elapsed)
+            .unwrap_or(state.start)
+    }
+
+    fn sleep_until(&self, deadline: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.lock();
+        if deadline <= state.now {
+            return future::ready(()).boxed();
+        }
+        let (waker, woken) = oneshot::channel();
+        state.sleepers.push((deadline, waker));
+        // the sender is only dropped once woken, or with the clock
+        woken.map(|_| ()).boxed()
+    }
+}
@@ -1,39 +1,635 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
+use config::{ConfigError, Environment, File};
 use eyre::Context;
-use serde::Deserialize;
+use rspotify::model::Country;
+use serde::{de::DeserializeOwned, Deserialize};
+use spotify_music_vid::schedule::{Backoff, PollSchedule};
 use sqlx::{postgres::PgPoolOptions, PgPool};
 use tracing::{info, instrument};
+use tracing_subscriber::EnvFilter;
 
-#[derive(Debug, Deserialize)]
+/// Environment variable pointing at an explicit configuration file.
+const CONFIG_FILE_VAR: &str = "CONFIG_FILE";
+
+/// Flat environment variables supported before the configuration was split
+/// into sections, mapped to the key they override.
+const LEGACY_ENV_VARS: [(&str, &str); 8] = [
+    ("HOST", "server.host"),
+    ("PORT", "server.port"),
+    ("ADMIN_TOKEN", "server.admin_token"),
+    ("DATABASE_URL", "database.url"),
+    ("SPOTIFY_CLIENT_ID", "spotify.client_id"),
+    ("SPOTIFY_CLIENT_SECRET", "spotify.client_secret"),
+    ("SPOTIFY_CALLBACK_URL", "spotify.redirect_uri"),
+    ("YOUTUBE_API_KEY", "youtube.api_key"),
+];
+
+/// The settings a command needs, only those are required to be set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The server and the commands using the database and Spotify
+    Full,
+    /// Commands only searching youtube
+    Youtube,
+    /// Commands only reading and writing the database
+    Database,
+    /// Commands using the database and searching youtube, but not Spotify
+    DatabaseAndYoutube,
+}
+
+impl Scope {
+    const fn needs_youtube(self) -> bool {
+        matches!(self, Self::Full | Self::Youtube | Self::DatabaseAndYoutube)
+    }
+
+    const fn needs_database(self) -> bool {
+        matches!(self, Self::Full | Self::Database | Self::DatabaseAndYoutube)
+    }
+}
+
+/// Typed configuration tree for the whole service.
+///
+/// Values are layered, from lowest to highest priority:
+/// 1. `config.toml` / `config.yaml` in the working directory, or the file named by `CONFIG_FILE`
+/// 2. nested environment variables such as `SPOTIFY__CLIENT_ID` or `POLLING__INTERVAL_MS`
+/// 3. the flat legacy variables (`DATABASE_URL`, `SPOTIFY_CLIENT_ID`, ...)
+#[derive(Debug, Clone)]
 pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub spotify: SpotifyConfig,
+    pub youtube: YoutubeConfig,
+    pub polling: PollingConfig,
+    pub cache: CacheConfig,
+    pub lastfm: LastfmConfig,
+    pub log: LogConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+/// A configuration value that must never reach the logs, its [`Debug`] output is redacted.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            f.write_str("\"\"")
+        } else {
+            f.write_str("\"[redacted]\"")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
     pub host: String,
-    pub port: i64,
-    pub database_url: String,
-    pub secret_key: String,
+    pub port: u16,
+    /// Bearer token of the admin http api, the api is disabled while it is empty
+    pub admin_token: Secret,
+    /// How long youtube may keep failing before `/readyz` reports the server as not ready
+    pub readiness_window_secs: u64,
+    /// Whether `/ws?source=mpris` may follow the players of the server's D-Bus session
+    pub allow_local_players: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    /// May contain the database password
+    pub url: Secret,
+    pub max_connections: u32,
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpotifyConfig {
+    pub client_id: String,
+    pub client_secret: Secret,
+    pub redirect_uri: String,
+    /// ISO 3166-1 alpha-2 country such as `DE`, the country of the user's profile when unset
+    pub market: Option<Country>,
+    /// Root of the Web API, tests point it at a local stand-in
+    pub api_url: String,
+    /// Root of the accounts service, where users authorize the app and tokens are requested and refreshed
+    pub accounts_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct YoutubeConfig {
+    pub api_key: Secret,
+    /// Root of the youtube data api, tests point it at a local stand-in
+    pub api_url: String,
+    /// Units of the youtube data api quota available per day
+    pub daily_quota: u64,
+    /// ISO 639-1 language such as `de` favoured by the search, youtube guesses it when empty
+    pub relevance_language: String,
+    /// Instances searched in order after the youtube data api, when it fails or is out of quota
+    pub fallbacks: Vec<FallbackConfig>,
+    /// Time a video provider may take to answer a search
+    pub timeout_ms: u64,
+    /// Consecutive failures after which a video provider is skipped
+    pub breaker_threshold: u32,
+    /// Time a failing video provider is skipped for, before a single search probes it again
+    pub breaker_cooldown_secs: u64,
+    /// Delay between two reloads of the blocked and allowed channels by the server,
+    /// changes made with the admin api apply immediately
+    pub rules_refresh_secs: u64,
+}
+
+/// The api spoken by a [`FallbackConfig`] instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackApi {
+    /// `GET /api/v1/search` of an Invidious instance
+    Invidious,
+    /// `GET /search` of a Piped api instance
+    Piped,
+}
+
+/// A public youtube frontend searched when the youtube data api fails or is out of quota.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FallbackConfig {
+    #[serde(default = "default_fallback_api")]
+    pub api: FallbackApi,
+    /// Base url of the instance such as `https://invidious.example.org`
+    pub instance_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PollingConfig {
+    /// Delay between two polls of the currently playing track
+    pub interval_ms: u64,
+    /// Delay before retrying after Spotify returned an error, doubled after every failure in a row
+    pub retry_secs: u64,
+    /// Longest delay between the retries
+    pub max_retry_secs: u64,
+    /// Progress jumps longer than this are seeks, the video is then sent again at the new position
+    pub seek_tolerance_secs: u64,
+    /// Directory the Spotify sessions are recorded to for replays, not recorded while empty
+    pub record_dir: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Whether resolved videos are looked up in and stored to the database
+    pub enabled: bool,
+    pub warm: WarmConfig,
+}
+
+/// Scrobbling to Last.fm, disabled while `api_key` is empty.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LastfmConfig {
+    pub api_key: String,
+    pub api_secret: Secret,
+    /// Root of the Last.fm api, tests point it at a local stand-in
+    pub base_url: String,
+    /// Page users are sent to for allowing the scrobbles
+    pub auth_url: String,
+    /// Key the Last.fm session keys of the users are encrypted with in the database
+    pub encryption_key: Secret,
+    /// Delay before retrying a failed scrobble, doubled after every attempt
+    pub retry_secs: u64,
+    /// Attempts after which a failed scrobble is dropped
+    pub max_attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable multi-line output
+    Pretty,
+    /// One JSON object per event, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Filter directives such as `info` or `spotify_music_vid=debug,warp=info`,
+    /// `RUST_LOG` takes precedence when set
+    pub level: String,
+}
+
+/// Export of the tracing spans, only used when built with the `otel` feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP traces url such as `http://localhost:4318/v1/traces`, export is disabled when empty
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+/// Settings for warming the cache from a playlist, album or saved library.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WarmConfig {
+    /// Number of tracks resolved at the same time
+    pub concurrency: usize,
+    /// Minimum delay between two youtube searches
+    pub request_interval_ms: u64,
+    /// Quota units kept for live playback, warming stops once only this much is left
+    pub quota_reserve: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            admin_token: Secret::default(),
+            readiness_window_secs: 300,
+            allow_local_players: false,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: Secret::default(),
+            max_connections: 10,
+            idle_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for SpotifyConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: Secret::default(),
+            redirect_uri: "http://localhost:5173/callback".to_string(),
+            market: None,
+            api_url: "https://api.spotify.com/v1/".to_string(),
+            accounts_url: "https://accounts.spotify.com".to_string(),
+        }
+    }
+}
+
+impl Default for YoutubeConfig {
+    fn default() -> Self {
+        Self {
+            api_key: Secret::default(),
+            api_url: "https://youtube.googleapis.com/youtube/v3".to_string(),
+            daily_quota: 10_000,
+            relevance_language: String::new(),
+            fallbacks: Vec::new(),
+            timeout_ms: 10_000,
+            breaker_threshold: 5,
+            breaker_cooldown_secs: 60,
+            rules_refresh_secs: 60,
+        }
+    }
+}
+
+/// Reads the single `[youtube.fallback]` instance of configurations written before
+/// several instances could be set in `[[youtube.fallbacks]]`, it is searched first.
+fn legacy_fallback(raw: &config::Config) -> Option<FallbackConfig> {
+    raw.get::<FallbackConfig>("youtube.fallback")
+        .ok()
+        .filter(|fallback| !fallback.instance_url.trim().is_empty())
+}
+
+const fn default_fallback_api() -> FallbackApi {
+    FallbackApi::Invidious
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 250,
+            retry_secs: 5,
+            max_retry_secs: 60,
+            seek_tolerance_secs: 3,
+            record_dir: String::new(),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            warm: WarmConfig::default(),
+        }
+    }
+}
+
+impl Default for LastfmConfig {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            api_secret: Secret::default(),
+            base_url: "https://ws.audioscrobbler.com/2.0/".to_string(),
+            auth_url: "https://www.last.fm/api/auth/".to_string(),
+            encryption_key: Secret::default(),
+            retry_secs: 60,
+            max_attempts: 10,
+        }
+    }
 }
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: String::new(),
+            service_name: "spotify-music-vid".to_string(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            level: "info".to_string(),
+        }
+    }
+}
+
+impl Default for WarmConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            request_interval_ms: 500,
+            quota_reserve: 2_000,
+        }
+    }
+}
+
+impl YoutubeConfig {
+    pub const fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub const fn breaker_cooldown(&self) -> Duration {
+        Duration::from_secs(self.breaker_cooldown_secs)
+    }
+
+    pub const fn rules_refresh(&self) -> Duration {
+        Duration::from_secs(self.rules_refresh_secs)
+    }
+}
+
+impl LastfmConfig {
+    pub fn enabled(&self) -> bool {
+        !self.api_key.trim().is_empty()
+    }
+
+    pub const fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_secs)
+    }
+}
+
+impl PollingConfig {
+    pub const fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub const fn retry_delay(&self) -> Duration {
+        Duration::from_secs(self.retry_secs)
+    }
+
+    pub const fn seek_tolerance(&self) -> Duration {
+        Duration::from_secs(self.seek_tolerance_secs)
+    }
+
+    pub const fn schedule(&self) -> PollSchedule {
+        let backoff = Backoff::new(self.retry_delay(), Duration::from_secs(self.max_retry_secs));
+        PollSchedule::new(self.interval(), backoff)
+    }
+}
+
 impl Config {
+    /// Loads the configuration from the optional config file and the environment,
+    /// only the keys needed in `scope` are required.
+    /// # Errors
+    /// Returns an error listing every missing or invalid key if the configuration is not usable.
     #[instrument]
-    pub fn from_env() -> Result<Self> {
-        // dotenv().ok();
+    pub fn from_env(scope: Scope) -> Result<Self> {
+        info!("Loading config from file and environment variables");
+
+        let mut builder = config::Config::builder();
+        builder = match std::env::var(CONFIG_FILE_VAR) {
+            Ok(path) => builder.add_source(File::with_name(&path)),
+            Err(_) => builder.add_source(File::with_name("config").required(false)),
+        };
+        builder = builder.add_source(Environment::default().separator("__"));
+        for (var, key) in LEGACY_ENV_VARS {
+            builder = builder.set_override_option(key, std::env::var(var).ok())?;
+        }
+        let raw = builder.build().context("Reading configuration sources")?;
 
-        info!("Loading config from environment variables");
+        let mut errors = Vec::new();
+        let server = section::<ServerConfig>(&raw, "server", &mut errors);
+        let database = section::<DatabaseConfig>(&raw, "database", &mut errors);
+        let spotify = section::<SpotifyConfig>(&raw, "spotify", &mut errors);
+        let mut youtube = section::<YoutubeConfig>(&raw, "youtube", &mut errors);
+        if let Some(fallback) = legacy_fallback(&raw) {
+            youtube.fallbacks.insert(0, fallback);
+        }
+        let polling = section::<PollingConfig>(&raw, "polling", &mut errors);
+        let cache = section::<CacheConfig>(&raw, "cache", &mut errors);
+        let lastfm = section::<LastfmConfig>(&raw, "lastfm", &mut errors);
+        let log = section::<LogConfig>(&raw, "log", &mut errors);
+        let telemetry = section::<TelemetryConfig>(&raw, "telemetry", &mut errors);
 
-        let config = config::Config::builder()
-            .add_source(config::Environment::default())
-            .build()?
-            .try_deserialize()?;
+        let config = Self {
+            server,
+            database,
+            spotify,
+            youtube,
+            polling,
+            cache,
+            lastfm,
+            log,
+            telemetry,
+        };
+        config.validate(scope, &mut errors);
 
-        Ok(config)
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(eyre!(
+                "Invalid configuration:\n  - {}",
+                errors.join("\n  - ")
+            ))
+        }
     }
-    #[instrument]
+
+    /// Pushes a readable message to `errors` for every key required in `scope` that is missing,
+    /// and for every invalid key.
+    fn validate(&self, scope: Scope, errors: &mut Vec<String>) {
+        let mut required = Vec::new();
+        if scope.needs_youtube() {
+            required.push((
+                "youtube.api_key",
+                "YOUTUBE_API_KEY",
+                self.youtube.api_key.expose(),
+            ));
+        }
+        if scope.needs_database() {
+            required.push(("database.url", "DATABASE_URL", self.database.url.expose()));
+        }
+        if scope == Scope::Full {
+            required.extend([
+                (
+                    "spotify.client_id",
+                    "SPOTIFY_CLIENT_ID",
+                    self.spotify.client_id.as_str(),
+                ),
+                (
+                    "spotify.client_secret",
+                    "SPOTIFY_CLIENT_SECRET",
+                    self.spotify.client_secret.expose(),
+                ),
+            ]);
+        }
+        for (key, var, value) in required {
+            if value.trim().is_empty() {
+                errors.push(format!(
+                    "{key} is missing (set it in the config file or {var})"
+                ));
+            }
+        }
+
+        if url::Url::parse(&self.spotify.redirect_uri).is_err() {
+            errors.push(format!(
+                "spotify.redirect_uri is not a valid url: {:?}",
+                self.spotify.redirect_uri
+            ));
+        }
+        for (key, value) in [
+            ("spotify.api_url", &self.spotify.api_url),
+            ("spotify.accounts_url", &self.spotify.accounts_url),
+            ("youtube.api_url", &self.youtube.api_url),
+        ] {
+            if url::Url::parse(value).is_err() {
+                errors.push(format!("{key} is not a valid url: {value:?}"));
+            }
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            errors.push(format!("log.level is not a valid filter: {e}"));
+        }
+        if !self.telemetry.otlp_endpoint.is_empty()
+            && url::Url::parse(&self.telemetry.otlp_endpoint).is_err()
+        {
+            errors.push(format!(
+                "telemetry.otlp_endpoint is not a valid url: {:?}",
+                self.telemetry.otlp_endpoint
+            ));
+        }
+        for fallback in &self.youtube.fallbacks {
+            if url::Url::parse(&fallback.instance_url).is_err() {
+                errors.push(format!(
+                    "youtube.fallbacks.instance_url is not a valid url: {:?}",
+                    fallback.instance_url
+                ));
+            }
+        }
+        if self.youtube.timeout_ms == 0 {
+            errors.push("youtube.timeout_ms must be greater than 0".to_string());
+        }
+        if self.youtube.rules_refresh_secs == 0 {
+            errors.push("youtube.rules_refresh_secs must be greater than 0".to_string());
+        }
+        if self.youtube.breaker_threshold == 0 {
+            errors.push("youtube.breaker_threshold must be greater than 0".to_string());
+        }
+        if self.lastfm.enabled() {
+            self.validate_lastfm(errors);
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections must be greater than 0".to_string());
+        }
+        if self.cache.warm.concurrency == 0 {
+            errors.push("cache.warm.concurrency must be greater than 0".to_string());
+        }
+        if self.polling.interval_ms == 0 {
+            errors.push("polling.interval_ms must be greater than 0".to_string());
+        }
+        if self.polling.max_retry_secs < self.polling.retry_secs {
+            errors.push(
+                "polling.max_retry_secs must not be less than polling.retry_secs".to_string(),
+            );
+        }
+    }
+
+    /// Checks the `lastfm` section, only when scrobbling is enabled.
+    fn validate_lastfm(&self, errors: &mut Vec<String>) {
+        let lastfm = &self.lastfm;
+        if lastfm.api_secret.is_empty() {
+            errors.push("lastfm.api_secret is missing while lastfm.api_key is set".to_string());
+        }
+        if lastfm.encryption_key.is_empty() {
+            errors.push("lastfm.encryption_key is missing while lastfm.api_key is set".to_string());
+        }
+        for (key, value) in [
+            ("lastfm.base_url", &lastfm.base_url),
+            ("lastfm.auth_url", &lastfm.auth_url),
+        ] {
+            if url::Url::parse(value).is_err() {
+                errors.push(format!("{key} is not a valid url: {value:?}"));
+            }
+        }
+        if lastfm.retry_secs == 0 {
+            errors.push("lastfm.retry_secs must be greater than 0".to_string());
+        }
+        if lastfm.max_attempts == 0 {
+            errors.push("lastfm.max_attempts must be greater than 0".to_string());
+        }
+    }
+
+    #[instrument(skip(self))]
     pub async fn create_db_pool(&self) -> Result<PgPool> {
         info!("Creating database pool");
         PgPoolOptions::new()
-            .idle_timeout(Duration::from_secs(30))
-            .connect(&self.database_url)
+            .max_connections(self.database.max_connections)
+            .idle_timeout(Duration::from_secs(self.database.idle_timeout_secs))
+            .connect(self.database.url.expose())
             .await
             .context("Creating database pool")
     }
 }
+
+/// Deserializes one section of the configuration, falling back to its defaults when absent.
+/// Type errors are pushed to `errors` so that every invalid key is reported at once.
+fn section<T>(raw: &config::Config, key: &str, errors: &mut Vec<String>) -> T
+where
+    T: DeserializeOwned + Default,
+{
+    match raw.get::<T>(key) {
+        Ok(value) => value,
+        Err(ConfigError::NotFound(_)) => T::default(),
+        Err(e) => {
+            errors.push(format!("{key}: {e}"));
+            T::default()
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use spotify_music_vid::{Result, Song};
+use sqlx::PgPool;
+use tracing::instrument;
+use uuid::Uuid;
+
+/// A track played by a Spotify user.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Play {
+    pub id: Uuid,
+    pub spotify_user: String,
+    pub track_id: Option<String>,
+    pub title: String,
+    pub artist: String,
+    /// The video sent for the track, `None` when no video was found
+    pub youtube_id: Option<String>,
+    pub started_at: DateTime<Utc>,
+    /// Seconds the track was playing before the next one started
+    pub listened_secs: i32,
+    /// Whether the user replaced the video that was sent
+    pub corrected: bool,
+    /// Where the video was found, `cache` or a provider
+    pub source: Option<String>,
+}
+
+/// Statistics of the plays of a user, or of every user.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub plays: i64,
+    pub listeners: i64,
+    pub listened_secs: i64,
+    /// Fraction of the video lookups answered by the cache, `None` before any lookup was recorded
+    pub cache_hit_rate: Option<f64>,
+    /// Fraction of the plays without a video, `None` without plays
+    pub no_video_rate: Option<f64>,
+    pub top_artists: Vec<ArtistCount>,
+    pub top_tracks: Vec<TrackCount>,
+    /// The tracks whose video was replaced by their listeners the most
+    pub most_corrected: Vec<TrackCount>,
+    /// Plays by where their video was found, `cache` or a provider
+    pub sources: Vec<SourceCount>,
+    /// How often each provider found the video of the plays that were searched
+    pub providers: Vec<ProviderStats>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ArtistCount {
+    pub artist: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TrackCount {
+    pub title: String,
+    pub artist: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SourceCount {
+    pub source: String,
+    pub count: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ProviderStats {
+    pub provider: String,
+    /// Plays whose video the provider found
+    pub found: i64,
+    /// Fraction of the searched plays whose video the provider found, searches only reach
+    /// the next provider when one fails or finds nothing
+    pub success_rate: Option<f64>,
+}
+
+/// The tracks played by each Spotify user.
+#[derive(Clone)]
+pub struct HistoryRepository {
+    pool: Arc<PgPool>,
+}
+
+impl HistoryRepository {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Records that `song` started playing, returning the id of the play.
+    /// `source` tells where the video was found, both are `None` when no video was found.
+    #[instrument(skip(self, song), fields(%song))]
+    pub async fn start(
+        &self,
+        spotify_user: &str,
+        song: &Song,
+        youtube_id: Option<&str>,
+        source: Option<&str>,
+    ) -> Result<Uuid> {
+        let id = sqlx::query_scalar(
+            r#"
+            INSERT INTO history (spotify_user, track_id, title, artist, youtube_id, source)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(&song.track_id)
+        .bind(&song.name)
+        .bind(&song.artist)
+        .bind(youtube_id)
+        .bind(source)
+        .fetch_one(&*self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Stores how long a play lasted.
+    #[instrument(skip(self))]
+    pub async fn finish(&self, id: Uuid, listened_secs: i32) -> Result<()> {
+        sqlx::query("UPDATE history SET listened_secs = $2 WHERE id = $1")
+            .bind(id)
+            .bind(listened_secs)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records that the user replaced the video of a play.
+    #[instrument(skip(self))]
+    pub async fn correct(&self, id: Uuid, youtube_id: &str) -> Result<()> {
+        sqlx::query("UPDATE history SET youtube_id = $2, corrected = true WHERE id = $1")
+            .bind(id)
+            .bind(youtube_id)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns a page of the plays of a user, or of every user, most recent first.
+    #[instrument(skip(self))]
+    pub async fn recent(
+        &self,
+        spotify_user: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Play>> {
+        let plays = sqlx::query_as::<_, Play>(
+            r#"
+            SELECT * FROM history
+            WHERE ($1::text IS NULL OR spotify_user = $1)
+            ORDER BY started_at DESC
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(plays)
+    }
+
+    /// Returns the number of plays of a user, or of every user.
+    #[instrument(skip(self))]
+    pub async fn count(&self, spotify_user: Option<&str>) -> Result<i64> {
+        let count = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM history WHERE ($1::text IS NULL OR spotify_user = $1)",
+        )
+        .bind(spotify_user)
+        .fetch_one(&*self.pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    /// Returns the statistics of a user, or of every user, with the `limit` first of each ranking.
+    #[instrument(skip(self))]
+    pub async fn stats(&self, spotify_user: Option<&str>, limit: i64) -> Result<Stats> {
+        // lookups are the plays with a known source and the plays without a video,
+        // plays recorded before the source was stored are left out
+        let (plays, listeners, listened_secs, cache_hits, no_video, lookups) =
+            sqlx::query_as::<_, (i64, i64, i64, i64, i64, i64)>(
+                r#"
+                SELECT
+                    COUNT(*),
+                    COUNT(DISTINCT spotify_user),
+                    COALESCE(SUM(listened_secs), 0)::bigint,
+                    COUNT(*) FILTER (WHERE source = 'cache'),
+                    COUNT(*) FILTER (WHERE youtube_id IS NULL),
+                    COUNT(*) FILTER (WHERE source IS NOT NULL OR youtube_id IS NULL)
+                FROM history
+                WHERE ($1::text IS NULL OR spotify_user = $1)
+                "#,
+            )
+            .bind(spotify_user)
+            .fetch_one(&*self.pool)
+            .await?;
+
+        let top_artists = sqlx::query_as::<_, ArtistCount>(
+            r#"
+            SELECT artist, COUNT(*) AS count FROM history
+            WHERE ($1::text IS NULL OR spotify_user = $1)
+            GROUP BY artist
+            ORDER BY count DESC, artist
+            LIMIT $2
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let top_tracks = sqlx::query_as::<_, TrackCount>(
+            r#"
+            SELECT title, artist, COUNT(*) AS count FROM history
+            WHERE ($1::text IS NULL OR spotify_user = $1)
+            GROUP BY title, artist
+            ORDER BY count DESC, artist, title
+            LIMIT $2
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let most_corrected = sqlx::query_as::<_, TrackCount>(
+            r#"
+            SELECT title, artist, COUNT(*) AS count FROM history
+            WHERE ($1::text IS NULL OR spotify_user = $1) AND corrected
+            GROUP BY title, artist
+            ORDER BY count DESC, artist, title
+            LIMIT $2
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let sources = sqlx::query_as::<_, SourceCount>(
+            r#"
+            SELECT source, COUNT(*) AS count FROM history
+            WHERE ($1::text IS NULL OR spotify_user = $1) AND source IS NOT NULL
+            GROUP BY source
+            ORDER BY count DESC, source
+            "#,
+        )
+        .bind(spotify_user)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(Stats {
+            plays,
+            listeners,
+            listened_secs,
+            cache_hit_rate: ratio(cache_hits, lookups),
+            no_video_rate: ratio(no_video, plays),
+            top_artists,
+            top_tracks,
+            most_corrected,
+            providers: provider_stats(&sources, lookups - cache_hits),
+            sources,
+        })
+    }
+}
+
+/// Returns the plays found by each provider out of the `searched` plays,
+/// the plays that missed the cache, including the ones without a video.
+fn provider_stats(sources: &[SourceCount], searched: i64) -> Vec<ProviderStats> {
+    sources
+        .iter()
+        .filter(|source| source.source != "cache")
+        .map(|source| ProviderStats {
+            provider: source.source.clone(),
+            found: source.count,
+            success_rate: ratio(source.count, searched),
+        })
+        .collect()
+}
+
+/// Returns `part / total`, or `None` when `total` is zero.
+fn ratio(part: i64, total: i64) -> Option<f64> {
+    (total > 0).then(|| part as f64 / total as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(source: &str, count: i64) -> SourceCount {
+        SourceCount {
+            source: source.to_string(),
+            count,
+        }
+    }
+
+    #[test]
+    fn rates_providers_by_the_plays_that_missed_the_cache() {
+        let sources = [
+            source("cache", 6),
+            source("youtube", 3),
+            source("https://invidious.example", 1),
+        ];
+        // 4 plays found by a provider and 1 without a video
+        let providers = provider_stats(&sources, 5);
+        assert_eq!(
+            providers,
+            [
+                ProviderStats {
+                    provider: "youtube".to_string(),
+                    found: 3,
+                    success_rate: Some(0.6),
+                },
+                ProviderStats {
+                    provider: "https://invidious.example".to_string(),
+                    found: 1,
+                    success_rate: Some(0.2),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn has_no_rate_before_any_search() {
+        assert!(provider_stats(&[source("cache", 2)], 0).is_empty());
+        assert_eq!(ratio(0, 0), None);
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use spotify_music_vid::{Result, Song};
+use sqlx::PgPool;
+use tracing::instrument;
+use uuid::Uuid;
+
+/// A track as sent to Last.fm.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Scrobble {
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    /// Seconds
+    pub duration: Option<i32>,
+    /// When the track started playing
+    pub played_at: DateTime<Utc>,
+}
+
+/// A scrobble waiting to be retried, with the encrypted session key of its user.
+#[derive(Debug, sqlx::FromRow)]
+pub struct QueuedScrobble {
+    pub id: Uuid,
+    pub spotify_user: String,
+    pub session_key: Vec<u8>,
+    pub attempts: i32,
+    #[sqlx(flatten)]
+    pub scrobble: Scrobble,
+}
+
+/// The Last.fm accounts of the users and their failed scrobbles.
+#[derive(Clone)]
+pub struct LastfmRepository {
+    pool: Arc<PgPool>,
+}
+
+impl Scrobble {
+    pub fn new(song: &Song, played_at: DateTime<Utc>) -> Self {
+        Self {
+            artist: song.artist.clone(),
+            track: song.name.clone(),
+            album: song.album.clone(),
+            duration: song.duration.and_then(|duration| duration.try_into().ok()),
+            played_at,
+        }
+    }
+}
+
+impl LastfmRepository {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Stores the Last.fm account of a user, replacing the previous one.
+    #[instrument(skip(self, session_key))]
+    pub async fn set_session(
+        &self,
+        spotify_user: &str,
+        username: &str,
+        session_key: &[u8],
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO lastfm_sessions (spotify_user, username, session_key)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (spotify_user) DO UPDATE SET username = $2, session_key = $3
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(username)
+        .bind(session_key)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns the Last.fm username and encrypted session key of a user.
+    #[instrument(skip(self))]
+    pub async fn session(&self, spotify_user: &str) -> Result<Option<(String, Vec<u8>)>> {
+        let session = sqlx::query_as(
+            "SELECT username, session_key FROM lastfm_sessions WHERE spotify_user = $1",
+        )
+        .bind(spotify_user)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    /// Forgets the Last.fm account of a user and their queued scrobbles,
+    /// returning whether they had one.
+    #[instrument(skip(self))]
+    pub async fn delete_session(&self, spotify_user: &str) -> Result<bool> {
+        let res = sqlx::query("DELETE FROM lastfm_sessions WHERE spotify_user = $1")
+            .bind(spotify_user)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Queues a scrobble that failed once, to be retried at `next_attempt_at`.
+    #[instrument(skip(self, scrobble))]
+    pub async fn enqueue(
+        &self,
+        spotify_user: &str,
+        scrobble: &Scrobble,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO scrobble_queue
+                (spotify_user, artist, track, album, duration, played_at, next_attempt_at, last_error)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(&scrobble.artist)
+        .bind(&scrobble.track)
+        .bind(&scrobble.album)
+        .bind(scrobble.duration)
+        .bind(scrobble.played_at)
+        .bind(next_attempt_at)
+        .bind(error)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns up to `limit` queued scrobbles due for a retry at `now`, oldest first.
+    #[instrument(skip(self))]
+    pub async fn due(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<QueuedScrobble>> {
+        let scrobbles = sqlx::query_as::<_, QueuedScrobble>(
+            r#"
+            SELECT q.id, q.spotify_user, s.session_key, q.attempts,
+                   q.artist, q.track, q.album, q.duration, q.played_at
+            FROM scrobble_queue q
+            JOIN lastfm_sessions s USING (spotify_user)
+            WHERE q.next_attempt_at <= $1
+            ORDER BY q.played_at
+            LIMIT $2
+            "#,
+        )
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(scrobbles)
+    }
+
+    /// Records another failed attempt of a queued scrobble.
+    #[instrument(skip(self))]
+    pub async fn retry_later(
+        &self,
+        id: Uuid,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE scrobble_queue
+            SET attempts = attempts + 1, next_attempt_at = $2, last_error = $3
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(next_attempt_at)
+        .bind(error)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Removes a scrobble from the queue, once sent or dropped.
+    #[instrument(skip(self))]
+    pub async fn dequeue(&self, id: Uuid) -> Result<()> {
+        sqlx::query("DELETE FROM scrobble_queue WHERE id = $1")
+            .bind(id)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -2,7 +2,12 @@ use serde::Serialize;
 use uuid::Uuid;
 
 pub mod config;
+pub mod history;
+pub mod lastfm;
+pub mod preferences;
+pub mod rules;
 pub mod songs;
+pub mod transfer;
 
 #[derive(Debug, Serialize, sqlx::FromRow)]
 pub struct Songs {
@@ -10,4 +15,15 @@ pub struct Songs {
     pub title: String,
     pub artist: String,
     pub youtube_id: String,
+    pub track_id: Option<String>,
+    /// Seconds to skip at the start of the video, for videos with an intro
+    pub start_offset: i32,
+    /// Whether the video was picked by hand rather than by the search
+    pub manual_override: bool,
+    /// The region this video was resolved for, only set for region restricted videos
+    pub region: Option<String>,
+    /// The preference profile this video was resolved for, see `VideoPreferences::profile`
+    pub profile: String,
+    /// The youtube channel of the video, unknown for songs cached before it was stored
+    pub channel_id: Option<String>,
 }
@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use spotify_music_vid::{
+    protocol::{VideoKind, VideoPreferences},
+    Result,
+};
+use sqlx::PgPool;
+use tracing::{instrument, warn};
+
+/// The video preferences of each Spotify user.
+#[derive(Clone)]
+pub struct PreferenceRepository {
+    pool: Arc<PgPool>,
+}
+
+impl PreferenceRepository {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Returns the preferences of a user, the defaults if they never set any.
+    #[instrument(skip(self))]
+    pub async fn get(&self, spotify_user: &str) -> Result<VideoPreferences> {
+        let row = sqlx::query_as::<_, (Option<String>, Vec<String>)>(
+            "SELECT prefer, exclude FROM user_preferences WHERE spotify_user = $1",
+        )
+        .bind(spotify_user)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        let Some((prefer, exclude)) = row else {
+            return Ok(VideoPreferences::default());
+        };
+        Ok(VideoPreferences {
+            prefer: prefer.as_deref().and_then(parse_kind),
+            exclude: exclude.iter().filter_map(|kind| parse_kind(kind)).collect(),
+        })
+    }
+
+    /// Stores the preferences of a user, replacing the previous ones.
+    #[instrument(skip(self))]
+    pub async fn set(&self, spotify_user: &str, preferences: &VideoPreferences) -> Result<()> {
+        let exclude: Vec<&str> = preferences
+            .exclude
+            .iter()
+            .map(|kind| kind.as_str())
+            .collect();
+        sqlx::query(
+            r#"
+            INSERT INTO user_preferences (spotify_user, prefer, exclude)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (spotify_user) DO UPDATE SET prefer = $2, exclude = $3
+            "#,
+        )
+        .bind(spotify_user)
+        .bind(preferences.prefer.map(VideoKind::as_str))
+        .bind(exclude)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Parses a stored kind, kinds removed since they were stored are ignored.
+fn parse_kind(value: &str) -> Option<VideoKind> {
+    let kind = VideoKind::parse(value);
+    if kind.is_none() {
+        warn!("Ignoring unknown video kind {value:?}");
+    }
+    kind
+}
@@ -0,0 +1,162 @@
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use spotify_music_vid::Result;
+use sqlx::PgPool;
+use tracing::{instrument, warn};
+use uuid::Uuid;
+
+/// What a [`VideoRule`] applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleKind {
+    /// Every video uploaded by a channel
+    Channel,
+    /// A single video
+    Video,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// The videos are never played
+    Block,
+    /// The videos are ranked above the others
+    Allow,
+}
+
+/// A youtube channel or video blocked or allowed by an admin.
+#[derive(Debug, Clone, Serialize)]
+pub struct VideoRule {
+    pub id: Uuid,
+    pub kind: RuleKind,
+    /// The channel or video id
+    pub target: String,
+    pub action: RuleAction,
+    pub note: Option<String>,
+}
+
+type RuleRow = (Uuid, String, String, String, Option<String>);
+
+#[derive(Clone)]
+pub struct RuleRepository {
+    pool: Arc<PgPool>,
+}
+
+impl RuleKind {
+    const ALL: [Self; 2] = [Self::Channel, Self::Video];
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Channel => "channel",
+            Self::Video => "video",
+        }
+    }
+
+    /// Parses a kind as stored in the database.
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+}
+
+impl RuleAction {
+    const ALL: [Self; 2] = [Self::Block, Self::Allow];
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Block => "block",
+            Self::Allow => "allow",
+        }
+    }
+
+    /// Parses an action as stored in the database.
+    fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|action| action.as_str() == value)
+    }
+}
+
+impl VideoRule {
+    /// Reads a stored rule, rules of an unknown kind or action are skipped.
+    fn from_row((id, kind, target, action, note): RuleRow) -> Option<Self> {
+        let Some(parsed_kind) = RuleKind::parse(&kind) else {
+            warn!("Ignoring rule {id} of unknown kind {kind:?}");
+            return None;
+        };
+        let Some(parsed_action) = RuleAction::parse(&action) else {
+            warn!("Ignoring rule {id} with unknown action {action:?}");
+            return None;
+        };
+        Some(Self {
+            id,
+            kind: parsed_kind,
+            target,
+            action: parsed_action,
+            note,
+        })
+    }
+}
+
+impl RuleRepository {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Returns every rule ordered by kind and target.
+    #[instrument(skip(self))]
+    pub async fn list(&self) -> Result<Vec<VideoRule>> {
+        let rows = sqlx::query_as::<_, RuleRow>(
+            "SELECT id, kind, target, action, note FROM video_rules ORDER BY kind, target",
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows.into_iter().filter_map(VideoRule::from_row).collect())
+    }
+
+    /// Blocks or allows a channel or video, replacing the rule already set for it.
+    #[instrument(skip(self))]
+    pub async fn set(
+        &self,
+        kind: RuleKind,
+        target: &str,
+        action: RuleAction,
+        note: Option<&str>,
+    ) -> Result<VideoRule> {
+        let row = sqlx::query_as::<_, RuleRow>(
+            r#"
+            INSERT INTO video_rules (kind, target, action, note)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (kind, target) DO UPDATE SET action = $3, note = $4
+            RETURNING id, kind, target, action, note
+            "#,
+        )
+        .bind(kind.as_str())
+        .bind(target)
+        .bind(action.as_str())
+        .bind(note)
+        .fetch_one(&*self.pool)
+        .await?;
+
+        Ok(VideoRule {
+            id: row.0,
+            kind,
+            target: row.2,
+            action,
+            note: row.4,
+        })
+    }
+
+    /// Deletes a rule, returning whether a row was removed.
+    #[instrument(skip(self))]
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        let res = sqlx::query("DELETE FROM video_rules WHERE id = $1")
+            .bind(id)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(res.rows_affected() > 0)
+    }
+}
@@ -1,53 +1,316 @@
 use std::sync::Arc;
 
-use color_eyre::eyre::Result;
-use spotify_music_vid::Song;
+use serde::Deserialize;
+use spotify_music_vid::{Result, Song};
 use sqlx::PgPool;
 use tracing::instrument;
+use uuid::Uuid;
 
-use super::Songs;
+use super::{rules::RuleKind, transfer::CacheEntry, Songs};
+use crate::metrics::DB_QUERY_DURATION;
 
+#[derive(Clone)]
 pub struct SongRepository {
     pool: Arc<PgPool>,
 }
 
+/// Case insensitive substring filters, unset fields match every song.
+#[derive(Debug, Default, Deserialize)]
+pub struct SongFilter {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    /// Matches either the artist or the title
+    pub q: Option<String>,
+}
+
+impl SongFilter {
+    fn artist_pattern(&self) -> Option<String> {
+        self.artist.as_deref().map(like_pattern)
+    }
+
+    fn title_pattern(&self) -> Option<String> {
+        self.title.as_deref().map(like_pattern)
+    }
+
+    fn query_pattern(&self) -> Option<String> {
+        self.q.as_deref().map(like_pattern)
+    }
+}
+
+/// Escapes the `LIKE` wildcards of `value` and wraps it for a substring match.
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
 impl SongRepository {
     pub fn new(pool: Arc<PgPool>) -> Self {
         Self { pool }
     }
 
+    /// Caches the video of a song for a preference profile,
+    /// `region` is set for region restricted videos.
     #[instrument(skip(self))]
-    pub async fn create(&self, song: Song, song_id: &String) -> Result<()> {
+    pub async fn create(
+        &self,
+        song: Song,
+        song_id: &String,
+        channel_id: Option<&str>,
+        region: Option<&str>,
+        profile: &str,
+    ) -> Result<()> {
+        let _timer = DB_QUERY_DURATION
+            .with_label_values(&["create"])
+            .start_timer();
         sqlx::query_as!(
             Songs,
             r#"
-            insert into songs (title, ARTIST, YOUTUBE_ID)
-            values ($1, $2, $3)
+            insert into songs (title, ARTIST, YOUTUBE_ID, TRACK_ID, REGION, PROFILE, CHANNEL_ID)
+            values ($1, $2, $3, $4, $5, $6, $7)
             returning *
             "#,
             song.name,
             song.artist,
-            song_id
+            song_id,
+            song.track_id,
+            region,
+            profile,
+            channel_id
         )
         .fetch_one(&*self.pool)
         .await?;
 
         Ok(())
     }
+    /// Returns the cached video of a song for a preference profile playable in `region`,
+    /// preferring a video cached for that region over one cached for every region.
+    /// # Errors
+    /// This function will return an error if the database can not be queried.
     #[instrument(skip(self))]
-    pub async fn get(&self, song: &Song) -> Option<String> {
+    pub async fn get(
+        &self,
+        song: &Song,
+        region: Option<&str>,
+        profile: &str,
+    ) -> Result<Option<Songs>> {
+        let _timer = DB_QUERY_DURATION.with_label_values(&["get"]).start_timer();
         let song = sqlx::query_as::<_, Songs>(
             r#"
             SELECT * FROM songs
-            WHERE title = $1 AND artist = $2
+            WHERE title = $1 AND artist = $2 AND (region IS NULL OR region = $3) AND profile = $4
+            ORDER BY region IS NULL
+            LIMIT 1
             "#,
         )
         .bind(song.name.to_string())
         .bind(song.artist.to_string())
+        .bind(region)
+        .bind(profile)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(song)
+    }
+
+    /// Returns a page of cached songs ordered by artist and title.
+    #[instrument(skip(self))]
+    pub async fn list(&self, limit: i64, offset: i64) -> Result<Vec<Songs>> {
+        self.search(&SongFilter::default(), limit, offset).await
+    }
+
+    /// Returns a page of the cached songs matching the filter, ordered by artist and title.
+    #[instrument(skip(self))]
+    pub async fn search(&self, filter: &SongFilter, limit: i64, offset: i64) -> Result<Vec<Songs>> {
+        let songs = sqlx::query_as::<_, Songs>(
+            r#"
+            SELECT * FROM songs
+            WHERE ($1::text IS NULL OR artist ILIKE $1)
+              AND ($2::text IS NULL OR title ILIKE $2)
+              AND ($3::text IS NULL OR artist ILIKE $3 OR title ILIKE $3)
+            ORDER BY artist, title
+            LIMIT $4 OFFSET $5
+            "#,
+        )
+        .bind(filter.artist_pattern())
+        .bind(filter.title_pattern())
+        .bind(filter.query_pattern())
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(songs)
+    }
+
+    /// Returns the number of cached songs matching the filter.
+    #[instrument(skip(self))]
+    pub async fn count(&self, filter: &SongFilter) -> Result<i64> {
+        let count = sqlx::query_scalar(
+            r#"
+            SELECT COUNT(*) FROM songs
+            WHERE ($1::text IS NULL OR artist ILIKE $1)
+              AND ($2::text IS NULL OR title ILIKE $2)
+              AND ($3::text IS NULL OR artist ILIKE $3 OR title ILIKE $3)
+            "#,
+        )
+        .bind(filter.artist_pattern())
+        .bind(filter.title_pattern())
+        .bind(filter.query_pattern())
         .fetch_one(&*self.pool)
-        .await
-        .ok()?;
+        .await?;
+
+        Ok(count)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn find(&self, id: Uuid) -> Result<Option<Songs>> {
+        let song = sqlx::query_as::<_, Songs>("SELECT * FROM songs WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&*self.pool)
+            .await?;
+
+        Ok(song)
+    }
+
+    /// Returns every cached song ordered by artist and title.
+    #[instrument(skip(self))]
+    pub async fn all(&self) -> Result<Vec<Songs>> {
+        let songs = sqlx::query_as::<_, Songs>("SELECT * FROM songs ORDER BY artist, title")
+            .fetch_all(&*self.pool)
+            .await?;
+
+        Ok(songs)
+    }
+
+    /// Inserts a song with all of its metadata, as read from an export.
+    #[instrument(skip(self))]
+    pub async fn insert(&self, entry: &CacheEntry) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO songs (title, artist, youtube_id, track_id, start_offset, manual_override, region, profile, channel_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            "#,
+        )
+        .bind(&entry.title)
+        .bind(&entry.artist)
+        .bind(&entry.youtube_id)
+        .bind(&entry.track_id)
+        .bind(entry.start_offset)
+        .bind(entry.manual_override)
+        .bind(&entry.region)
+        .bind(&entry.profile)
+        .bind(&entry.channel_id)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replaces the video and metadata of an existing song.
+    #[instrument(skip(self))]
+    pub async fn update(&self, id: Uuid, entry: &CacheEntry) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE songs
+            SET youtube_id = $2, track_id = $3, start_offset = $4, manual_override = $5, channel_id = $6
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(&entry.youtube_id)
+        .bind(&entry.track_id)
+        .bind(entry.start_offset)
+        .bind(entry.manual_override)
+        .bind(&entry.channel_id)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replaces the video of a cached song by hand, returning the updated song if it exists.
+    #[instrument(skip(self))]
+    pub async fn set_youtube_id(&self, id: Uuid, youtube_id: &str) -> Result<Option<Songs>> {
+        let song = sqlx::query_as::<_, Songs>(
+            r#"
+            UPDATE songs
+            SET youtube_id = $2, manual_override = true, channel_id = NULL
+            WHERE id = $1
+            RETURNING *
+            "#,
+        )
+        .bind(id)
+        .bind(youtube_id)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(song)
+    }
+
+    /// Deletes every cached song of an artist, returning the number of rows removed.
+    #[instrument(skip(self))]
+    pub async fn delete_by_artist(&self, artist: &str) -> Result<u64> {
+        let res = sqlx::query("DELETE FROM songs WHERE artist = $1")
+            .bind(artist)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Returns the cached videos whose channel is unknown,
+    /// cached before channels were stored or picked by hand.
+    #[instrument(skip(self))]
+    pub async fn videos_without_channel(&self) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar::<_, String>(
+            "SELECT DISTINCT youtube_id FROM songs WHERE channel_id IS NULL ORDER BY youtube_id",
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(ids)
+    }
+
+    /// Stores the channel of a cached video, returning the number of rows updated.
+    #[instrument(skip(self))]
+    pub async fn set_channel(&self, youtube_id: &str, channel_id: &str) -> Result<u64> {
+        let res = sqlx::query(
+            "UPDATE songs SET channel_id = $2 WHERE youtube_id = $1 AND channel_id IS NULL",
+        )
+        .bind(youtube_id)
+        .bind(channel_id)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Deletes every cached song pointing to a blocked channel or video,
+    /// returning the number of rows removed.
+    /// Songs whose channel is unknown are only removed by channel once
+    /// [`crate::youtube_client::YoutubeClient::resolve_channels`] stored it.
+    #[instrument(skip(self))]
+    pub async fn delete_by_rule(&self, kind: RuleKind, target: &str) -> Result<u64> {
+        let query = match kind {
+            RuleKind::Channel => "DELETE FROM songs WHERE channel_id = $1",
+            RuleKind::Video => "DELETE FROM songs WHERE youtube_id = $1",
+        };
+        let res = sqlx::query(query).bind(target).execute(&*self.pool).await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Deletes a cached song, returning whether a row was removed.
+    #[instrument(skip(self))]
+    pub async fn delete(&self, id: Uuid) -> Result<bool> {
+        let res = sqlx::query("DELETE FROM songs WHERE id = $1")
+            .bind(id)
+            .execute(&*self.pool)
+            .await?;
 
-        Some(song.youtube_id)
+        Ok(res.rows_affected() > 0)
     }
 }
@@ -0,0 +1,176 @@
+use std::io::{BufRead, Write};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use spotify_music_vid::{protocol::VideoPreferences, Song};
+use tracing::{info, instrument};
+
+use super::{songs::SongRepository, Songs};
+
+/// A cached video as written to and read from an export file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub title: String,
+    pub artist: String,
+    pub track_id: Option<String>,
+    pub youtube_id: String,
+    #[serde(default)]
+    pub start_offset: i32,
+    #[serde(default)]
+    pub manual_override: bool,
+    #[serde(default)]
+    pub region: Option<String>,
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    #[serde(default)]
+    pub channel_id: Option<String>,
+}
+
+/// Exports written before videos were cached per preference profile
+fn default_profile() -> String {
+    VideoPreferences::DEFAULT_PROFILE.to_string()
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// One JSON object per line
+    Jsonl,
+    Csv,
+}
+
+/// What to do when an imported entry is already cached.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the cached entry
+    Skip,
+    /// Replace the cached entry with the imported one
+    Overwrite,
+    /// Keep whichever entry was picked by hand, the imported one wins ties
+    PreferOverride,
+}
+
+/// Counts of what an import did with the entries it read.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub inserted: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+impl From<Songs> for CacheEntry {
+    fn from(song: Songs) -> Self {
+        Self {
+            title: song.title,
+            artist: song.artist,
+            track_id: song.track_id,
+            youtube_id: song.youtube_id,
+            start_offset: song.start_offset,
+            manual_override: song.manual_override,
+            region: song.region,
+            profile: song.profile,
+            channel_id: song.channel_id,
+        }
+    }
+}
+
+impl ConflictPolicy {
+    /// Returns whether the imported entry should replace the cached one.
+    const fn replaces(self, existing: &Songs, incoming: &CacheEntry) -> bool {
+        match self {
+            Self::Skip => false,
+            Self::Overwrite => true,
+            Self::PreferOverride => incoming.manual_override || !existing.manual_override,
+        }
+    }
+}
+
+/// Writes every cached song to `out`, returning the number of entries written.
+/// # Errors
+/// This function will return an error if the songs can not be read or written.
+#[instrument(skip(repo, out))]
+pub async fn export(repo: &SongRepository, format: Format, out: impl Write) -> Result<usize> {
+    let songs = repo.all().await?;
+    let count = songs.len();
+    let entries = songs.into_iter().map(CacheEntry::from);
+
+    match format {
+        Format::Jsonl => {
+            let mut out = out;
+            for entry in entries {
+                serde_json::to_writer(&mut out, &entry)?;
+                writeln!(out)?;
+            }
+            out.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for entry in entries {
+                writer.serialize(entry)?;
+            }
+            writer.flush()?;
+        }
+    }
+    info!("Exported {count} songs");
+    Ok(count)
+}
+
+/// Reads entries from `input` and merges them into the cache.
+/// Entries are matched with cached songs by title, artist, region and preference profile.
+/// # Errors
+/// This function will return an error if an entry is malformed or the database fails,
+/// entries before the failing one are kept.
+#[instrument(skip(repo, input))]
+pub async fn import(
+    repo: &SongRepository,
+    format: Format,
+    policy: ConflictPolicy,
+    input: impl BufRead,
+) -> Result<ImportSummary> {
+    let entries = match format {
+        Format::Jsonl => read_jsonl(input)?,
+        Format::Csv => csv::Reader::from_reader(input)
+            .deserialize()
+            .collect::<Result<Vec<CacheEntry>, _>>()?,
+    };
+
+    let mut summary = ImportSummary::default();
+    for entry in entries {
+        let song = Song::new(entry.title.clone(), entry.artist.clone(), 0);
+        match repo
+            .get(&song, entry.region.as_deref(), &entry.profile)
+            .await?
+        {
+            // the entry and the cached video are for different regions, keep both
+            Some(existing) if existing.region != entry.region => {
+                repo.insert(&entry).await?;
+                summary.inserted += 1;
+            }
+            None => {
+                repo.insert(&entry).await?;
+                summary.inserted += 1;
+            }
+            Some(existing) if policy.replaces(&existing, &entry) => {
+                repo.update(existing.id, &entry).await?;
+                summary.updated += 1;
+            }
+            Some(_) => summary.skipped += 1,
+        }
+    }
+    info!(?summary, "Imported songs");
+    Ok(summary)
+}
+
+fn read_jsonl(input: impl BufRead) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid entry on line {}", number + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
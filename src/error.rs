@@ -0,0 +1,136 @@
+//! Errors returned by the library and the server, each with a stable [`ErrorCode`]
+//! that is sent to the client.
+
+use rspotify::ClientError;
+use serde::Serialize;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The authorization code flow with Spotify failed, or the code could not be exchanged for a token
+    #[error("Spotify authorization failed: {0}")]
+    Auth(#[source] ClientError),
+    #[error("Spotify request failed: {0}")]
+    Spotify(#[from] ClientError),
+    /// A local player could not be read
+    #[error("Local player request failed: {0}")]
+    Player(String),
+    /// A recording of the polls could not be written or read
+    #[error("Recording failed: {0}")]
+    Recording(#[source] std::io::Error),
+    #[error("Youtube request failed: {0}")]
+    Youtube(#[source] reqwest::Error),
+    #[error("Youtube returned status {0}")]
+    YoutubeStatus(reqwest::StatusCode),
+    #[error("Youtube quota exceeded")]
+    QuotaExceeded,
+    /// A video provider did not answer in time
+    #[error("Video provider {0} timed out")]
+    Timeout(String),
+    /// A video provider is skipped after failing repeatedly
+    #[error("Video provider {0} is unavailable")]
+    ProviderUnavailable(String),
+    #[error("No video found")]
+    NoVideo,
+    /// A database query failed, the details are only logged
+    #[error("Database query failed: {0}")]
+    Storage(#[from] sqlx::Error),
+    /// The client sent something that is not a valid message
+    #[error("Invalid message: {0}")]
+    Protocol(String),
+    /// The websocket connection with the client failed
+    #[error("Websocket connection failed: {0}")]
+    Connection(#[from] warp::Error),
+    #[error("No song is currently playing")]
+    NothingPlaying,
+    /// An ad, or an item Spotify doesn't describe, is playing
+    #[error("The playing item is not a track or an episode")]
+    UnsupportedItem,
+    #[error("The cache is disabled")]
+    CacheDisabled,
+    #[error("Last.fm request failed: {0}")]
+    Lastfm(#[source] reqwest::Error),
+    #[error("Last.fm returned status {0}")]
+    LastfmStatus(reqwest::StatusCode),
+    /// An error reported by the Last.fm api, see <https://www.last.fm/api/errorcodes>
+    #[error("Last.fm error {code}: {message}")]
+    LastfmApi { code: u64, message: String },
+    #[error("Last.fm returned an invalid response: {0}")]
+    LastfmResponse(#[source] serde_json::Error),
+    /// A Last.fm session key could not be encrypted before it is stored
+    #[error("Encrypting the Last.fm session key failed")]
+    LastfmEncryption,
+    #[error("Last.fm scrobbling is disabled")]
+    LastfmDisabled,
+}
+
+/// Identifies the kind of an [`Error`] for clients, these values never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Auth,
+    Spotify,
+    Player,
+    Recording,
+    Youtube,
+    QuotaExceeded,
+    Timeout,
+    ProviderUnavailable,
+    NoVideo,
+    Storage,
+    Protocol,
+    Connection,
+    NothingPlaying,
+    UnsupportedItem,
+    CacheDisabled,
+    Lastfm,
+    LastfmDisabled,
+}
+
+impl Error {
+    #[must_use]
+    pub const fn code(&self) -> ErrorCode {
+        match self {
+            Self::Auth(_) => ErrorCode::Auth,
+            Self::Spotify(_) => ErrorCode::Spotify,
+            Self::Player(_) => ErrorCode::Player,
+            Self::Recording(_) => ErrorCode::Recording,
+            Self::Youtube(_) | Self::YoutubeStatus(_) => ErrorCode::Youtube,
+            Self::QuotaExceeded => ErrorCode::QuotaExceeded,
+            Self::Timeout(_) => ErrorCode::Timeout,
+            Self::ProviderUnavailable(_) => ErrorCode::ProviderUnavailable,
+            Self::NoVideo => ErrorCode::NoVideo,
+            Self::Storage(_) => ErrorCode::Storage,
+            Self::Protocol(_) => ErrorCode::Protocol,
+            Self::Connection(_) => ErrorCode::Connection,
+            Self::NothingPlaying => ErrorCode::NothingPlaying,
+            Self::UnsupportedItem => ErrorCode::UnsupportedItem,
+            Self::CacheDisabled => ErrorCode::CacheDisabled,
+            Self::Lastfm(_)
+            | Self::LastfmStatus(_)
+            | Self::LastfmApi { .. }
+            | Self::LastfmResponse(_)
+            | Self::LastfmEncryption => ErrorCode::Lastfm,
+            Self::LastfmDisabled => ErrorCode::LastfmDisabled,
+        }
+    }
+
+    /// Returns the message sent to the client, database errors are not described
+    /// as they would show the queries and the schema.
+    #[must_use]
+    pub fn client_message(&self) -> String {
+        match self {
+            Self::Storage(_) => "The data could not be read or stored".to_string(),
+            e => e.to_string(),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        // the request url contains the youtube api key
+        Self::Youtube(err.without_url())
+    }
+}
@@ -0,0 +1,95 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use spotify_music_vid::{Error, Result};
+
+use crate::db::config::Secret;
+
+/// Bytes of the nonce stored before each encrypted session key
+const NONCE_LEN: usize = 12;
+/// Ties the derived key to the session keys, so it differs from keys derived for other uses
+const KEY_INFO: &[u8] = b"spotify-music-vid lastfm session key";
+
+/// Encrypts the Last.fm session keys of the users before they are stored.
+pub struct SessionCipher(Aes256Gcm);
+
+impl SessionCipher {
+    pub fn new(key: &Secret) -> Self {
+        // the configured key can have any length, HKDF derives the 256 bits of the cipher from it
+        let mut derived = Key::<Aes256Gcm>::default();
+        Hkdf::<Sha256>::new(None, key.expose().as_bytes())
+            .expand(KEY_INFO, &mut derived)
+            .expect("HKDF-SHA256 can derive 256 bits");
+        Self(Aes256Gcm::new(&derived))
+    }
+
+    /// Returns the encrypted session key prefixed by its random nonce.
+    /// # Errors
+    /// This function will return [`Error::LastfmEncryption`] if the session key can't be encrypted.
+    pub fn encrypt(&self, session_key: &str) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, session_key.as_bytes())
+            .map_err(|_| Error::LastfmEncryption)?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Returns `None` if the session key was encrypted with another key, or altered.
+    pub fn decrypt(&self, data: &[u8]) -> Option<String> {
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let session_key = self.0.decrypt(Nonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(session_key).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher(key: &str) -> SessionCipher {
+        SessionCipher::new(&Secret::from(key))
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypted() {
+        let cipher = cipher("a configured key");
+        let encrypted = cipher.encrypt("d580d57f32848f5dcf574d1ce18d78b2").unwrap();
+        assert_eq!(
+            cipher.decrypt(&encrypted).as_deref(),
+            Some("d580d57f32848f5dcf574d1ce18d78b2")
+        );
+    }
+
+    #[test]
+    fn every_encryption_uses_a_new_nonce() {
+        let cipher = cipher("a configured key");
+        let first = cipher.encrypt("session").unwrap();
+        let second = cipher.encrypt("session").unwrap();
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn another_key_can_not_decrypt() {
+        let encrypted = cipher("a configured key").encrypt("session").unwrap();
+        assert_eq!(cipher("another key").decrypt(&encrypted), None);
+    }
+
+    #[test]
+    fn altered_or_truncated_data_is_rejected() {
+        let cipher = cipher("a configured key");
+        let mut encrypted = cipher.encrypt("session").unwrap();
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert_eq!(cipher.decrypt(&encrypted), None);
+        assert_eq!(cipher.decrypt(&encrypted[..NONCE_LEN - 1]), None);
+        assert_eq!(cipher.decrypt(&[]), None);
+    }
+}
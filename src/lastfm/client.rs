@@ -0,0 +1,195 @@
+use md5::{Digest, Md5};
+use reqwest::Client;
+use serde::{de::DeserializeOwned, de::IgnoredAny, Deserialize};
+use spotify_music_vid::{Error, Result};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::db::{
+    config::{LastfmConfig, Secret},
+    lastfm::Scrobble,
+};
+
+/// An authenticated Last.fm session.
+#[derive(Debug, Deserialize)]
+pub struct Session {
+    /// The Last.fm username
+    pub name: String,
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+struct SessionResponse {
+    session: Session,
+}
+
+/// The body of a failed request, see <https://www.last.fm/api/errorcodes>
+#[derive(Deserialize)]
+struct ApiError {
+    error: u64,
+    message: String,
+}
+
+/// Signed calls to the Last.fm api.
+#[derive(Clone)]
+pub struct LastfmClient {
+    client: Client,
+    base_url: String,
+    api_key: String,
+    api_secret: Secret,
+}
+
+impl LastfmClient {
+    pub fn new(client: Client, config: &LastfmConfig) -> Self {
+        Self {
+            client,
+            base_url: config.base_url.clone(),
+            api_key: config.api_key.trim().to_string(),
+            api_secret: config.api_secret.clone(),
+        }
+    }
+
+    pub fn api_key(&self) -> &str {
+        &self.api_key
+    }
+
+    /// Exchanges the token Last.fm gave the user once they allowed scrobbling for a session.
+    /// # Errors
+    /// This function will return an error if the token is invalid or the request fails.
+    #[instrument(skip_all)]
+    pub async fn get_session(&self, token: &str) -> Result<Session> {
+        let res: SessionResponse = self
+            .call("auth.getSession", vec![("token", token.to_string())])
+            .await?;
+        Ok(res.session)
+    }
+
+    /// Tells Last.fm what the user started listening to.
+    /// # Errors
+    /// This function will return an error if the request fails.
+    #[instrument(skip(self, session_key))]
+    pub async fn update_now_playing(&self, session_key: &str, scrobble: &Scrobble) -> Result<()> {
+        let mut params = track_params(scrobble);
+        params.push(("sk", session_key.to_string()));
+        self.call::<IgnoredAny>("track.updateNowPlaying", params)
+            .await?;
+        Ok(())
+    }
+
+    /// Adds a track to the user's listening history.
+    /// # Errors
+    /// This function will return an error if the request fails.
+    #[instrument(skip(self, session_key))]
+    pub async fn scrobble(&self, session_key: &str, scrobble: &Scrobble) -> Result<()> {
+        let mut params = track_params(scrobble);
+        params.push(("timestamp", scrobble.played_at.timestamp().to_string()));
+        params.push(("sk", session_key.to_string()));
+        self.call::<IgnoredAny>("track.scrobble", params).await?;
+        Ok(())
+    }
+
+    /// Calls a method of the api with a signed request.
+    /// # Errors
+    /// This function will return an error if the request fails, or if Last.fm answers with an error.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        mut params: Vec<(&'static str, String)>,
+    ) -> Result<T> {
+        params.push(("method", method.to_string()));
+        params.push(("api_key", self.api_key.clone()));
+        let signature = sign(&mut params, self.api_secret.expose());
+        params.push(("api_sig", signature));
+        params.push(("format", "json".to_string()));
+
+        let res = self
+            .client
+            .post(&self.base_url)
+            .form(&params)
+            .send()
+            .instrument(info_span!("lastfm_request", method))
+            .await
+            .map_err(Error::Lastfm)?;
+        let status = res.status();
+        let body = res.text().await.map_err(Error::Lastfm)?;
+        if let Ok(ApiError { error, message }) = serde_json::from_str(&body) {
+            return Err(Error::LastfmApi {
+                code: error,
+                message,
+            });
+        }
+        if !status.is_success() {
+            return Err(Error::LastfmStatus(status));
+        }
+        serde_json::from_str(&body).map_err(Error::LastfmResponse)
+    }
+}
+
+fn track_params(scrobble: &Scrobble) -> Vec<(&'static str, String)> {
+    let mut params = vec![
+        ("artist", scrobble.artist.clone()),
+        ("track", scrobble.track.clone()),
+    ];
+    if let Some(album) = &scrobble.album {
+        params.push(("album", album.clone()));
+    }
+    if let Some(duration) = scrobble.duration {
+        params.push(("duration", duration.to_string()));
+    }
+    params
+}
+
+/// Sorts the parameters by name and returns their signature,
+/// the md5 of every name and value followed by the api secret.
+fn sign(params: &mut [(&'static str, String)], secret: &str) -> String {
+    params.sort_unstable();
+    let mut hasher = Md5::new();
+    for (name, value) in params.iter() {
+        hasher.update(name.as_bytes());
+        hasher.update(value.as_bytes());
+    }
+    hasher.update(secret.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_sorted_parameters_with_the_secret() {
+        let mut params = vec![
+            ("token", "tok".to_string()),
+            ("method", "auth.getSession".to_string()),
+            ("api_key", "key".to_string()),
+        ];
+        // md5("api_keykeymethodauth.getSessiontokentoksecret")
+        assert_eq!(
+            sign(&mut params, "secret"),
+            "04e870be4bb79756721b7bc1937fe83d"
+        );
+        assert_eq!(params[0].0, "api_key");
+    }
+
+    #[test]
+    fn sends_the_album_and_duration_when_known() {
+        let mut scrobble = Scrobble {
+            artist: "Daft Punk".to_string(),
+            track: "Around the World".to_string(),
+            album: Some("Homework".to_string()),
+            duration: Some(429),
+            played_at: chrono::Utc::now(),
+        };
+        assert_eq!(
+            track_params(&scrobble),
+            [
+                ("artist", "Daft Punk".to_string()),
+                ("track", "Around the World".to_string()),
+                ("album", "Homework".to_string()),
+                ("duration", "429".to_string()),
+            ]
+        );
+        scrobble.album = None;
+        scrobble.duration = None;
+        assert_eq!(track_params(&scrobble).len(), 2);
+    }
+}
@@ -0,0 +1,605 @@
+//! Scrobbling to Last.fm for the users who connected their account.
+//!
+//! "Now playing" is sent when a track starts, and the track is scrobbled once it played
+//! for half its duration or 4 minutes. Scrobbles that fail with a temporary error are
+//! queued in the database and retried with an exponential backoff.
+
+mod cipher;
+mod client;
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use spotify_music_vid::{clock::SharedClock, schedule::Backoff, Error, Result};
+use sqlx::PgPool;
+use tracing::{debug, error, info, instrument, warn, Instrument};
+
+use crate::{
+    db::{
+        config::LastfmConfig,
+        lastfm::{LastfmRepository, QueuedScrobble, Scrobble},
+    },
+    metrics::{SCROBBLES, SESSION_DECRYPT_FAILURES},
+};
+use cipher::SessionCipher;
+use client::LastfmClient;
+
+/// Queued scrobbles retried at once by [`Scrobbler::retry_queued`]
+const RETRY_BATCH: i64 = 50;
+/// Tracks up to this many seconds long are never scrobbled
+const MIN_DURATION: i32 = 30;
+/// Tracks are scrobbled once they played for this many seconds, even if it is less than half
+const SCROBBLE_AFTER: i32 = 240;
+/// The delay between the retries of a scrobble stops doubling after this many attempts
+const MAX_DOUBLINGS: u32 = 10;
+
+/// Sends the plays of the connected users to Last.fm.
+#[derive(Clone)]
+pub struct Scrobbler {
+    client: LastfmClient,
+    cipher: Arc<SessionCipher>,
+    repo: LastfmRepository,
+    auth_url: String,
+    retry_delay: Duration,
+    backoff: Backoff,
+    max_attempts: u32,
+    clock: SharedClock,
+}
+
+impl Scrobbler {
+    /// Creates a [`Scrobbler`], or returns `None` when scrobbling is disabled.
+    /// Retries are timed with `clock`.
+    pub fn new(
+        config: &LastfmConfig,
+        pool: Arc<PgPool>,
+        client: reqwest::Client,
+        clock: SharedClock,
+    ) -> Option<Self> {
+        if !config.enabled() {
+            return None;
+        }
+        let retry_delay = config.retry_delay();
+        Some(Self {
+            client: LastfmClient::new(client, config),
+            cipher: Arc::new(SessionCipher::new(&config.encryption_key)),
+            repo: LastfmRepository::new(pool),
+            auth_url: config.auth_url.clone(),
+            retry_delay,
+            backoff: Backoff::new(
+                retry_delay,
+                retry_delay.saturating_mul(2_u32.pow(MAX_DOUBLINGS)),
+            ),
+            max_attempts: config.max_attempts,
+            clock,
+        })
+    }
+
+    /// Returns the page where users allow the scrobbles,
+    /// Last.fm then gives them the token expected by [`Scrobbler::connect`].
+    pub fn auth_url(&self) -> String {
+        url::Url::parse_with_params(&self.auth_url, [("api_key", self.client.api_key())])
+            .map_or_else(|_| self.auth_url.clone(), String::from)
+    }
+
+    /// Connects a user to the Last.fm account that allowed the scrobbles, returning its username.
+    /// # Errors
+    /// This function will return an error if Last.fm rejects the token or the session can't be stored.
+    #[instrument(skip(self, token))]
+    pub async fn connect(&self, spotify_user: &str, token: &str) -> Result<String> {
+        let session = self.client.get_session(token).await?;
+        let session_key = self.cipher.encrypt(&session.key)?;
+        self.repo
+            .set_session(spotify_user, &session.name, &session_key)
+            .await?;
+        info!(username = session.name, "Connected to Last.fm");
+        Ok(session.name)
+    }
+
+    /// Disconnects a user from Last.fm, dropping their queued scrobbles.
+    /// # Errors
+    /// This function will return an error if the session can't be deleted.
+    pub async fn disconnect(&self, spotify_user: &str) -> Result<bool> {
+        self.repo.delete_session(spotify_user).await
+    }
+
+    /// Sends "now playing" in the background, failures are only logged.
+    pub fn now_playing(&self, spotify_user: &str, scrobble: Scrobble) {
+        let scrobbler = self.clone();
+        let spotify_user = spotify_user.to_string();
+        tokio::spawn(
+            async move {
+                let Some(session_key) = scrobbler.session_key(&spotify_user).await else {
+                    return;
+                };
+                match scrobbler
+                    .client
+                    .update_now_playing(&session_key, &scrobble)
+                    .await
+                {
+                    Ok(()) => debug!("Sent now playing to Last.fm"),
+                    Err(e) => {
+                        warn!("Failed to send now playing to Last.fm: {e}");
+                        scrobbler.forget_invalid_session(&spotify_user, &e).await;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Scrobbles a track in the background, it is queued for a retry if Last.fm is unavailable.
+    pub fn scrobble(&self, spotify_user: &str, scrobble: Scrobble) {
+        let scrobbler = self.clone();
+        let spotify_user = spotify_user.to_string();
+        tokio::spawn(
+            async move {
+                let Some(session_key) = scrobbler.session_key(&spotify_user).await else {
+                    return;
+                };
+                let Err(e) = scrobbler.client.scrobble(&session_key, &scrobble).await else {
+                    info!("Scrobbled to Last.fm");
+                    SCROBBLES.with_label_values(&["ok"]).inc();
+                    return;
+                };
+                if !is_temporary(&e) {
+                    error!("Failed to scrobble to Last.fm: {e}");
+                    SCROBBLES.with_label_values(&["failed"]).inc();
+                    scrobbler.forget_invalid_session(&spotify_user, &e).await;
+                    return;
+                }
+                warn!("Failed to scrobble to Last.fm, retrying later: {e}");
+                let next_attempt_at = scrobbler.next_attempt_at(1);
+                match scrobbler
+                    .repo
+                    .enqueue(&spotify_user, &scrobble, next_attempt_at, &e.to_string())
+                    .await
+                {
+                    Ok(()) => SCROBBLES.with_label_values(&["queued"]).inc(),
+                    Err(e) => {
+                        error!("Failed to queue the scrobble: {e}");
+                        SCROBBLES.with_label_values(&["failed"]).inc();
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Retries the queued scrobbles every `lastfm.retry_secs`, starting immediately.
+    pub async fn run_retries(self) {
+        loop {
+            if let Err(e) = self.retry_queued().await {
+                error!("Failed to retry the queued scrobbles: {e}");
+            }
+            self.clock.sleep(self.retry_delay).await;
+        }
+    }
+
+    /// Sends the queued scrobbles that are due.
+    /// Scrobbles failing for good, or too many times, are dropped.
+    /// # Errors
+    /// This function will return an error if the queue can't be read or updated.
+    #[instrument(skip(self))]
+    pub async fn retry_queued(&self) -> Result<()> {
+        for queued in self.repo.due(self.clock.utc(), RETRY_BATCH).await? {
+            let QueuedScrobble {
+                id,
+                spotify_user,
+                session_key,
+                attempts,
+                scrobble,
+            } = queued;
+            let Some(session_key) = self.decrypt(&spotify_user, &session_key) else {
+                SCROBBLES.with_label_values(&["dropped"]).inc();
+                self.repo.dequeue(id).await?;
+                continue;
+            };
+            let Err(e) = self.client.scrobble(&session_key, &scrobble).await else {
+                info!(attempts, "Scrobbled a queued track to Last.fm");
+                SCROBBLES.with_label_values(&["ok"]).inc();
+                self.repo.dequeue(id).await?;
+                continue;
+            };
+            let attempts = u32::try_from(attempts).unwrap_or(u32::MAX);
+            if is_temporary(&e) && attempts < self.max_attempts {
+                warn!(
+                    attempts,
+                    "Failed to scrobble a queued track, retrying later: {e}"
+                );
+                let next_attempt_at = self.next_attempt_at(attempts + 1);
+                self.repo
+                    .retry_later(id, next_attempt_at, &e.to_string())
+                    .await?;
+            } else {
+                error!(attempts, "Dropping a queued scrobble: {e}");
+                SCROBBLES.with_label_values(&["dropped"]).inc();
+                self.repo.dequeue(id).await?;
+                self.forget_invalid_session(&spotify_user, &e).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the decrypted session key of a user, or `None` if they are not connected.
+    async fn session_key(&self, spotify_user: &str) -> Option<String> {
+        match self.repo.session(spotify_user).await {
+            Ok(Some((_, session_key))) => self.decrypt(spotify_user, &session_key),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to read the Last.fm session: {e}");
+                None
+            }
+        }
+    }
+
+    /// Returns the decrypted session key, counting the ones that can't be decrypted
+    /// in `lastfm_session_decrypt_failures_total`.
+    fn decrypt(&self, spotify_user: &str, session_key: &[u8]) -> Option<String> {
+        let session_key = self.cipher.decrypt(session_key);
+        if session_key.is_none() {
+            error!(
+                spotify_user,
+                "Can't decrypt the Last.fm session key, was lastfm.encryption_key changed?"
+            );
+            SESSION_DECRYPT_FAILURES.inc();
+        }
+        session_key
+    }
+
+    /// Disconnects a user whose session Last.fm no longer accepts, they have to connect again.
+    async fn forget_invalid_session(&self, spotify_user: &str, err: &Error) {
+        // 9: invalid session key
+        if !matches!(err, Error::LastfmApi { code: 9, .. }) {
+            return;
+        }
+        warn!(
+            spotify_user,
+            "The Last.fm session was revoked, disconnecting"
+        );
+        if let Err(e) = self.repo.delete_session(spotify_user).await {
+            error!("Failed to delete the Last.fm session: {e}");
+        }
+    }
+
+    /// Returns when the given attempt is due, the delay is doubled after every attempt.
+    fn next_attempt_at(&self, attempt: u32) -> DateTime<Utc> {
+        let delay = chrono::Duration::from_std(self.backoff.delay(attempt))
+            .unwrap_or_else(|_| chrono::Duration::days(1));
+        self.clock.utc() + delay
+    }
+}
+
+/// Returns whether a track that played for `listened_secs` should be scrobbled,
+/// tracks must be longer than 30 seconds and play for half their duration or 4 minutes.
+pub fn should_scrobble(duration: Option<i32>, listened_secs: i32) -> bool {
+    match duration {
+        Some(duration) => {
+            duration > MIN_DURATION && listened_secs >= (duration / 2).min(SCROBBLE_AFTER)
+        }
+        None => listened_secs >= SCROBBLE_AFTER,
+    }
+}
+
+/// Returns whether a request may succeed later.
+fn is_temporary(err: &Error) -> bool {
+    match err {
+        Error::Lastfm(_) => true,
+        Error::LastfmStatus(status) => {
+            status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        // 11: service offline, 16: temporarily unavailable, 29: rate limit exceeded
+        Error::LastfmApi { code, .. } => matches!(code, 11 | 16 | 29),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::Mutex,
+    };
+
+    use spotify_music_vid::clock::{Clock, ManualClock};
+    use uuid::Uuid;
+    use warp::{http::StatusCode, Filter};
+
+    use super::*;
+
+    #[test]
+    fn scrobbles_after_half_the_track_or_four_minutes() {
+        assert!(!should_scrobble(Some(200), 99));
+        assert!(should_scrobble(Some(200), 100));
+        assert!(!should_scrobble(Some(600), 239));
+        assert!(should_scrobble(Some(600), 240));
+        assert!(!should_scrobble(None, 239));
+        assert!(should_scrobble(None, 240));
+    }
+
+    #[test]
+    fn never_scrobbles_short_tracks() {
+        assert!(!should_scrobble(Some(30), 30));
+        assert!(should_scrobble(Some(31), 15));
+    }
+
+    #[test]
+    fn retries_unavailable_and_rate_limited_requests_only() {
+        let api = |code| Error::LastfmApi {
+            code,
+            message: String::new(),
+        };
+        assert!(is_temporary(&Error::LastfmStatus(
+            reqwest::StatusCode::BAD_GATEWAY
+        )));
+        assert!(is_temporary(&Error::LastfmStatus(
+            reqwest::StatusCode::TOO_MANY_REQUESTS
+        )));
+        assert!(!is_temporary(&Error::LastfmStatus(
+            reqwest::StatusCode::BAD_REQUEST
+        )));
+        assert!(is_temporary(&api(29)));
+        assert!(!is_temporary(&api(9)));
+    }
+
+    #[tokio::test]
+    async fn long_retry_delays_do_not_overflow() {
+        let config = LastfmConfig {
+            api_key: "key".to_string(),
+            api_secret: "secret".into(),
+            encryption_key: "encryption key".into(),
+            retry_secs: u64::MAX / 2,
+            ..LastfmConfig::default()
+        };
+        let pool = Arc::new(PgPool::connect_lazy("postgres://localhost/unused").unwrap());
+        let clock = ManualClock::default();
+        let scrobbler =
+            Scrobbler::new(&config, pool, reqwest::Client::new(), clock.shared()).unwrap();
+        assert_eq!(scrobbler.backoff.delay(20), Duration::MAX);
+    }
+
+    /// The status and body of a reply
+    type Reply = (u16, String);
+
+    /// A local stand-in of the Last.fm api answering the replies scripted for each method,
+    /// in order, the last one being repeated. Methods without replies answer `{}`.
+    #[derive(Clone, Default)]
+    struct FakeLastfm {
+        replies: Arc<Mutex<HashMap<String, VecDeque<Reply>>>>,
+        calls: Arc<Mutex<Vec<HashMap<String, String>>>>,
+    }
+
+    impl FakeLastfm {
+        /// Starts the server and returns its url.
+        fn start(&self) -> String {
+            let fake = self.clone();
+            let route = warp::body::form().map(move |params: HashMap<String, String>| {
+                let (status, body) = fake.answer(&params["method"]);
+                fake.calls.lock().unwrap().push(params);
+                warp::reply::with_status(body, StatusCode::from_u16(status).unwrap())
+            });
+            let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            format!("http://{addr}/")
+        }
+
+        fn reply(&self, method: &str, status: u16, body: &str) {
+            self.replies
+                .lock()
+                .unwrap()
+                .entry(method.to_string())
+                .or_default()
+                .push_back((status, body.to_string()));
+        }
+
+        fn answer(&self, method: &str) -> Reply {
+            let mut replies = self.replies.lock().unwrap();
+            let Some(queue) = replies.get_mut(method) else {
+                return (200, "{}".to_string());
+            };
+            if queue.len() > 1 {
+                queue.pop_front().unwrap()
+            } else {
+                queue.front().cloned().unwrap()
+            }
+        }
+
+        /// Returns the calls of a method, oldest first.
+        fn calls(&self, method: &str) -> Vec<HashMap<String, String>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|params| params["method"] == method)
+                .cloned()
+                .collect()
+        }
+
+        /// Waits for the `count`th call of a method, made in the background.
+        async fn wait_for(&self, method: &str, count: usize) -> Vec<HashMap<String, String>> {
+            for _ in 0..500 {
+                let calls = self.calls(method);
+                if calls.len() >= count {
+                    return calls;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("{method} was not called {count} times");
+        }
+    }
+
+    /// A scrobbler against `lastfm` and the database of the tests, with a user connected.
+    struct Connected {
+        scrobbler: Scrobbler,
+        pool: Arc<PgPool>,
+        clock: ManualClock,
+        spotify_user: String,
+    }
+
+    impl Connected {
+        async fn new(lastfm: &FakeLastfm) -> Self {
+            let database_url = std::env::var("DATABASE_URL")
+                .expect("DATABASE_URL must point to the test database");
+            let pool = Arc::new(PgPool::connect(&database_url).await.unwrap());
+            sqlx::migrate!("./migrations").run(&*pool).await.unwrap();
+
+            let config = LastfmConfig {
+                api_key: "key".to_string(),
+                api_secret: "secret".into(),
+                base_url: lastfm.start(),
+                encryption_key: "encryption key".into(),
+                max_attempts: 3,
+                ..LastfmConfig::default()
+            };
+            let clock = ManualClock::default();
+            let scrobbler = Scrobbler::new(
+                &config,
+                pool.clone(),
+                reqwest::Client::new(),
+                clock.shared(),
+            )
+            .unwrap();
+
+            lastfm.reply(
+                "auth.getSession",
+                200,
+                r#"{"session": {"name": "lastfm-user", "key": "session-key", "subscriber": 0}}"#,
+            );
+            let spotify_user = format!("lastfm-{}", Uuid::new_v4());
+            let username = scrobbler.connect(&spotify_user, "token").await.unwrap();
+            assert_eq!(username, "lastfm-user");
+            Self {
+                scrobbler,
+                pool,
+                clock,
+                spotify_user,
+            }
+        }
+
+        fn scrobble(&self) -> Scrobble {
+            Scrobble {
+                artist: "Daft Punk".to_string(),
+                track: "Around the World".to_string(),
+                album: Some("Homework".to_string()),
+                duration: Some(429),
+                played_at: self.clock.utc(),
+            }
+        }
+
+        /// Returns the number of queued scrobbles of the user.
+        async fn queued(&self) -> i64 {
+            sqlx::query_scalar("SELECT COUNT(*) FROM scrobble_queue WHERE spotify_user = $1")
+                .bind(&self.spotify_user)
+                .fetch_one(&*self.pool)
+                .await
+                .unwrap()
+        }
+
+        /// Waits for a scrobble made in the background to be queued.
+        async fn wait_for_queue(&self) {
+            for _ in 0..500 {
+                if self.queued().await > 0 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("the scrobble was not queued");
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_now_playing_with_the_session_of_the_user() {
+        let lastfm = FakeLastfm::default();
+        let connected = Connected::new(&lastfm).await;
+        let token = &lastfm.calls("auth.getSession")[0];
+        assert_eq!(token["token"], "token");
+        assert_eq!(token["api_key"], "key");
+
+        connected
+            .scrobbler
+            .now_playing(&connected.spotify_user, connected.scrobble());
+        let call = &lastfm.wait_for("track.updateNowPlaying", 1).await[0];
+        assert_eq!(call["sk"], "session-key");
+        assert_eq!(call["artist"], "Daft Punk");
+        assert_eq!(call["track"], "Around the World");
+        assert!(!call.contains_key("timestamp"));
+    }
+
+    #[tokio::test]
+    async fn scrobbles_with_the_time_the_track_started() {
+        let lastfm = FakeLastfm::default();
+        let connected = Connected::new(&lastfm).await;
+
+        let scrobble = connected.scrobble();
+        let timestamp = scrobble.played_at.timestamp().to_string();
+        connected
+            .scrobbler
+            .scrobble(&connected.spotify_user, scrobble);
+        let call = &lastfm.wait_for("track.scrobble", 1).await[0];
+        assert_eq!(call["sk"], "session-key");
+        assert_eq!(call["timestamp"], timestamp);
+        assert_eq!(call["album"], "Homework");
+        assert_eq!(connected.queued().await, 0);
+    }
+
+    #[tokio::test]
+    async fn queues_a_failed_scrobble_and_retries_it_with_a_backoff() {
+        let lastfm = FakeLastfm::default();
+        let connected = Connected::new(&lastfm).await;
+        lastfm.reply("track.scrobble", 503, "");
+        lastfm.reply("track.scrobble", 503, "");
+        lastfm.reply("track.scrobble", 200, "{}");
+
+        connected
+            .scrobbler
+            .scrobble(&connected.spotify_user, connected.scrobble());
+        connected.wait_for_queue().await;
+        // nothing is due before the retry delay has passed on the clock
+        connected.scrobbler.retry_queued().await.unwrap();
+        assert_eq!(lastfm.calls("track.scrobble").len(), 1);
+
+        connected.clock.advance(Duration::from_secs(60));
+        connected.scrobbler.retry_queued().await.unwrap();
+        assert_eq!(lastfm.calls("track.scrobble").len(), 2);
+        assert_eq!(connected.queued().await, 1);
+
+        // the delay doubled after the second failure
+        connected.clock.advance(Duration::from_secs(60));
+        connected.scrobbler.retry_queued().await.unwrap();
+        assert_eq!(lastfm.calls("track.scrobble").len(), 2);
+        connected.clock.advance(Duration::from_secs(60));
+        connected.scrobbler.retry_queued().await.unwrap();
+        let calls = lastfm.calls("track.scrobble");
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0]["timestamp"], calls[2]["timestamp"]);
+        assert_eq!(connected.queued().await, 0);
+    }
+
+    #[tokio::test]
+    async fn disconnects_a_user_whose_session_was_revoked() {
+        let lastfm = FakeLastfm::default();
+        let connected = Connected::new(&lastfm).await;
+        lastfm.reply(
+            "track.scrobble",
+            403,
+            r#"{"error": 9, "message": "Invalid session key"}"#,
+        );
+
+        connected
+            .scrobbler
+            .scrobble(&connected.spotify_user, connected.scrobble());
+        lastfm.wait_for("track.scrobble", 1).await;
+        for _ in 0..500 {
+            if connected
+                .scrobbler
+                .session_key(&connected.spotify_user)
+                .await
+                .is_none()
+            {
+                assert_eq!(connected.queued().await, 0);
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the revoked session was kept");
+    }
+}
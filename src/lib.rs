@@ -1,55 +1,95 @@
-use color_eyre::Result;
+pub mod auth;
+pub mod chapters;
+pub mod clock;
+pub mod error;
+pub mod normalize;
+pub mod poller;
+pub mod protocol;
+pub mod replay;
+pub mod schedule;
+pub mod source;
+pub mod tracker;
+
+use auth::{SpotifyAuth, SpotifyCreds};
+use chapters::Chapter;
+pub use error::{Error, ErrorCode, Result};
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use rspotify::{
-    model::{CurrentlyPlayingContext, PlayableItem},
-    prelude::OAuthClient,
-    scopes, AuthCodeSpotify, Credentials, OAuth,
+    model::{
+        CurrentlyPlayingContext, CurrentlyPlayingType, FullEpisode, FullTrack, Image, PlayableItem,
+        SimplifiedArtist, SimplifiedTrack,
+    },
+    prelude::{Id, OAuthClient},
+    scopes, Config, Credentials, OAuth,
 };
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 use tracing::{info, instrument};
 use warp::ws::{Message, WebSocket};
 
 type Reader = SplitStream<WebSocket>;
 type Writer = SplitSink<WebSocket, Message>;
 
-/// # Panics
-/// Panics if the environment variables are not set
-/// requires `SPOTIFY_CLIENT_ID` and `SPOTIFY_CLIENT_SECRET`
-/// # Errors
-/// Returns an error if the environment variables are not set
-#[instrument]
-pub fn get_auth() -> Result<AuthCodeSpotify> {
-    info!("Getting env variables");
-    // dotenv::dotenv().ok();
-    let client_id = std::env::var("SPOTIFY_CLIENT_ID")?;
-    let client_secret = std::env::var("SPOTIFY_CLIENT_SECRET")?;
-
-    let creds = Credentials::new(client_id.as_str(), client_secret.as_str());
+/// Builds the Spotify auth client from the configured credentials.
+/// `redirect_uri` must match one of the redirect URIs registered for the Spotify app.
+/// Requests are sent to the Web API at `api_url`, and tokens requested from the accounts
+/// service at `accounts_url`.
+#[instrument(skip(client_secret))]
+pub fn get_auth(
+    client_id: &str,
+    client_secret: &str,
+    redirect_uri: &str,
+    api_url: &str,
+    accounts_url: &str,
+) -> SpotifyAuth {
+    info!("Building Spotify auth client");
+    let creds = Credentials::new(client_id, client_secret);
 
     let oauth = OAuth {
-        redirect_uri: "http://localhost:5173/callback".to_owned(),
-        scopes: scopes!["user-read-currently-playing", "user-read-playback-state"],
+        redirect_uri: redirect_uri.to_owned(),
+        scopes: scopes![
+            "user-read-currently-playing",
+            "user-read-playback-state",
+            "user-library-read",
+            "user-read-private"
+        ],
         ..Default::default()
     };
-    let spotify = AuthCodeSpotify::new(creds, oauth);
-    Ok(spotify)
+    SpotifyAuth::new(creds, oauth, api_config(api_url), accounts_url)
+}
+
+/// Builds the client of the client credentials flow, which only reads public data.
+/// Requests are sent to the Web API at `api_url`, and tokens requested from the accounts
+/// service at `accounts_url`.
+#[instrument(skip(client_secret))]
+pub fn get_client_creds(
+    client_id: &str,
+    client_secret: &str,
+    api_url: &str,
+    accounts_url: &str,
+) -> SpotifyCreds {
+    info!("Building Spotify client credentials client");
+    let creds = Credentials::new(client_id, client_secret);
+    SpotifyCreds::new(creds, api_config(api_url), accounts_url)
+}
+
+fn api_config(api_url: &str) -> Config {
+    Config {
+        // endpoints are appended to the prefix as is
+        prefix: format!("{}/", api_url.trim().trim_end_matches('/')),
+        ..Default::default()
+    }
 }
 
-/// # Panics
-/// Panics if the environment variables are not set
-/// requires `SPOTIFY_CLIENT_ID` and `SPOTIFY_CLIENT_SECRET`
+/// Sends the client the url where the user authorizes the app, then exchanges the code it sends back.
 /// # Errors
-/// Returns an error if the environment variables are not set
-#[instrument]
-pub async fn get_token(
-    auth: &AuthCodeSpotify,
-    read: &mut Reader,
-    write: &mut Writer,
-) -> Result<()> {
-    let auth_url = auth.get_authorize_url(true)?;
+/// Returns an error if the client does not send a valid code, Spotify rejects it
+/// or the websocket fails.
+#[instrument(skip_all)]
+pub async fn get_token(auth: &SpotifyAuth, read: &mut Reader, write: &mut Writer) -> Result<()> {
+    let auth_url = auth.authorize_url().map_err(Error::Auth)?;
 
     info!("Sending auth url to client");
     let msg = Message::text(auth_url.as_str());
@@ -57,43 +97,82 @@ pub async fn get_token(
     info!("Sent auth url to client");
     // wait for the client to send the code back
     let code = read.next().await;
-    let code = code.ok_or(color_eyre::eyre::eyre!("No code from client"))??;
+    let code = code.ok_or_else(|| Error::Protocol("No code from client".to_string()))??;
     let code = handle_message(&code)?;
     info!("Got code from client");
     info!("Requesting token from spotify");
-    auth.request_token(&code).await?;
-
-    Ok(())
+    auth.request_token(&code).await.map_err(Error::Auth)
 }
 
 /// Handles a [`Message`] from the client.
 /// returns the message as a string
 /// # Errors
 /// This function will return an error if the message received from the client is not a string
-#[instrument]
+#[instrument(skip(msg))]
 pub fn handle_message(msg: &Message) -> Result<String> {
     let msg = msg
         .to_str()
-        .map_err(|_| color_eyre::eyre::eyre!("Could not convert message to string"))?;
+        .map_err(|_| Error::Protocol("Could not convert message to string".to_string()))?;
     let msg = msg.trim();
     Ok(msg.to_string())
 }
 
-#[derive(Debug, Clone, sqlx::FromRow, sqlx::Decode)]
+/// A track or podcast episode, for episodes `name` is the episode title and `artist` the show.
+#[derive(Debug, Clone)]
 pub struct Song {
     pub name: String,
+    /// The main artist, songs are cached by title and main artist
     pub artist: String,
+    /// Every artist credited on the track, starting with the main artist
+    pub artists: Vec<String>,
     pub progress: i64,
+    /// The Spotify id of the track or episode, when known
+    pub track_id: Option<String>,
+    /// The album of the track, or `None` for episodes
+    pub album: Option<String>,
+    /// Covers of the album, or images of the episode, largest first
+    pub artwork: Vec<Image>,
+    /// Length of the track or episode in seconds, when known
+    pub duration: Option<i64>,
+    pub kind: ItemKind,
+    /// Chapters listed in the description of an episode, empty for tracks
+    pub chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ItemKind {
+    #[default]
+    Track,
+    /// A podcast episode lasting `duration` seconds
+    Episode { duration: i64 },
 }
 
 impl Song {
     /// Creates a new [`Song`].
     #[must_use]
-    pub const fn new(name: String, artist: String, progress: i64) -> Self {
+    pub fn new(name: String, artist: String, progress: i64) -> Self {
         Self {
             name,
+            artists: vec![artist.clone()],
             artist,
             progress,
+            track_id: None,
+            album: None,
+            artwork: Vec::new(),
+            duration: None,
+            kind: ItemKind::Track,
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Returns whether both are the same track or episode, regardless of the progress.
+    /// Tracks are compared by title as their id changes between releases of the same song.
+    #[must_use]
+    pub fn is_same_item(&self, other: &Self) -> bool {
+        match (self.kind, other.kind) {
+            (ItemKind::Track, ItemKind::Track) => self.name == other.name,
+            (ItemKind::Episode { .. }, ItemKind::Episode { .. }) => self.track_id == other.track_id,
+            _ => false,
         }
     }
 
@@ -101,20 +180,91 @@ impl Song {
     ///
     /// # Errors
     ///
-    /// This function will return an error if the [`CurrentlyPlayingContext`] does not contain a track.
+    /// This function will return [`Error::UnsupportedItem`] while an ad or an item Spotify
+    /// doesn't describe is playing, or [`Error::NothingPlaying`] if the
+    /// [`CurrentlyPlayingContext`] has no item otherwise.
     pub fn from_context(ctx: CurrentlyPlayingContext) -> Result<Self> {
-        let item = ctx
-            .item
-            .ok_or(color_eyre::eyre::eyre!("No item in context"))?;
-        let track = match item {
-            PlayableItem::Track(track) => track,
-            PlayableItem::Episode(_) => return Err(color_eyre::eyre::eyre!("Item is an episode")),
+        let Some(item) = ctx.item else {
+            return Err(match ctx.currently_playing_type {
+                CurrentlyPlayingType::Advertisement | CurrentlyPlayingType::Unknown => {
+                    Error::UnsupportedItem
+                }
+                CurrentlyPlayingType::Track | CurrentlyPlayingType::Episode => {
+                    Error::NothingPlaying
+                }
+            });
         };
-        let artist = track.artists[0].name.clone();
-        let name = track.name;
-        let progress = ctx.progress.unwrap_or_default();
-        let progress = progress.as_secs().try_into()?;
-        Ok(Self::new(name, artist, progress))
+        let progress = seconds(ctx.progress.unwrap_or_default());
+        Ok(match item {
+            PlayableItem::Track(track) => Self::from_track(track, progress),
+            PlayableItem::Episode(episode) => Self::from_episode(episode, progress),
+        })
+    }
+
+    /// Creates a new [`Song`] from a podcast [`FullEpisode`] with the given progress in seconds.
+    #[must_use]
+    pub fn from_episode(episode: FullEpisode, progress: i64) -> Self {
+        Self {
+            track_id: Some(episode.id.id().to_string()),
+            artwork: episode.images,
+            duration: Some(seconds(episode.duration)),
+            kind: ItemKind::Episode {
+                duration: seconds(episode.duration),
+            },
+            chapters: chapters::parse(&episode.description),
+            ..Self::new(episode.name, episode.show.name, progress)
+        }
+    }
+
+    fn with_artists(name: String, artists: &[SimplifiedArtist], progress: i64) -> Self {
+        let artists: Vec<String> = artists.iter().map(|artist| artist.name.clone()).collect();
+        Self {
+            artists: artists.clone(),
+            ..Self::new(name, artists.first().cloned().unwrap_or_default(), progress)
+        }
+    }
+
+    /// Returns the title without featured artists and release annotations,
+    /// see [`normalize::clean_title`].
+    #[must_use]
+    pub fn clean_name(&self) -> String {
+        match self.kind {
+            ItemKind::Track => normalize::clean_title(&self.name, &self.artists),
+            ItemKind::Episode { .. } => self.name.clone(),
+        }
+    }
+
+    /// Returns the duration in seconds if the song is a podcast episode.
+    #[must_use]
+    pub const fn episode_duration(&self) -> Option<i64> {
+        match self.kind {
+            ItemKind::Episode { duration } => Some(duration),
+            ItemKind::Track => None,
+        }
+    }
+
+    /// Creates a new [`Song`] from a [`FullTrack`] with the given progress in seconds.
+    #[must_use]
+    pub fn from_track(track: FullTrack, progress: i64) -> Self {
+        let track_id = track.id.map(|id| id.id().to_string());
+        Self {
+            track_id,
+            album: Some(track.album.name),
+            artwork: track.album.images,
+            duration: Some(seconds(track.duration)),
+            ..Self::with_artists(track.name, &track.artists, progress)
+        }
+    }
+
+    /// Creates a new [`Song`] from a [`SimplifiedTrack`], as listed in an album.
+    #[must_use]
+    pub fn from_simplified_track(track: SimplifiedTrack) -> Self {
+        let track_id = track.id.map(|id| id.id().to_string());
+        Self {
+            track_id,
+            duration: Some(seconds(track.duration)),
+            ..Self::with_artists(track.name, &track.artists, 0)
+        }
     }
 
     // Returns the embed url for the song
@@ -129,8 +279,15 @@ impl Song {
     }
 }
 
+fn seconds(duration: Duration) -> i64 {
+    i64::try_from(duration.as_secs()).unwrap_or(i64::MAX)
+}
+
 impl Display for Song {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} by {}", self.name, self.artist)
+        match self.kind {
+            ItemKind::Track => write!(f, "{} by {}", self.name, self.artists.join(", ")),
+            ItemKind::Episode { .. } => write!(f, "{} from {}", self.name, self.artist),
+        }
     }
 }
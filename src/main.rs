@@ -1,83 +1,345 @@
+mod api;
+mod artwork;
+mod cli;
 mod db;
+mod lastfm;
+mod metrics;
+mod playback;
 mod spotify_client;
+mod telemetry;
+mod warm;
 mod youtube_client;
 
-use std::sync::Arc;
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
+use clap::Parser;
+use cli::{Cli, Command};
 use color_eyre::Result;
-use db::config::Config;
-use futures_util::{stream::SplitSink, StreamExt};
-use rspotify::AuthCodeSpotify;
-use spotify_client::SpotifyClient;
-use spotify_music_vid::{get_auth, get_token};
+use db::{
+    config::{Config, LogFormat},
+    history::HistoryRepository,
+    rules::RuleRepository,
+    songs::SongRepository,
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use playback::{PlaybackSource, SourceKind, SpotifySource};
+use rspotify::{clients::OAuthClient, model::Market, prelude::Id};
+use serde::Deserialize;
+use spotify_client::{Listener, SpotifyClient};
+use spotify_music_vid::{
+    auth::SpotifyAuth,
+    clock::{SharedClock, SystemClock},
+    get_auth, get_token,
+    poller::Poller,
+    protocol::ServerMessage,
+    replay::Recorder,
+    tracker::StateTracker,
+};
 use sqlx::{Pool, Postgres};
-use tracing::{error, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use uuid::Uuid;
 use warp::{
     ws::{Message, WebSocket},
     Filter,
 };
+use youtube_client::YoutubeClient;
+type Reader = SplitStream<WebSocket>;
 type Writer = SplitSink<WebSocket, Message>;
+/// The source of a session, and the Spotify session used to warm the cache if there is one
+type Session = (Box<dyn PlaybackSource>, Option<SpotifyAuth>, Listener);
+
+/// The query of `/ws`, picking the player the session follows.
+#[derive(Debug, Deserialize)]
+struct SessionQuery {
+    #[serde(default)]
+    source: SourceKind,
+    /// With the MPRIS source, only follows the players whose name starts with it, such as `mpv`
+    player: Option<String>,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init()?;
-    let config = Config::from_env()?;
-    let pool = config.create_db_pool().await?;
-    let arc_pool = Arc::new(pool);
+    let cli = Cli::parse();
+    color_eyre::install()?;
+    let command = cli.command.unwrap_or(Command::Serve);
+    let config = Arc::new(Config::from_env(command.scope())?);
+    init(&config)?;
+
+    let res = cli::run(command, config).await;
+    telemetry::shutdown();
+    res
+}
+
+/// Serves the websocket endpoint and the admin api until the process is stopped.
+async fn serve(config: Arc<Config>, arc_pool: Arc<Pool<Postgres>>) -> Result<()> {
+    let addr = SocketAddr::new(config.server.host.parse()?, config.server.port);
+    let clock = SystemClock::shared();
+    // shared by every session so the quota is tracked for the whole server
+    let yt_client = YoutubeClient::new(&config.youtube, clock.clone());
+    metrics::init();
+    metrics::YOUTUBE_QUOTA_REMAINING.set(config.youtube.daily_quota.try_into()?);
+    if config.server.admin_token.is_empty() {
+        warn!("No admin token configured, the admin api is disabled");
+    }
+    let api = api::routes(SongRepository::new(arc_pool.clone()), &config);
+    let rules = RuleRepository::new(arc_pool.clone());
+    tokio::spawn(refresh_rules(
+        yt_client.clone(),
+        rules.clone(),
+        config.youtube.rules_refresh(),
+        clock.clone(),
+    ));
+    let rules_api = api::rules::routes(
+        rules,
+        SongRepository::new(arc_pool.clone()),
+        yt_client.clone(),
+        &config,
+    );
+    if let Some(scrobbler) = lastfm::Scrobbler::new(
+        &config.lastfm,
+        arc_pool.clone(),
+        reqwest::Client::new(),
+        clock,
+    ) {
+        tokio::spawn(scrobbler.run_retries());
+    }
+    let history = HistoryRepository::new(arc_pool.clone());
+    let history_api = api::history::routes(history.clone(), &config);
+    let stats_api = api::stats::routes(history, &config);
+    let health = api::health::routes(arc_pool.clone(), &config);
 
     // create websocket client
-    let routes = warp::path("ws")
+    let ws = warp::path("ws")
         .and(warp::ws())
+        .and(warp::query::<SessionQuery>())
         .and(warp::any().map(move || arc_pool.clone()))
-        .map(|ws: warp::ws::Ws, pool_conn| {
-            ws.on_upgrade(move |socket| handle_connect(socket, pool_conn))
+        .and(warp::any().map(move || yt_client.clone()))
+        .and(warp::any().map(move || config.clone()))
+        .map(|ws: warp::ws::Ws, query, pool_conn, yt_client, config| {
+            ws.on_upgrade(move |socket| handle_connect(socket, query, pool_conn, yt_client, config))
         });
 
-    warp::serve(routes).run(([0, 0, 0, 0], 8080)).await;
+    let routes = ws
+        .or(health)
+        .or(api)
+        .or(rules_api)
+        .or(history_api)
+        .or(stats_api)
+        .recover(api::handle_rejection);
+    warp::serve(routes).run(addr).await;
     Ok(())
 }
 
-fn init() -> Result<()> {
-    color_eyre::install()?;
+/// Reloads the blocked and allowed channels every `period` of `clock`, starting immediately,
+/// so changes made from the cli reach the running server.
+async fn refresh_rules(
+    yt_client: YoutubeClient,
+    rules: RuleRepository,
+    period: Duration,
+    clock: SharedClock,
+) {
+    let mut next = clock.now();
+    loop {
+        clock.sleep_until(next).await;
+        match yt_client.reload_rules(&rules).await {
+            Ok(count) => debug!("Loaded {count} video rules"),
+            Err(e) => error!("Failed to load the video rules: {e}"),
+        }
+        // reloads that take longer than the period delay the next one instead of bunching up
+        next = next.saturating_add(period).max(clock.now());
+    }
+}
+
+/// Installs the log subscriber, `RUST_LOG` takes precedence over `log.level`.
+/// Spans are also exported when `telemetry.otlp_endpoint` is set and the `otel` feature is enabled.
+fn init(config: &Config) -> Result<()> {
     // dotenv::dotenv()?;
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .pretty()
-        .finish();
+    let filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&config.log.level))?;
+    // logs go to stderr so they don't mix with the output of the cli commands
+    let fmt = match config.log.format {
+        LogFormat::Pretty => fmt::layer().with_writer(std::io::stderr).pretty().boxed(),
+        // the span list keeps the session fields on lines logged from nested spans
+        LogFormat::Json => fmt::layer()
+            .with_writer(std::io::stderr)
+            .json()
+            .with_span_list(true)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(telemetry::layer(&config.telemetry)?)
+        .try_init()?;
 
-    tracing::subscriber::set_global_default(subscriber)?;
+    if cfg!(not(feature = "otel")) && !config.telemetry.otlp_endpoint.is_empty() {
+        warn!("telemetry.otlp_endpoint is ignored, build with the `otel` feature to export spans");
+    }
     Ok(())
 }
 
 async fn run_program(
     write: Writer,
-    auth: AuthCodeSpotify,
+    read: Reader,
+    session: Session,
     pool: Arc<Pool<Postgres>>,
+    yt_client: YoutubeClient,
+    config: Arc<Config>,
+    clock: SharedClock,
 ) -> Result<()> {
-    let mut client = SpotifyClient::new(auth, write, pool)?;
-    client.start_polling().await?;
+    let (source, spotify, listener) = session;
+    let poller = Poller::new(
+        source,
+        config.polling.schedule(),
+        StateTracker::new(config.polling.seek_tolerance()),
+        clock,
+    );
+    let mut client = SpotifyClient::new(poller, spotify, write, pool, &yt_client, config, listener);
+    client.start_polling(read).await?;
     Ok(())
 }
 
-async fn handle_connect(socket: WebSocket, pool: Arc<Pool<Postgres>>) {
+async fn handle_connect(
+    socket: WebSocket,
+    query: SessionQuery,
+    pool: Arc<Pool<Postgres>>,
+    yt_client: YoutubeClient,
+    config: Arc<Config>,
+) {
+    let _session = metrics::ActiveSession::start();
+    let span = info_span!(
+        "session",
+        session_id = %Uuid::new_v4(),
+        source = ?query.source,
+        spotify_user = field::Empty
+    );
+    run_session(socket, query, pool, yt_client, config)
+        .instrument(span)
+        .await;
+}
+
+async fn run_session(
+    socket: WebSocket,
+    query: SessionQuery,
+    pool: Arc<Pool<Postgres>>,
+    yt_client: YoutubeClient,
+    config: Arc<Config>,
+) {
     let (mut tx, mut rx) = socket.split();
-    let auth = match get_auth() {
-        Ok(auth) => auth,
-        Err(e) => {
-            error!("Failed to get auth: {e}");
-            return;
-        }
+    // times the polls, the plays and the recording of the session
+    let clock = SystemClock::shared();
+    let session = match query.source {
+        SourceKind::Spotify => spotify_session(&config, &clock, &mut rx, &mut tx).await,
+        SourceKind::Mpris => match mpris_session(&config, query.player).await {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("Failed to open the local player: {e}");
+                let _ = tx.send(ServerMessage::error(&e).to_message()).await;
+                None
+            }
+        },
+    };
+    let Some(session) = session else {
+        return;
     };
-    match get_token(&auth, &mut rx, &mut tx).await {
+    match run_program(tx, rx, session, pool, yt_client, config, clock).await {
+        Ok(_) => (),
+        Err(e) => error!("Failed to run program: {e}"),
+    }
+}
+
+/// Authorizes the session with Spotify, and polls the user's Spotify account.
+/// Returns `None` if the client could not be authorized.
+async fn spotify_session(
+    config: &Config,
+    clock: &SharedClock,
+    rx: &mut Reader,
+    tx: &mut Writer,
+) -> Option<Session> {
+    let spotify = &config.spotify;
+    let auth = get_auth(
+        &spotify.client_id,
+        spotify.client_secret.expose(),
+        &spotify.redirect_uri,
+        &spotify.api_url,
+        &spotify.accounts_url,
+    );
+    match get_token(&auth, rx, tx).await {
         Ok(_) => (),
         Err(e) => {
             error!("Failed to get token: {:?}", e);
-            return;
+            return None;
         }
     };
-    match run_program(tx, auth, pool).await {
-        Ok(_) => (),
-        Err(e) => error!("Failed to run program: {e}"),
+    let mut listener = match auth.current_user().await {
+        Ok(user) => {
+            Span::current().record("spotify_user", user.id.id());
+            info!("Session started");
+            Listener {
+                id: Some(user.id.id().to_string()),
+                country: user.country,
+            }
+        }
+        Err(e) => {
+            warn!("Failed to get the spotify user: {e}");
+            Listener::default()
+        }
+    };
+    listener.country = config.spotify.market.or(listener.country);
+    // Spotify picks the market of the token's user when the country is unknown
+    let market = listener.country.map_or(Market::FromToken, Market::Country);
+    let mut source = SpotifySource::new(auth.clone(), market);
+    if !config.polling.record_dir.is_empty() {
+        let path = Path::new(&config.polling.record_dir).join(format!("{}.jsonl", Uuid::new_v4()));
+        match Recorder::create(&path, clock.clone()).await {
+            Ok(recorder) => {
+                info!(path = %path.display(), "Recording the session");
+                source = source.record_to(recorder);
+            }
+            Err(e) => error!("Failed to record the session: {e}"),
+        }
+    }
+    Some((Box::new(source), Some(auth), listener))
+}
+
+/// Anyone who can reach the server could otherwise follow the players of its desktop session.
+fn local_players_disabled() -> spotify_music_vid::Error {
+    spotify_music_vid::Error::Player(
+        "local players are disabled, see server.allow_local_players".to_string(),
+    )
+}
+
+/// Follows a local player, the listener is unknown so nothing is stored for them.
+/// # Errors
+/// This function will return an error if local players are disabled, the session bus
+/// is not available, or the server was built without the `mpris` feature.
+#[cfg(feature = "mpris")]
+async fn mpris_session(
+    config: &Config,
+    player: Option<String>,
+) -> spotify_music_vid::Result<Session> {
+    if !config.server.allow_local_players {
+        return Err(local_players_disabled());
+    }
+    let source = playback::MprisSource::new(player).await?;
+    info!("Session started");
+    let listener = Listener {
+        id: None,
+        country: config.spotify.market,
+    };
+    Ok((Box::new(source), None, listener))
+}
+
+#[cfg(not(feature = "mpris"))]
+async fn mpris_session(config: &Config, _: Option<String>) -> spotify_music_vid::Result<Session> {
+    if !config.server.allow_local_players {
+        return Err(local_players_disabled());
     }
+    Err(spotify_music_vid::Error::Player(
+        "the server was built without the mpris feature".to_string(),
+    ))
 }
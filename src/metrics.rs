@@ -0,0 +1,252 @@
+//! Process wide prometheus metrics and the health of the external services.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use rspotify::{http::HttpError, ClientError};
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+pub static ACTIVE_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new(
+        "active_sessions",
+        "Websocket sessions currently connected",
+    ))
+});
+
+pub static SPOTIFY_POLLS: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new(
+        "spotify_polls_total",
+        "Requests made to Spotify for the currently playing track",
+    ))
+});
+
+pub static SPOTIFY_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "spotify_errors_total",
+            "Failed Spotify requests by http status, or the kind of error",
+        ),
+        &["code"],
+    ))
+});
+
+pub static YOUTUBE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("youtube_requests_total", "Youtube searches by outcome"),
+        &["outcome"],
+    ))
+});
+
+pub static PROVIDER_SEARCHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "video_provider_searches_total",
+            "Searches per video provider by outcome, `skipped` while its circuit breaker is open",
+        ),
+        &["provider", "outcome"],
+    ))
+});
+
+pub static PROVIDER_OPEN: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new(
+            "video_provider_open",
+            "1 while the circuit breaker of a video provider is open",
+        ),
+        &["provider"],
+    ))
+});
+
+pub static SCROBBLES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "lastfm_scrobbles_total",
+            "Scrobbles sent to Last.fm by outcome, `queued` when a failed scrobble is retried later",
+        ),
+        &["outcome"],
+    ))
+});
+
+pub static SESSION_DECRYPT_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register(IntCounter::new(
+        "lastfm_session_decrypt_failures_total",
+        "Last.fm session keys that could not be decrypted, after `lastfm.encryption_key` changed",
+    ))
+});
+
+pub static YOUTUBE_QUOTA_REMAINING: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new(
+        "youtube_quota_remaining",
+        "Youtube data api units left for today",
+    ))
+});
+
+pub static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "cache_lookups_total",
+            "Video lookups for the playing track by result",
+        ),
+        &["result"],
+    ))
+});
+
+pub static LOOKUP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "lookup_duration_seconds",
+            "Time spent finding the video of the playing track",
+        ),
+        &["source"],
+    ))
+});
+
+pub static DB_QUERY_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "db_query_duration_seconds",
+            "Time spent in song cache queries",
+        ),
+        &["query"],
+    ))
+});
+
+/// Registers every metric so they are exported before their first update.
+pub fn init() {
+    Lazy::force(&ACTIVE_SESSIONS);
+    Lazy::force(&SPOTIFY_POLLS);
+    Lazy::force(&SPOTIFY_ERRORS);
+    Lazy::force(&YOUTUBE_REQUESTS);
+    Lazy::force(&PROVIDER_SEARCHES);
+    Lazy::force(&PROVIDER_OPEN);
+    Lazy::force(&SCROBBLES);
+    Lazy::force(&SESSION_DECRYPT_FAILURES);
+    Lazy::force(&YOUTUBE_QUOTA_REMAINING);
+    Lazy::force(&CACHE_LOOKUPS);
+    Lazy::force(&LOOKUP_DURATION);
+    Lazy::force(&DB_QUERY_DURATION);
+}
+
+/// Registers a collector, panicking if the metric is invalid or registered twice
+/// since both are programming errors.
+fn register<T>(collector: prometheus::Result<T>) -> T
+where
+    T: prometheus::core::Collector + Clone + 'static,
+{
+    let collector = collector.expect("invalid metric definition");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric registered twice");
+    collector
+}
+
+/// Renders every metric in the prometheus text format.
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {e}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// Returns the label recorded in `spotify_errors_total` for an error.
+pub fn spotify_error_code(err: &ClientError) -> String {
+    match err {
+        ClientError::Http(http) => match http.as_ref() {
+            HttpError::StatusCode(response) => response.status().as_u16().to_string(),
+            HttpError::Client(_) => "network".to_string(),
+        },
+        ClientError::ParseJson(_) => "invalid_response".to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+/// Counts a websocket session in [`ACTIVE_SESSIONS`] until dropped,
+/// so sessions ending with a panic are not counted forever.
+pub struct ActiveSession(());
+
+impl ActiveSession {
+    #[must_use]
+    pub fn start() -> Self {
+        ACTIVE_SESSIONS.inc();
+        Self(())
+    }
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.dec();
+    }
+}
+
+/// Outcome of the latest calls made to an external service.
+pub struct ServiceHealth {
+    last_success: AtomicU64,
+    last_failure: AtomicU64,
+}
+
+pub static SPOTIFY_HEALTH: ServiceHealth = ServiceHealth::new();
+pub static YOUTUBE_HEALTH: ServiceHealth = ServiceHealth::new();
+
+impl ServiceHealth {
+    const fn new() -> Self {
+        Self {
+            last_success: AtomicU64::new(0),
+            last_failure: AtomicU64::new(0),
+        }
+    }
+
+    pub fn success(&self) {
+        self.last_success.store(now(), Ordering::Relaxed);
+    }
+
+    pub fn failure(&self) {
+        self.last_failure.store(now(), Ordering::Relaxed);
+    }
+
+    /// Returns `None` while the service was not called yet.
+    /// Otherwise the service is healthy if the latest call succeeded,
+    /// or if a call succeeded within `window` despite later failures.
+    pub fn is_healthy(&self, window: Duration) -> Option<bool> {
+        let success = self.last_success.load(Ordering::Relaxed);
+        let failure = self.last_failure.load(Ordering::Relaxed);
+        if success == 0 && failure == 0 {
+            return None;
+        }
+        Some(success >= failure || now().saturating_sub(success) <= window.as_secs())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    #[test]
+    fn sessions_ending_with_a_panic_are_not_counted() {
+        let before = ACTIVE_SESSIONS.get();
+        let result = panic::catch_unwind(|| {
+            let _session = ActiveSession::start();
+            assert_eq!(ACTIVE_SESSIONS.get(), before + 1);
+            panic!("the session failed");
+        });
+        assert!(result.is_err());
+        assert_eq!(ACTIVE_SESSIONS.get(), before);
+    }
+}
@@ -0,0 +1,163 @@
+//! Normalisation of track titles before they are matched against video titles.
+
+use deunicode::deunicode;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Annotations that describe a release rather than the song itself
+const VERSION_WORDS: &str = r"remaster(?:ed)?|radio edit|edit|single version|album version|mono|stereo|bonus track|explicit|clean";
+
+/// An annotation made only of version words, with an optional year and "version",
+/// so `Remastered 2011` and `2011 Remaster` are annotations but `Clean Bandit Remix` is not
+static ANNOTATION: Lazy<String> = Lazy::new(|| {
+    format!(
+        r"(?:\d{{4}}\s+)?(?:digital\s+)?(?:{VERSION_WORDS})(?:\s+\d{{4}})?(?:\s+(?:version|mix))?"
+    )
+});
+
+/// `(feat. Artist)`, `[ft. Artist]`
+static BRACKETED_FEATURE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\s*[(\[]\s*(?:feat\.?|ft\.?|featuring)\s[^)\]]*[)\]]").expect("valid regex")
+});
+
+/// `(with Artist)`, only a feature when it names a credited artist, unlike `(With Me)`
+static BRACKETED_WITH: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\s*[(\[]\s*with\s([^)\]]*)[)\]]").expect("valid regex"));
+
+/// `(Remastered 2011)`, `[Radio Edit]`
+static BRACKETED_VERSION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(&format!(r"(?i)\s*[(\[]\s*{}\s*[)\]]", *ANNOTATION)).expect("valid regex")
+});
+
+/// `- Remastered 2011`, `- 2011 Remaster`, `- Radio Edit`
+static DASH_VERSION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(&format!(r"(?i)\s+-\s+{}\s*$", *ANNOTATION)).expect("valid regex"));
+
+/// `Song feat. Artist`
+static INLINE_FEATURE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)\s+(?:feat\.?|ft\.?|featuring)\s.*$").expect("valid regex"));
+
+/// Strips featured artists and release annotations such as "Remastered 2011" or "- Radio Edit"
+/// from a track title, keeping the ones that change the recording like "- Live".
+/// `(with ...)` is only stripped when it names one of the credited `artists`.
+#[must_use]
+pub fn clean_title(title: &str, artists: &[String]) -> String {
+    let artists: Vec<String> = artists.iter().map(|artist| fold(artist)).collect();
+    let cleaned = BRACKETED_FEATURE.replace_all(title, "");
+    let cleaned = BRACKETED_WITH.replace_all(&cleaned, |captures: &Captures| {
+        let named = fold(&captures[1]);
+        let credited = artists
+            .iter()
+            .any(|artist| !artist.is_empty() && contains_words(&named, artist));
+        if credited {
+            String::new()
+        } else {
+            captures[0].to_string()
+        }
+    });
+    let cleaned = BRACKETED_VERSION.replace_all(&cleaned, "");
+    let cleaned = DASH_VERSION.replace(&cleaned, "");
+    let cleaned = INLINE_FEATURE.replace(&cleaned, "");
+    let cleaned = cleaned.trim();
+    if cleaned.is_empty() {
+        // the whole title was an annotation, better to search for it than for nothing
+        return title.trim().to_string();
+    }
+    cleaned.to_string()
+}
+
+/// Returns whether the folded `text` contains the folded `words` as whole words.
+fn contains_words(text: &str, words: &str) -> bool {
+    format!(" {text} ").contains(&format!(" {words} "))
+}
+
+/// `Name (Alternate name)`, an alternate name is only told apart from an annotation
+/// such as `(Acoustic)` when one of them is written in latin and the other is not
+static ALTERNATE_NAME: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(.+?)\s*[(\[]([^)\]]+)[)\]]$").expect("valid regex"));
+
+/// Returns `text` in a form that compares equal across the ways the same words are written:
+/// compatibility characters and diacritics removed, lower case, and punctuation
+/// replaced by single spaces.
+#[must_use]
+pub fn fold(text: &str) -> String {
+    let stripped: String = text
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the latin transliteration of `text`, folded,
+/// e.g. `Москва` becomes `moskva`.
+/// Japanese with kanji is not transliterated and an empty string is returned,
+/// kanji would be read as Chinese and never match how the title is written in romaji.
+#[must_use]
+pub fn romanize(text: &str) -> String {
+    let japanese = text.chars().any(is_kana);
+    if japanese && text.chars().any(is_han) {
+        return String::new();
+    }
+    fold(&deunicode(text))
+}
+
+/// Returns the forms of a video title or channel that [`match_forms`] are looked for in:
+/// the title folded, transliterated, and transliterated without spaces,
+/// as kana are transliterated without spaces between words.
+#[must_use]
+pub fn title_forms(title: &str) -> Vec<String> {
+    let romanized = romanize(title);
+    let compact = romanized.replace(' ', "");
+    vec![fold(title), romanized, compact]
+}
+
+/// Returns whether every letter of `text` is written in the latin script, accented or not.
+fn is_latin(text: &str) -> bool {
+    text.chars().filter(|c| c.is_alphabetic()).all(|c| {
+        matches!(c,
+            'a'..='z' | 'A'..='Z'
+            // Latin-1 Supplement and Latin Extended-A and B
+            | '\u{c0}'..='\u{24f}'
+            // Latin Extended Additional
+            | '\u{1e00}'..='\u{1eff}'
+            // fullwidth latin letters
+            | '\u{ff21}'..='\u{ff3a}' | '\u{ff41}'..='\u{ff5a}')
+            && c != '\u{d7}'
+            && c != '\u{f7}'
+    })
+}
+
+const fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30ff}' | '\u{31f0}'..='\u{31ff}')
+}
+
+const fn is_han(c: char) -> bool {
+    matches!(c, '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}')
+}
+
+/// Returns every folded form a name may appear as in a video title:
+/// the name itself, its alternate names and their transliterations.
+#[must_use]
+pub fn match_forms(name: &str) -> Vec<String> {
+    let mut names = vec![name.to_string()];
+    if let Some(captures) = ALTERNATE_NAME.captures(name) {
+        let (main, alternate) = (&captures[1], &captures[2]);
+        // "Déjà Vu (Remix)" is an annotation, "夜に駆ける (Yoru ni Kakeru)" an alternate name
+        if is_latin(main) != is_latin(alternate) {
+            names = vec![main.to_string(), alternate.to_string()];
+        }
+    }
+
+    let mut forms = Vec::new();
+    for name in names {
+        for form in [fold(&name), romanize(&name)] {
+            if !form.is_empty() && !forms.contains(&form) {
+                forms.push(form);
+            }
+        }
+    }
+    forms
+}
@@ -0,0 +1,26 @@
+//! Players whose playing track is polled by a session.
+//!
+//! Sessions read the Spotify Web API by default, or a local player over MPRIS
+//! when the server is built with the `mpris` feature, picked with `/ws?source=`.
+
+#[cfg(feature = "mpris")]
+mod mpris;
+mod spotify;
+
+use serde::Deserialize;
+
+#[cfg(feature = "mpris")]
+pub use mpris::MprisSource;
+pub use spotify::SpotifySource;
+pub use spotify_music_vid::source::{Playback, PlaybackSource};
+
+/// The [`PlaybackSource`]s a session can be opened with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// The Spotify Web API, the client authorizes the session first
+    #[default]
+    Spotify,
+    /// A local player over D-Bus, such as mpv, VLC or the Spotify desktop app
+    Mpris,
+}
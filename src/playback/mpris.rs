@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use futures_util::future::BoxFuture;
+use rspotify::model::Image;
+use spotify_music_vid::{Error, Result, Song};
+use zbus::{
+    fdo::DBusProxy,
+    zvariant::{OwnedValue, Value},
+    CacheProperties, Connection, Proxy, ProxyBuilder,
+};
+
+use super::{Playback, PlaybackSource};
+
+/// Bus names of the MPRIS players start with this prefix, followed by the player's name
+const BUS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const PLAYER_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+/// The track ids of the Spotify desktop app are Spotify track ids under this path
+const SPOTIFY_TRACK_PATH: &str = "/com/spotify/track/";
+
+/// Reads the track playing on a local player through the MPRIS D-Bus interface
+/// of the session bus the server runs on.
+pub struct MprisSource {
+    connection: Connection,
+    /// Only players whose bus name starts with `org.mpris.MediaPlayer2.<player>` are read
+    player: Option<String>,
+}
+
+impl MprisSource {
+    /// Connects to the session bus, `player` such as `mpv` or `vlc` restricts the players read.
+    /// # Errors
+    /// This function will return an error if the session bus is not available.
+    pub async fn new(player: Option<String>) -> Result<Self> {
+        let connection = Connection::session().await.map_err(player_error)?;
+        Ok(Self { connection, player })
+    }
+
+    /// Returns what the first playing player is playing, or the first paused one.
+    /// # Errors
+    /// This function will return an error if the bus can't be read or no player has a track.
+    async fn fetch(&self) -> Result<Playback> {
+        let names = DBusProxy::new(&self.connection)
+            .await
+            .map_err(player_error)?
+            .list_names()
+            .await
+            .map_err(player_error)?;
+        let prefix = format!("{BUS_PREFIX}{}", self.player.as_deref().unwrap_or_default());
+
+        let mut paused = None;
+        for name in names.iter().filter(|name| name.starts_with(&prefix)) {
+            let player = self.player(name).await?;
+            let status: String = player
+                .get_property("PlaybackStatus")
+                .await
+                .map_err(player_error)?;
+            match status.as_str() {
+                "Playing" => return read_playback(&player, true).await,
+                "Paused" if paused.is_none() => paused = Some(player),
+                _ => (),
+            }
+        }
+        match paused {
+            Some(player) => read_playback(&player, false).await,
+            None => Err(Error::NothingPlaying),
+        }
+    }
+
+    async fn player(&self, name: &str) -> Result<Proxy<'static>> {
+        ProxyBuilder::new_bare(&self.connection)
+            .destination(name.to_string())
+            .and_then(|builder| builder.path(PLAYER_PATH))
+            .and_then(|builder| builder.interface(PLAYER_INTERFACE))
+            .map_err(player_error)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await
+            .map_err(player_error)
+    }
+}
+
+impl PlaybackSource for MprisSource {
+    fn name(&self) -> &'static str {
+        "mpris"
+    }
+
+    fn current(&mut self) -> BoxFuture<'_, Result<Playback>> {
+        Box::pin(self.fetch())
+    }
+}
+
+/// Reads the track of a player from its `Metadata` and `Position` properties.
+/// # Errors
+/// This function will return [`Error::NothingPlaying`] if the track has no title.
+async fn read_playback(player: &Proxy<'_>, is_playing: bool) -> Result<Playback> {
+    let metadata: HashMap<String, OwnedValue> = player
+        .get_property("Metadata")
+        .await
+        .map_err(player_error)?;
+    // some players don't report the position, the video then starts at the beginning
+    let position: i64 = player.get_property("Position").await.unwrap_or_default();
+    parse_metadata(&metadata, position, is_playing)
+}
+
+/// Builds the playback from MPRIS metadata, `position` is in microseconds.
+/// # Errors
+/// This function will return [`Error::NothingPlaying`] if the track has no title.
+fn parse_metadata(
+    metadata: &HashMap<String, OwnedValue>,
+    position: i64,
+    is_playing: bool,
+) -> Result<Playback> {
+    let title = metadata
+        .get("xesam:title")
+        .and_then(|title| string(title))
+        .filter(|title| !title.is_empty())
+        .ok_or(Error::NothingPlaying)?;
+    let artists = metadata
+        .get("xesam:artist")
+        .map(|artists| strings(artists))
+        .unwrap_or_default();
+    let mut song = Song::new(
+        title,
+        artists.first().cloned().unwrap_or_default(),
+        position / 1_000_000,
+    );
+    if !artists.is_empty() {
+        song.artists = artists;
+    }
+    song.album = metadata
+        .get("xesam:album")
+        .and_then(|album| string(album))
+        .filter(|album| !album.is_empty());
+    song.duration = metadata
+        .get("mpris:length")
+        .and_then(|length| integer(length))
+        .map(|micros| micros / 1_000_000);
+    song.track_id = metadata
+        .get("mpris:trackid")
+        .and_then(|id| string(id))
+        .and_then(|id| id.strip_prefix(SPOTIFY_TRACK_PATH).map(str::to_string));
+    song.artwork = metadata
+        .get("mpris:artUrl")
+        .and_then(|url| string(url))
+        .filter(|url| url.starts_with("http"))
+        .map(|url| Image {
+            url,
+            width: None,
+            height: None,
+        })
+        .into_iter()
+        .collect();
+    Ok(Playback { song, is_playing })
+}
+
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::Str(text) => Some(text.to_string()),
+        Value::ObjectPath(path) => Some(path.to_string()),
+        _ => None,
+    }
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values.iter().filter_map(string).collect(),
+        value => string(value).into_iter().collect(),
+    }
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::I64(value) => Some(*value),
+        Value::U64(value) => i64::try_from(*value).ok(),
+        Value::I32(value) => Some(i64::from(*value)),
+        Value::U32(value) => Some(i64::from(*value)),
+        _ => None,
+    }
+}
+
+fn player_error(err: impl std::fmt::Display) -> Error {
+    Error::Player(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use zbus::zvariant::ObjectPath;
+
+    use super::*;
+
+    fn metadata(entries: Vec<(&str, Value<'static>)>) -> HashMap<String, OwnedValue> {
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), OwnedValue::from(value)))
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_spotify_desktop_metadata() {
+        let metadata = metadata(vec![
+            ("xesam:title", Value::from("Around the World")),
+            ("xesam:artist", Value::from(vec!["Daft Punk"])),
+            ("xesam:album", Value::from("Homework")),
+            ("mpris:length", Value::from(429_000_000_u64)),
+            (
+                "mpris:trackid",
+                Value::from(
+                    ObjectPath::try_from("/com/spotify/track/1pKYYY0dkg23sQQXi0Q5zN").unwrap(),
+                ),
+            ),
+            (
+                "mpris:artUrl",
+                Value::from("https://i.scdn.co/image/ab67616d"),
+            ),
+        ]);
+
+        let playback = parse_metadata(&metadata, 61_500_000, true).unwrap();
+        assert!(playback.is_playing);
+        let song = playback.song;
+        assert_eq!(song.name, "Around the World");
+        assert_eq!(song.artists, vec!["Daft Punk".to_string()]);
+        assert_eq!(song.album.as_deref(), Some("Homework"));
+        assert_eq!(song.progress, 61);
+        assert_eq!(song.duration, Some(429));
+        assert_eq!(song.track_id.as_deref(), Some("1pKYYY0dkg23sQQXi0Q5zN"));
+        assert_eq!(song.artwork.len(), 1);
+        assert_eq!(song.artwork[0].url, "https://i.scdn.co/image/ab67616d");
+    }
+
+    #[test]
+    fn reads_several_artists_and_a_signed_length() {
+        let metadata = metadata(vec![
+            ("xesam:title", Value::from("Get Lucky")),
+            (
+                "xesam:artist",
+                Value::from(vec!["Daft Punk", "Pharrell Williams"]),
+            ),
+            ("mpris:length", Value::from(369_000_000_i64)),
+        ]);
+
+        let song = parse_metadata(&metadata, 0, false).unwrap().song;
+        assert_eq!(
+            song.artists,
+            vec!["Daft Punk".to_string(), "Pharrell Williams".to_string()]
+        );
+        assert_eq!(song.duration, Some(369));
+        assert!(song.album.is_none());
+    }
+
+    #[test]
+    fn ignores_local_art_and_other_players_track_ids() {
+        let metadata = metadata(vec![
+            ("xesam:title", Value::from("Song")),
+            ("xesam:album", Value::from("")),
+            (
+                "mpris:trackid",
+                Value::from(ObjectPath::try_from("/org/mpris/MediaPlayer2/Track/3").unwrap()),
+            ),
+            ("mpris:artUrl", Value::from("file:///tmp/cover.jpg")),
+        ]);
+
+        let song = parse_metadata(&metadata, 0, true).unwrap().song;
+        assert!(song.album.is_none());
+        assert!(song.track_id.is_none());
+        assert!(song.artwork.is_empty());
+        assert!(song.duration.is_none());
+    }
+
+    #[test]
+    fn a_track_without_a_title_is_nothing_playing() {
+        let empty = metadata(vec![("xesam:title", Value::from(""))]);
+        assert!(matches!(
+            parse_metadata(&empty, 0, true),
+            Err(Error::NothingPlaying)
+        ));
+        assert!(matches!(
+            parse_metadata(&HashMap::new(), 0, true),
+            Err(Error::NothingPlaying)
+        ));
+    }
+}
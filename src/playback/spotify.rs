@@ -0,0 +1,90 @@
+use futures_util::future::BoxFuture;
+use rspotify::{
+    model::{AdditionalType, CurrentlyPlayingContext, Market},
+    prelude::OAuthClient,
+};
+use spotify_music_vid::{auth::SpotifyAuth, replay::Recorder, Error, Result, Song};
+use tracing::{error, info_span, Instrument};
+
+use super::{Playback, PlaybackSource};
+use crate::metrics::{self, SPOTIFY_HEALTH};
+
+/// Polls the track playing on the user's Spotify account through the Web API.
+pub struct SpotifySource {
+    spotify: SpotifyAuth,
+    market: Market,
+    /// Writes every poll when the session is recorded
+    recorder: Option<Recorder>,
+}
+
+impl SpotifySource {
+    /// Tracks are requested for `market`, the market of the token's user with [`Market::FromToken`].
+    pub const fn new(spotify: SpotifyAuth, market: Market) -> Self {
+        Self {
+            spotify,
+            market,
+            recorder: None,
+        }
+    }
+
+    /// Records every poll with `recorder`, see [`spotify_music_vid::replay`].
+    #[must_use]
+    pub fn record_to(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Fetches the currently playing track or episode.
+    /// # Errors
+    /// This function will return an error if the request fails or nothing is playing.
+    async fn fetch(&mut self) -> Result<Playback> {
+        let types = [AdditionalType::Track, AdditionalType::Episode];
+        metrics::SPOTIFY_POLLS.inc();
+        let res = match self
+            .spotify
+            .current_playing(Some(self.market), Some(&types))
+            .instrument(info_span!("current_playing"))
+            .await
+        {
+            Ok(res) => {
+                SPOTIFY_HEALTH.success();
+                res
+            }
+            Err(e) => {
+                let code = metrics::spotify_error_code(&e);
+                metrics::SPOTIFY_ERRORS.with_label_values(&[&code]).inc();
+                SPOTIFY_HEALTH.failure();
+                return Err(e.into());
+            }
+        };
+
+        self.record(res.as_ref()).await;
+        let context = res.ok_or(Error::NothingPlaying)?;
+        let is_playing = context.is_playing;
+        Ok(Playback {
+            song: Song::from_context(context)?,
+            is_playing,
+        })
+    }
+
+    /// Records a poll, the recording stops at the first failure.
+    async fn record(&mut self, context: Option<&CurrentlyPlayingContext>) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(e) = recorder.record(context).await {
+            error!("Failed to record the poll, stopping the recording: {e}");
+            self.recorder = None;
+        }
+    }
+}
+
+impl PlaybackSource for SpotifySource {
+    fn name(&self) -> &'static str {
+        "spotify"
+    }
+
+    fn current(&mut self) -> BoxFuture<'_, Result<Playback>> {
+        Box::pin(self.fetch())
+    }
+}
@@ -0,0 +1,104 @@
+//! Polls a [`PlaybackSource`] on a [`Clock`], reporting what changed since the previous poll.
+
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+
+use crate::{
+    clock::SharedClock,
+    schedule::PollSchedule,
+    source::{Playback, PlaybackSource},
+    tracker::{Change, StateTracker},
+    Error, Result,
+};
+
+/// Reads a [`PlaybackSource`] when the [`PollSchedule`] says so.
+pub struct Poller {
+    source: Box<dyn PlaybackSource>,
+    schedule: PollSchedule,
+    tracker: StateTracker,
+    clock: SharedClock,
+    /// When the next poll is due, on the clock
+    next_poll: Duration,
+    /// The delay set by the last poll
+    delay: Duration,
+}
+
+impl Poller {
+    /// Creates a [`Poller`] whose first poll is due immediately.
+    #[must_use]
+    pub fn new(
+        source: Box<dyn PlaybackSource>,
+        schedule: PollSchedule,
+        tracker: StateTracker,
+        clock: SharedClock,
+    ) -> Self {
+        let next_poll = clock.now();
+        Self {
+            source,
+            schedule,
+            tracker,
+            clock,
+            next_poll,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Names the source in the logs
+    #[must_use]
+    pub fn source_name(&self) -> &'static str {
+        self.source.name()
+    }
+
+    #[must_use]
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Returns when the next poll is due, on the clock.
+    #[must_use]
+    pub const fn next_poll(&self) -> Duration {
+        self.next_poll
+    }
+
+    /// Returns the delay between the last poll and the next one.
+    #[must_use]
+    pub const fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// Returns a future completing when the next poll is due.
+    /// It doesn't borrow the poller, so it can wait alongside other work on the session.
+    #[must_use]
+    pub fn wait(&self) -> BoxFuture<'static, ()> {
+        self.clock.sleep_until(self.next_poll)
+    }
+
+    /// Forgets the previous poll, the item playing at the next poll is [`Change::Started`] again.
+    pub fn reset(&mut self) {
+        self.tracker.reset();
+    }
+
+    /// Reads the source and schedules the next poll.
+    /// Returns what is playing, `None` when nothing is, and what changed since the previous poll.
+    /// An item that can't be followed, such as an ad, is like nothing playing:
+    /// the source answered, so the poller doesn't back off.
+    /// # Errors
+    /// This function will return the error of the source, the next poll then backs off.
+    pub async fn poll(&mut self) -> Result<(Option<Playback>, Change)> {
+        let playback = match self.source.current().await {
+            Ok(playback) => Some(playback),
+            Err(Error::NothingPlaying | Error::UnsupportedItem) => None,
+            Err(e) => {
+                self.delay = self.schedule.failed();
+                self.next_poll = self.clock.now() + self.delay;
+                return Err(e);
+            }
+        };
+        let now = self.clock.now();
+        let change = self.tracker.observe(playback.as_ref(), now);
+        self.delay = self.schedule.succeeded(playback.as_ref());
+        self.next_poll = now + self.delay;
+        Ok((playback, change))
+    }
+}
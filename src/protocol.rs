@@ -0,0 +1,270 @@
+//! JSON messages exchanged over the websocket once the client is authenticated.
+//!
+//! Video urls are still sent as plain text messages so existing clients keep working,
+//! every other message is a JSON object tagged by its `type` field.
+
+use rspotify::model::Image;
+use serde::{Deserialize, Serialize};
+use warp::ws::Message;
+
+use crate::{Error, ErrorCode, Song};
+
+/// Messages the client can send while the video is playing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// `{"type": "warm", "source": "playlist", "id": "<playlist id>"}`
+    Warm(WarmSource),
+    /// `{"type": "preferences", "prefer": "lyrics", "exclude": ["audio"]}`
+    Preferences(VideoPreferences),
+    /// `{"type": "correct", "youtube_id": "<video id>"}`, replaces the video of the playing song
+    Correct { youtube_id: String },
+    /// `{"type": "lastfm_connect", "token": "<token>"}` connects the user to Last.fm,
+    /// without a token it is answered with the page where the user gets one
+    LastfmConnect {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// `{"type": "lastfm_disconnect"}` stops the scrobbles
+    LastfmDisconnect,
+}
+
+/// The collection of tracks whose videos should be cached ahead of time.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "source", content = "id", rename_all = "snake_case")]
+pub enum WarmSource {
+    Playlist(String),
+    Album(String),
+    /// The tracks saved in the user's library
+    Saved,
+    /// The tracks queued to play next
+    Queue,
+}
+
+/// The kinds of videos found for a song.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoKind {
+    /// The official music video, or any other video of the song
+    MusicVideo,
+    /// A video showing the lyrics
+    Lyrics,
+    /// The audio alone, such as the uploads of the "Topic" channels
+    Audio,
+}
+
+/// The kinds of videos a user wants to see, stored per Spotify user.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoPreferences {
+    /// Ranked above the other kinds when the search finds it
+    #[serde(default)]
+    pub prefer: Option<VideoKind>,
+    /// Never played
+    #[serde(default)]
+    pub exclude: Vec<VideoKind>,
+}
+
+/// Messages sent by the server in addition to the plain text video urls.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    WarmProgress(WarmProgress),
+    /// The preferences in effect, after the client changed them
+    Preferences(VideoPreferences),
+    /// The page where the user allows the scrobbles, Last.fm then redirects them with a token
+    LastfmAuth {
+        url: String,
+    },
+    /// The connected Last.fm account, `null` once disconnected
+    Lastfm {
+        username: Option<String>,
+    },
+    /// `{"type": "error", "code": "quota_exceeded", "message": "..."}`
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Sent instead of a video url when no provider found a video for the playing song,
+    /// `code` tells why, e.g. `no_video` or `timeout`
+    NoVideo {
+        code: ErrorCode,
+        message: String,
+        #[serde(flatten)]
+        now_playing: Box<NowPlaying>,
+    },
+}
+
+/// What is playing, for clients to display when there is no video.
+#[derive(Debug, Clone, Serialize)]
+pub struct NowPlaying {
+    /// The title of the track or episode
+    pub title: String,
+    /// The main artist, or the show of an episode
+    pub artist: String,
+    /// The credited artists, or the show of an episode
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    /// Seconds
+    pub duration: Option<i64>,
+    /// Seconds
+    pub progress: i64,
+    /// The url of the largest image of `artwork`
+    pub album_art: Option<String>,
+    /// The album covers or episode images, largest first
+    pub artwork: Vec<Image>,
+    /// Colours extracted from the artwork, when it could be downloaded
+    pub colors: Option<Palette>,
+}
+
+/// Colours of an artwork as `#rrggbb`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Palette {
+    /// The most common colour
+    pub dominant: String,
+    /// The average of every pixel
+    pub average: String,
+    /// Black or white, whichever is readable on the dominant colour
+    pub text: String,
+}
+
+/// Progress of a cache warm, sent after every resolved track.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WarmProgress {
+    pub total: usize,
+    /// Tracks that were already cached
+    pub cached: usize,
+    /// Tracks resolved and added to the cache
+    pub added: usize,
+    pub failed: usize,
+    /// Tracks left unresolved to preserve the youtube quota
+    pub skipped: usize,
+    pub finished: bool,
+}
+
+impl ClientMessage {
+    /// Parses a message received from the client.
+    /// # Errors
+    /// This function will return [`Error::Protocol`] if the message is not a known JSON message.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        serde_json::from_str(text).map_err(|e| Error::Protocol(e.to_string()))
+    }
+}
+
+impl ServerMessage {
+    /// Reports an error to the client with its stable code.
+    #[must_use]
+    pub fn error(err: &Error) -> Self {
+        Self::Error {
+            code: err.code(),
+            message: err.client_message(),
+        }
+    }
+
+    /// Tells the client that no video could be found because of `err`.
+    #[must_use]
+    pub fn no_video(now_playing: NowPlaying, err: &Error) -> Self {
+        Self::NoVideo {
+            code: err.code(),
+            message: err.client_message(),
+            now_playing: Box::new(now_playing),
+        }
+    }
+
+    /// Encodes the message as a websocket text message.
+    #[must_use]
+    pub fn to_message(&self) -> Message {
+        // serializing these types can not fail, they only contain strings, integers and lists
+        let text = serde_json::to_string(self).unwrap_or_default();
+        Message::text(text)
+    }
+}
+
+impl NowPlaying {
+    /// Describes `song`, with the colours of its artwork if they are known.
+    #[must_use]
+    pub fn new(song: &Song, colors: Option<Palette>) -> Self {
+        Self {
+            title: song.name.clone(),
+            artist: song.artist.clone(),
+            artists: song.artists.clone(),
+            album: song.album.clone(),
+            duration: song.duration,
+            progress: song.progress,
+            album_art: song.artwork.first().map(|image| image.url.clone()),
+            artwork: song.artwork.clone(),
+            colors,
+        }
+    }
+}
+
+impl VideoKind {
+    /// Every kind of video
+    pub const ALL: [Self; 3] = [Self::MusicVideo, Self::Lyrics, Self::Audio];
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::MusicVideo => "music_video",
+            Self::Lyrics => "lyrics",
+            Self::Audio => "audio",
+        }
+    }
+
+    /// Parses the value returned by [`VideoKind::as_str`].
+    #[must_use]
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.as_str() == value)
+    }
+}
+
+impl VideoPreferences {
+    /// The cache profile of users without preferences
+    pub const DEFAULT_PROFILE: &'static str = "default";
+
+    /// Returns the preferences with the excluded kinds sorted and deduplicated.
+    /// # Errors
+    /// This function will return [`Error::Protocol`] if the preferred kind is also excluded,
+    /// or if every kind is excluded.
+    pub fn normalized(mut self) -> Result<Self, Error> {
+        self.exclude.sort_unstable();
+        self.exclude.dedup();
+        if let Some(prefer) = self.prefer.filter(|prefer| self.exclude.contains(prefer)) {
+            return Err(Error::Protocol(format!(
+                "{} is both preferred and excluded",
+                prefer.as_str()
+            )));
+        }
+        if VideoKind::ALL
+            .iter()
+            .all(|kind| self.exclude.contains(kind))
+        {
+            return Err(Error::Protocol(
+                "every kind of video is excluded".to_string(),
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Returns the name of the cache profile, users with the same preferences share cached videos.
+    /// e.g. `default`, `lyrics` or `music_video-no-audio`.
+    #[must_use]
+    pub fn profile(&self) -> String {
+        if *self == Self::default() {
+            return Self::DEFAULT_PROFILE.to_string();
+        }
+        let mut profile = self.prefer.map_or("any", VideoKind::as_str).to_string();
+        for kind in &self.exclude {
+            profile.push_str("-no-");
+            profile.push_str(kind.as_str());
+        }
+        profile
+    }
+}
+
+impl WarmProgress {
+    /// Returns the number of tracks handled so far.
+    #[must_use]
+    pub const fn done(&self) -> usize {
+        self.cached + self.added + self.failed + self.skipped
+    }
+}
@@ -0,0 +1,179 @@
+//! Recordings of the playback polled during a session, and their replay.
+//!
+//! A recording has one JSON line per poll, the [`Snapshot`] of the Spotify [`CurrentlyPlayingContext`].
+//! Replaying it with a [`ManualClock`](crate::clock::ManualClock) gives the same polls every time,
+//! so track changes, seeks and pauses can be tested without Spotify.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use rspotify::model::CurrentlyPlayingContext;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    clock::SharedClock,
+    source::{Playback, PlaybackSource},
+    Error, Result, Song,
+};
+
+/// What a poll returned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Milliseconds since the recording started
+    pub at_ms: u64,
+    /// `None` when nothing was playing
+    pub context: Option<CurrentlyPlayingContext>,
+}
+
+/// A [`Snapshot`] as written to a recording.
+#[derive(Serialize)]
+struct RecordedSnapshot {
+    at_ms: u64,
+    context: Option<Value>,
+}
+
+/// Writes the polls of a session to a file, without blocking the runtime.
+/// Every poll is written as it happens, the recording is complete even if the server stops.
+pub struct Recorder {
+    file: tokio::fs::File,
+    clock: SharedClock,
+    /// The time of `clock` when the recording started
+    started: Duration,
+}
+
+impl Recorder {
+    /// Creates the recording at `path`, replacing any existing file.
+    /// The polls are timed with `clock`, from the time the recording is created.
+    /// # Errors
+    /// This function will return an error if the file can't be created.
+    pub async fn create(path: &Path, clock: SharedClock) -> Result<Self> {
+        let file = tokio::fs::File::create(path)
+            .await
+            .map_err(Error::Recording)?;
+        let started = clock.now();
+        Ok(Self {
+            file,
+            clock,
+            started,
+        })
+    }
+
+    /// Records what a poll returned, `None` when nothing was playing.
+    /// # Errors
+    /// This function will return an error if the file can't be written.
+    pub async fn record(&mut self, context: Option<&CurrentlyPlayingContext>) -> Result<()> {
+        let context = context
+            .map(to_recorded)
+            .transpose()
+            .map_err(|e| Error::Recording(e.into()))?;
+        let snapshot = RecordedSnapshot {
+            at_ms: u64::try_from(self.clock.now().saturating_sub(self.started).as_millis())
+                .unwrap_or(u64::MAX),
+            context,
+        };
+        let mut line = serde_json::to_vec(&snapshot).map_err(|e| Error::Recording(e.into()))?;
+        line.push(b'\n');
+        self.file.write_all(&line).await.map_err(Error::Recording)?;
+        // the write only completes in the background until the file is flushed
+        self.file.flush().await.map_err(Error::Recording)
+    }
+}
+
+/// Serializes a context the way Spotify sends it, so it can be read back.
+/// rspotify writes the disallowed actions as a list, but only reads them as a map of flags.
+fn to_recorded(context: &CurrentlyPlayingContext) -> serde_json::Result<Value> {
+    let mut context = serde_json::to_value(context)?;
+    if let Some(disallows) = context.pointer_mut("/actions/disallows") {
+        if let Value::Array(keys) = disallows {
+            let flags: Map<String, Value> = keys
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|key| (key.to_string(), Value::Bool(true)))
+                .collect();
+            *disallows = Value::Object(flags);
+        }
+    }
+    Ok(context)
+}
+
+/// Reads the snapshots of a recording, blank lines are skipped.
+/// # Errors
+/// This function will return an error if the file can't be read or a line is not a [`Snapshot`].
+pub fn read_recording(path: &Path) -> Result<Vec<Snapshot>> {
+    let file = File::open(path).map_err(Error::Recording)?;
+    let mut snapshots = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(Error::Recording)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let snapshot = serde_json::from_str(&line).map_err(|e| {
+            let message = format!("line {}: {e}", number + 1);
+            Error::Recording(io::Error::new(io::ErrorKind::InvalidData, message))
+        })?;
+        snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+}
+
+/// Plays a recording back, returning the last snapshot recorded before the time of its clock.
+pub struct ReplaySource {
+    snapshots: Vec<Snapshot>,
+    clock: SharedClock,
+}
+
+impl ReplaySource {
+    /// Creates a [`ReplaySource`] playing `snapshots`, which must be sorted by time.
+    #[must_use]
+    pub fn new(snapshots: Vec<Snapshot>, clock: SharedClock) -> Self {
+        Self { snapshots, clock }
+    }
+
+    /// Plays back the recording at `path`.
+    /// # Errors
+    /// This function will return an error if the recording can't be read.
+    pub fn open(path: &Path, clock: SharedClock) -> Result<Self> {
+        Ok(Self::new(read_recording(path)?, clock))
+    }
+
+    #[must_use]
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    /// Returns the playback recorded at the time of the clock.
+    /// # Errors
+    /// Returns [`Error::NothingPlaying`] before the first snapshot, or when nothing was playing.
+    pub fn playback(&self) -> Result<Playback> {
+        let now = self.clock.now();
+        let context = self
+            .snapshots
+            .iter()
+            .take_while(|snapshot| Duration::from_millis(snapshot.at_ms) <= now)
+            .last()
+            .and_then(|snapshot| snapshot.context.clone())
+            .ok_or(Error::NothingPlaying)?;
+        let is_playing = context.is_playing;
+        Ok(Playback {
+            song: Song::from_context(context)?,
+            is_playing,
+        })
+    }
+}
+
+impl PlaybackSource for ReplaySource {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn current(&mut self) -> BoxFuture<'_, Result<Playback>> {
+        Box::pin(futures_util::future::ready(self.playback()))
+    }
+}
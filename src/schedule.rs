@@ -0,0 +1,85 @@
+//! When to poll again, and how long to wait before retrying.
+
+use std::time::Duration;
+
+use crate::source::Playback;
+
+/// Spotify reports the next track a moment after the previous one ended
+pub const TRACK_CHANGE_DELAY: Duration = Duration::from_millis(500);
+
+/// Delays doubling after every failed attempt, up to a maximum.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    /// Creates a [`Backoff`] waiting `base` after the first failure and never longer than `max`.
+    #[must_use]
+    pub const fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max }
+    }
+
+    /// Returns the delay after `failures` failed attempts in a row, `base` after the first.
+    #[must_use]
+    pub fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.base
+            .checked_mul(1 << doublings)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// The delays between the polls of a session.
+/// Polls happen every `interval`, sooner when the playing track ends before,
+/// and back off while the source fails.
+#[derive(Debug, Clone)]
+pub struct PollSchedule {
+    interval: Duration,
+    backoff: Backoff,
+    /// Polls failed in a row
+    failures: u32,
+}
+
+impl PollSchedule {
+    #[must_use]
+    pub const fn new(interval: Duration, backoff: Backoff) -> Self {
+        Self {
+            interval,
+            backoff,
+            failures: 0,
+        }
+    }
+
+    /// Returns the delay before the poll following a successful one,
+    /// `playback` is `None` when nothing is playing.
+    pub fn succeeded(&mut self, playback: Option<&Playback>) -> Duration {
+        self.failures = 0;
+        playback
+            .and_then(until_end)
+            .map_or(self.interval, |end| end.min(self.interval))
+    }
+
+    /// Returns the delay before retrying a failed poll.
+    pub fn failed(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        self.backoff.delay(self.failures)
+    }
+
+    /// Returns the number of polls that failed in a row.
+    #[must_use]
+    pub const fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// Returns when the next item should be playing, `None` if the playback is paused or its duration unknown.
+fn until_end(playback: &Playback) -> Option<Duration> {
+    if !playback.is_playing {
+        return None;
+    }
+    let remaining = playback.song.duration? - playback.song.progress;
+    let remaining = u64::try_from(remaining).unwrap_or_default();
+    Some(Duration::from_secs(remaining) + TRACK_CHANGE_DELAY)
+}
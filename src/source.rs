@@ -0,0 +1,26 @@
+//! Players whose playing track can be polled.
+
+use futures_util::future::BoxFuture;
+
+use crate::{Result, Song};
+
+/// What a [`PlaybackSource`] is playing.
+#[derive(Debug, Clone)]
+pub struct Playback {
+    /// The playing track, with its progress
+    pub song: Song,
+    /// `false` while paused
+    pub is_playing: bool,
+}
+
+/// A player whose playing track can be polled.
+pub trait PlaybackSource: Send + Sync {
+    /// Names the source in the logs
+    fn name(&self) -> &'static str;
+
+    /// Returns what the player is playing.
+    /// # Errors
+    /// Returns [`Error::NothingPlaying`](crate::Error::NothingPlaying) when nothing is playing,
+    /// or an error if the player can't be read.
+    fn current(&mut self) -> BoxFuture<'_, Result<Playback>>;
+}
@@ -1,217 +1,663 @@
+mod play;
+
 use std::sync::Arc;
 
-use color_eyre::eyre::{Error, Result};
-use futures_util::{stream::SplitSink, SinkExt};
-use rspotify::{
-    model::{AdditionalType, CurrentlyPlayingContext, Market, PlayableItem},
-    prelude::OAuthClient,
-    AuthCodeSpotify,
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use rspotify::model::Country;
+use spotify_music_vid::{
+    auth::SpotifyAuth,
+    handle_message,
+    poller::Poller,
+    protocol::{ClientMessage, NowPlaying, ServerMessage, VideoPreferences, WarmSource},
+    tracker::Change,
+    Error, ItemKind, Result, Song,
 };
-use spotify_music_vid::Song;
 use sqlx::{Pool, Postgres};
-use tokio::time::{sleep, Duration};
-use tracing::{error, info, instrument, warn};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing::{debug, error, info, instrument, warn, Instrument};
 use warp::ws::{Message, WebSocket};
 
-use crate::{db::songs::SongRepository, youtube_client::YoutubeClient};
+use crate::{
+    artwork,
+    db::{
+        config::Config, history::HistoryRepository, lastfm::Scrobble,
+        preferences::PreferenceRepository, songs::SongRepository, Songs,
+    },
+    lastfm::{should_scrobble, Scrobbler},
+    metrics,
+    playback::Playback,
+    warm::{collect_queued_songs, collect_saved_songs, collect_songs, Warmer},
+    youtube_client::YoutubeClient,
+};
+use play::{CurrentPlay, SentVideo};
+
+/// The Spotify user of a session, as far as it is known.
+#[derive(Debug, Clone, Default)]
+pub struct Listener {
+    /// The Spotify user id, preferences and history are not stored when it is unknown
+    pub id: Option<String>,
+    /// The market to request tracks for and the youtube region
+    pub country: Option<Country>,
+}
 
+type Reader = SplitStream<WebSocket>;
 type Writer = SplitSink<WebSocket, Message>;
 pub struct SpotifyClient {
-    pub spotify: AuthCodeSpotify,
+    /// Reads the playing song, and tells what changed and when to read it again
+    poller: Poller,
+    /// Lists the tracks to warm, `None` when the session is not authorized with Spotify
+    spotify: Option<SpotifyAuth>,
     yt_client: YoutubeClient,
-    prev_state: Option<CurrentlyPlayingContext>,
     writer: Writer,
     db_pool: SongRepository,
+    preferences: PreferenceRepository,
+    history: HistoryRepository,
+    listener: Listener,
+    /// The song the last video was sent for
+    play: Option<CurrentPlay>,
+    /// The last video sent, sent again from the new position when the song is seeked
+    video: Option<SentVideo>,
+    config: Arc<Config>,
+    /// Downloads the artwork when there is no video
+    http: reqwest::Client,
+    /// `None` when scrobbling is disabled
+    scrobbler: Option<Scrobbler>,
 }
 
 impl SpotifyClient {
     /// Creates a new [`SpotifyClient`].
-    /// This function will also load the environment variables
-    /// `SPOTIFY_CLIENT_ID` and `SPOTIFY_CLIENT_SECRET` are required
-    #[instrument]
-    pub fn new(auth: AuthCodeSpotify, writer: Writer, pool: Arc<Pool<Postgres>>) -> Result<Self> {
-        info!("Creating new SpotifyClient and loading environment variables");
-        let yt_client = YoutubeClient::new()?;
-        let pool = SongRepository::new(pool);
-
-        Ok(Self {
-            spotify: auth,
-            yt_client,
-            prev_state: None,
+    /// The polling and cache settings are taken from the given [`Config`].
+    /// The playing song is read by `poller`, whose clock times the plays and the scrobble retries,
+    /// and videos are searched in the region of the listener's country.
+    #[instrument(skip_all, fields(source = poller.source_name(), ?listener))]
+    pub fn new(
+        poller: Poller,
+        spotify: Option<SpotifyAuth>,
+        writer: Writer,
+        pool: Arc<Pool<Postgres>>,
+        yt_client: &YoutubeClient,
+        config: Arc<Config>,
+        listener: Listener,
+    ) -> Self {
+        info!("Creating new SpotifyClient");
+        let country = listener.country;
+        let clock = poller.clock().clone();
+
+        Self {
+            poller,
+            spotify,
+            yt_client: yt_client.with_region(country),
             writer,
-            db_pool: pool,
-        })
+            db_pool: SongRepository::new(pool.clone()),
+            preferences: PreferenceRepository::new(pool.clone()),
+            history: HistoryRepository::new(pool.clone()),
+            scrobbler: Scrobbler::new(&config.lastfm, pool, reqwest::Client::new(), clock),
+            listener,
+            play: None,
+            video: None,
+            config,
+            http: artwork::client(),
+        }
     }
 
-    /// Returns the get state loop of this [`SpotifyClient`].
-    /// This function will attempt to get the state of the spotify client.
-    /// If it fails, it will retry in 5 seconds.
+    /// Returns the start polling of this [`SpotifyClient`].
+    /// This function will check if the state has changed every `polling.interval_ms` milliseconds,
+    /// or when the playing song ends if that is sooner.
+    /// If the state has changed, it will send the video url to the client.
+    /// Messages sent by the client are handled between polls, and polling stops once the client disconnects.
+    /// Every song played is recorded in the listener's history.
     /// # Errors
-    /// This function will return an error if returned state has an invalid format.
-    async fn get_state_loop(&mut self) -> Result<CurrentlyPlayingContext> {
-        let mut state = self.get_state().await;
-        while let Err(ref e) = state {
-            println!("Something went wrong, retrying in 5 seconds");
-            error!("Failed to get state: {e}");
-            sleep(Duration::from_secs(5)).await;
-            state = self.get_state().await;
+    /// This function will return an error if there is an error while handling the state change
+    /// or sending a message to the client.
+    pub async fn start_polling(&mut self, reader: Reader) -> Result<()> {
+        self.load_preferences().await;
+        let result = self.run(reader).await;
+        self.finish_play().await;
+        result
+    }
+
+    /// Polls and handles the client messages until the client disconnects.
+    async fn run(&mut self, mut reader: Reader) -> Result<()> {
+        info!("Starting polling");
+        let (events_tx, mut events) = unbounded_channel();
+        loop {
+            tokio::select! {
+                msg = reader.next() => match msg {
+                    Some(Ok(msg)) if msg.is_close() => break,
+                    Some(Ok(msg)) => self.handle_client_message(&msg, &events_tx).await?,
+                    Some(Err(e)) => {
+                        error!("Failed to read from client: {e}");
+                        break;
+                    }
+                    None => break,
+                },
+                Some(event) = events.recv() => self.writer.send(event.to_message()).await?,
+                () = self.poller.wait() => self.poll().await?,
+            }
         }
-        state
+        info!("Client disconnected, stopping polling");
+        Ok(())
     }
 
-    /// Fetches the state of the spotify client.
+    /// Reads the playing song once and sends a new video if it changed,
+    /// or the same video at the new position if the song was seeked.
+    /// The poller backs off while the source returns errors.
+    /// Failures to find the video are reported to the client and don't stop polling.
     /// # Errors
-    /// This function will return an error if an invalid state is returned.
-    async fn get_state(&self) -> Result<CurrentlyPlayingContext> {
-        let market = Market::Country(rspotify::model::Country::UnitedStates);
-        let add = AdditionalType::Track;
-        let res = self
-            .spotify
-            .current_playing(Some(market), Some(vec![&add]))
-            .await?;
+    /// This function will return an error if the websocket connection fails.
+    #[instrument(skip_all)]
+    async fn poll(&mut self) -> Result<()> {
+        let (playback, change) = match self.poller.poll().await {
+            Ok(polled) => polled,
+            Err(e) => {
+                error!(
+                    "Failed to get state: {e}, retrying in {} seconds",
+                    self.poller.delay().as_secs()
+                );
+                return Ok(());
+            }
+        };
+        let Some(playback) = playback else {
+            debug!("No song is currently playing");
+            self.observe_play(false);
+            return Ok(());
+        };
+        let result = match change {
+            Change::Started => {
+                info!("State changed, sending video");
+                self.handle_state_change(playback).await
+            }
+            Change::Seeked { from, to } => {
+                info!(from, to, "Song seeked, sending video");
+                self.handle_seek(playback).await
+            }
+            _ => {
+                self.observe_play(playback.is_playing);
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => (),
+            Err(e @ Error::Connection(_)) => return Err(e),
+            Err(e) => {
+                warn!("Failed to find a video: {e}");
+                self.writer
+                    .send(ServerMessage::error(&e).to_message())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
 
-        res.map_or_else(
-            || {
-                warn!("No song is currently playing");
-                Err(color_eyre::eyre::eyre!("No song is currently playing"))
-            },
-            Ok,
-        )
+    /// Handles a [`ClientMessage`] received while polling.
+    /// Unknown messages are answered with a [`ServerMessage::Error`].
+    /// # Errors
+    /// This function will return an error if the reply can not be sent to the client.
+    async fn handle_client_message(
+        &mut self,
+        msg: &Message,
+        events: &UnboundedSender<ServerMessage>,
+    ) -> Result<()> {
+        let parsed = handle_message(msg).and_then(|text| ClientMessage::parse(&text));
+        match parsed {
+            Ok(ClientMessage::Warm(source)) => self.start_warm(source, events.clone()),
+            Ok(ClientMessage::Preferences(preferences)) => {
+                self.set_preferences(preferences).await?;
+            }
+            Ok(ClientMessage::Correct { youtube_id }) => self.correct_video(&youtube_id).await?,
+            Ok(ClientMessage::LastfmConnect { token }) => {
+                self.connect_lastfm(token.as_deref()).await?;
+            }
+            Ok(ClientMessage::LastfmDisconnect) => self.disconnect_lastfm().await?,
+            Err(e) => {
+                warn!("Ignoring invalid message from client: {e}");
+                self.writer
+                    .send(ServerMessage::error(&e).to_message())
+                    .await?;
+            }
+        }
+        Ok(())
     }
 
-    /// Returns the start polling of this [`SpotifyClient`].
-    /// This function will check if the state has changed every 250 milliseconds.
-    /// If the state has changed, it will send the video url to the client.
+    /// Applies the stored video preferences of the listener.
+    /// The defaults are kept if the listener is unknown or the preferences can't be read.
+    async fn load_preferences(&mut self) {
+        let Some(id) = &self.listener.id else {
+            return;
+        };
+        match self.preferences.get(id).await {
+            Ok(preferences) => {
+                debug!(?preferences, "Loaded the video preferences");
+                self.yt_client = self.yt_client.with_preferences(preferences);
+            }
+            Err(e) => error!("Failed to load the video preferences: {e}"),
+        }
+    }
+
+    /// Stores and applies the video preferences sent by the client, and confirms them.
+    /// The playing song is looked up again so the new preferences take effect immediately.
+    /// When they can't be stored, only the error is sent and the previous preferences stay.
     /// # Errors
-    /// This function will return an error if there is an error while handling the state change.
-    pub async fn start_polling(&mut self) -> Result<()> {
-        info!("Starting polling");
-        while let Ok(state) = self.get_state_loop().await {
-            if self.check_state_change(&state) {
-                info!("State changed, sending video");
-                self.handle_state_change(state).await?;
+    /// This function will return an error if the reply can not be sent to the client.
+    async fn set_preferences(&mut self, preferences: VideoPreferences) -> Result<()> {
+        let preferences = match preferences.normalized() {
+            Ok(preferences) => preferences,
+            Err(e) => {
+                warn!("Ignoring invalid preferences: {e}");
+                self.writer
+                    .send(ServerMessage::error(&e).to_message())
+                    .await?;
+                return Ok(());
+            }
+        };
+        match &self.listener.id {
+            Some(id) => {
+                if let Err(e) = self.preferences.set(id, &preferences).await {
+                    error!("Failed to store the video preferences, keeping the previous ones: {e}");
+                    self.writer
+                        .send(ServerMessage::error(&e).to_message())
+                        .await?;
+                    return Ok(());
+                }
+            }
+            None => {
+                warn!("The Spotify user is unknown, the preferences only apply to this session")
             }
-            sleep(Duration::from_millis(250)).await;
         }
+        info!(?preferences, "Video preferences changed");
+        self.yt_client = self.yt_client.with_preferences(preferences.clone());
+        self.poller.reset();
+        self.writer
+            .send(ServerMessage::Preferences(preferences).to_message())
+            .await?;
         Ok(())
     }
 
-    /// Sends the video url to the client.
-    /// Cache is checked first, if the song is not in the cache, it will be added.
+    /// Replaces the video of the playing song with the one picked by the user.
+    /// The play is marked as corrected in the history, and the cached video is replaced.
     /// # Errors
-    /// This function will return an error if an invalid context is received, an error
-    /// occurs while sending the video.
-    /// # Logging
-    /// This function will log an error if there is an error while adding the song to the database.
-    async fn handle_state_change(&mut self, state: CurrentlyPlayingContext) -> Result<()> {
-        let song = Song::from_context(state)?;
-        info!("Checking if song is in database");
-        if let Some(song_id) = self.db_pool.get(&song).await {
-            info!("Song is in database, sending video");
-            let url = Song::get_url_with_duration(&song_id, &song.progress.to_string());
-            self.send_video(Ok(url)).await?;
+    /// This function will return an error if the video or the reply can not be sent to the client.
+    async fn correct_video(&mut self, youtube_id: &str) -> Result<()> {
+        let youtube_id = youtube_id.trim();
+        let Some(play) = self.play.as_mut().filter(|_| !youtube_id.is_empty()) else {
+            let e = Error::Protocol("nothing is playing or the youtube id is empty".to_string());
+            warn!("Ignoring correction: {e}");
+            self.writer
+                .send(ServerMessage::error(&e).to_message())
+                .await?;
             return Ok(());
+        };
+        play.tick();
+        let mut song = play.song.clone();
+        song.progress = play.progress();
+        if let Some(id) = play.id {
+            if let Err(e) = self.history.correct(id, youtube_id).await {
+                error!("Failed to record the correction: {e}");
+            }
         }
+        if self.config.cache.enabled {
+            if let Err(e) = self.store_correction(&song, youtube_id).await {
+                error!("Failed to cache the corrected video: {e}");
+            }
+        }
+        info!(youtube_id, "Video corrected for {song}");
+        let url = Song::get_url_with_duration(youtube_id, &song.progress.to_string());
+        self.send_video(url).await
+    }
 
-        let vid = self.yt_client.get_song_vid(&song).await;
-        if let Ok((url, id)) = vid {
-            info!("Song is not in database, adding to database");
-            match self.db_pool.create(song, &id).await {
-                Ok(_) => info!("Added song to database"),
-                Err(e) => error!("Failed to add song to database: {e}"),
+    /// Replaces the cached video of `song`, or caches it if it was not cached yet.
+    async fn store_correction(&self, song: &Song, youtube_id: &str) -> Result<()> {
+        let profile = self.yt_client.profile();
+        match self
+            .db_pool
+            .get(song, self.yt_client.region(), &profile)
+            .await?
+        {
+            Some(cached) => self
+                .db_pool
+                .set_youtube_id(cached.id, youtube_id)
+                .await
+                .map(|_| ()),
+            None => {
+                self.db_pool
+                    .create(song.clone(), &youtube_id.to_string(), None, None, &profile)
+                    .await
             }
-            self.send_video(Ok(url)).await?;
-            return Ok(());
         }
-        Ok(())
     }
 
-    /// Sends the video url to the client given a [`Result`] containing the url.
+    /// Connects the listener to Last.fm with the token they got after allowing the scrobbles,
+    /// or sends them the page allowing the scrobbles if there is no token.
     /// # Errors
-    /// This function will return an error if there is an error while sending the video
-    /// to the client via the websocket.
-    async fn send_video(&mut self, vid: Result<String, Error>) -> Result<(), Error> {
-        let msg = match vid {
-            Ok(url) => url,
-            Err(e) => {
-                error!("Failed to get video: {e}");
-                "Failed to get video".to_string()
+    /// This function will return an error if the reply can not be sent to the client.
+    async fn connect_lastfm(&mut self, token: Option<&str>) -> Result<()> {
+        let token = token.map(str::trim).filter(|token| !token.is_empty());
+        let reply = match (self.lastfm_user(), token) {
+            (Ok((scrobbler, _)), None) => Ok(ServerMessage::LastfmAuth {
+                url: scrobbler.auth_url(),
+            }),
+            (Ok((scrobbler, user)), Some(token)) => {
+                scrobbler
+                    .connect(user, token)
+                    .await
+                    .map(|username| ServerMessage::Lastfm {
+                        username: Some(username),
+                    })
             }
+            (Err(e), _) => Err(e),
         };
+        let reply = reply.unwrap_or_else(|e| {
+            warn!("Failed to connect to Last.fm: {e}");
+            ServerMessage::error(&e)
+        });
+        self.writer.send(reply.to_message()).await?;
+        Ok(())
+    }
 
-        self.writer.send(Message::text(msg)).await?;
+    /// Disconnects the listener from Last.fm.
+    /// # Errors
+    /// This function will return an error if the reply can not be sent to the client.
+    async fn disconnect_lastfm(&mut self) -> Result<()> {
+        let reply = match self.lastfm_user() {
+            Ok((scrobbler, user)) => scrobbler
+                .disconnect(user)
+                .await
+                .map(|_| ServerMessage::Lastfm { username: None }),
+            Err(e) => Err(e),
+        };
+        let reply = reply.unwrap_or_else(|e| {
+            warn!("Failed to disconnect from Last.fm: {e}");
+            ServerMessage::error(&e)
+        });
+        self.writer.send(reply.to_message()).await?;
         Ok(())
     }
 
-    /// Returns a boolean indicating if the state has changed.
-    fn check_state_change(&mut self, state: &CurrentlyPlayingContext) -> bool {
-        let prev_item = match self.get_prev_item() {
-            Ok(value) => value,
-            Err(value) => {
-                if value {
-                    self.update_state(state);
+    /// Returns the scrobbler and the listener's id.
+    /// # Errors
+    /// This function will return an error if scrobbling is disabled or the listener is unknown.
+    fn lastfm_user(&self) -> Result<(&Scrobbler, &str)> {
+        let scrobbler = self.scrobbler.as_ref().ok_or(Error::LastfmDisabled)?;
+        let user = self.listener.id.as_deref().ok_or_else(|| {
+            Error::Protocol("the Spotify user is unknown, Last.fm can't be connected".to_string())
+        })?;
+        Ok((scrobbler, user))
+    }
+
+    /// Records the start of a play in the listener's history, and sends it to Last.fm as now playing.
+    /// Plays of unknown listeners are only kept for the session, so they can still be corrected.
+    async fn start_play(&mut self, song: Song, video: Option<&SentVideo>, playing: bool) {
+        let id = match &self.listener.id {
+            Some(user) => {
+                let youtube_id = video.map(|video| video.youtube_id.as_str());
+                let source = video.map(|video| video.source.as_str());
+                match self.history.start(user, &song, youtube_id, source).await {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        error!("Failed to record the play: {e}");
+                        None
+                    }
                 }
-                return value;
             }
+            None => None,
         };
+        let play = CurrentPlay::new(song, id, playing, self.poller.clock().clone());
+        if let Some((scrobbler, user)) = self.scrobbled_user(&play) {
+            scrobbler.now_playing(user, Scrobble::new(&play.song, play.started_at));
+        }
+        self.play = Some(play);
+    }
 
-        let curr_item = match &state.item {
-            Some(item) => item,
-            None => {
-                warn!("Current item was None");
-                self.update_state(state);
-                return true;
+    /// Stores how long the current play lasted, and scrobbles it if it played long enough.
+    async fn finish_play(&mut self) {
+        let Some(mut play) = self.play.take() else {
+            return;
+        };
+        play.tick();
+        if let Some((scrobbler, user)) = self.scrobbled_user(&play) {
+            let scrobble = Scrobble::new(&play.song, play.started_at);
+            if should_scrobble(scrobble.duration, play.listened_secs()) {
+                scrobbler.scrobble(user, scrobble);
             }
+        }
+        let Some(id) = play.id else {
+            return;
         };
+        if let Err(e) = self.history.finish(id, play.listened_secs()).await {
+            error!("Failed to record the listened duration: {e}");
+        }
+    }
 
-        if Self::compare_tracks(prev_item, curr_item) {
-            self.update_state(state);
-            return true;
+    /// Returns the scrobbler and the listener's id if the play is a track that can be scrobbled.
+    fn scrobbled_user(&self, play: &CurrentPlay) -> Option<(&Scrobbler, &str)> {
+        if play.song.kind != ItemKind::Track {
+            return None;
         }
-        false
+        self.scrobbler.as_ref().zip(self.listener.id.as_deref())
     }
 
-    /// Returns a boolean indicating if the tracks are different.
-    fn compare_tracks(prev_item: &PlayableItem, curr_item: &PlayableItem) -> bool {
-        let prev_track = match prev_item {
-            PlayableItem::Track(track) => track,
-            PlayableItem::Episode(_) => {
-                warn!("Previous item was an episode");
-                return false;
-            }
+    fn observe_play(&mut self, playing: bool) {
+        if let Some(play) = &mut self.play {
+            play.observe(playing);
+        }
+    }
+
+    /// Warms the cache from the given source in the background.
+    /// Progress is reported to the client through `events`.
+    fn start_warm(&self, source: WarmSource, events: UnboundedSender<ServerMessage>) {
+        if !self.config.cache.enabled {
+            let _ = events.send(ServerMessage::error(&Error::CacheDisabled));
+            return;
+        }
+        let Some(spotify) = self.spotify.clone() else {
+            let e = Error::Protocol("warming needs a Spotify session".to_string());
+            let _ = events.send(ServerMessage::error(&e));
+            return;
         };
-        let curr_track = match curr_item {
-            PlayableItem::Track(track) => track,
-            PlayableItem::Episode(_) => {
-                warn!("Current item was an episode");
-                return false;
+        info!(?source, "Warming the cache");
+        let warmer = Warmer::new(
+            self.yt_client.clone(),
+            self.db_pool.clone(),
+            self.config.cache.warm.clone(),
+            self.poller.clock().clone(),
+        );
+        tokio::spawn(
+            async move {
+                let songs = match source {
+                    WarmSource::Saved => collect_saved_songs(&spotify).await,
+                    WarmSource::Queue => collect_queued_songs(&spotify).await,
+                    source => collect_songs(&spotify, &source).await,
+                };
+                match songs {
+                    Ok(songs) => {
+                        warmer
+                            .run(songs, |progress| {
+                                // the client may have disconnected, the warm still completes
+                                let _ = events.send(ServerMessage::WarmProgress(progress.clone()));
+                            })
+                            .await;
+                    }
+                    Err(e) => {
+                        error!("Failed to list tracks to warm: {e}");
+                        let _ = events.send(ServerMessage::error(&e));
+                    }
+                }
             }
-        };
-        prev_track.name != curr_track.name
+            .in_current_span(),
+        );
+    }
+
+    /// Sends the video of the new song to the client and records it in the history.
+    /// A song looked up again after the preferences changed is still the same play.
+    /// # Errors
+    /// This function will return an error if the video can not be found or sent.
+    #[instrument(skip_all)]
+    async fn handle_state_change(&mut self, playback: Playback) -> Result<()> {
+        let Playback { song, is_playing } = playback;
+        if self.play.as_ref().map_or(false, |play| play.is_song(&song)) {
+            return self.send_song_video(&song).await;
+        }
+        self.play_new(song, is_playing).await
+    }
+
+    /// Finishes the current play and starts recording `song`, sending its video.
+    /// # Errors
+    /// This function will return an error if the video can not be found or sent.
+    async fn play_new(&mut self, song: Song, is_playing: bool) -> Result<()> {
+        self.finish_play().await;
+        let sent = self.send_song_video(&song).await;
+        let video = self.video.clone();
+        self.start_play(song, video.as_ref(), is_playing).await;
+        sent
+    }
+
+    /// Sends the video of the playing song again at the position it was seeked to,
+    /// without looking it up again. When no video was found, the song is looked up again.
+    /// A song starting over after it ended is recorded as a new play.
+    /// # Errors
+    /// This function will return an error if the video can not be found or sent.
+    async fn handle_seek(&mut self, playback: Playback) -> Result<()> {
+        if let Some(play) = &mut self.play {
+            play.observe(playback.is_playing);
+            if play.is_replay(&playback.song) {
+                info!("Song playing again, recording a new play");
+                return self.play_new(playback.song, playback.is_playing).await;
+            }
+            play.seek(playback.song.progress);
+        }
+        match &self.video {
+            Some(video) => {
+                let url = video.url(&playback.song);
+                self.send_video(url).await
+            }
+            None => self.send_song_video(&playback.song).await,
+        }
     }
 
-    /// Returns the get prev item of this [`SpotifyClient`].
+    /// Sends the video url to the client, keeping the video sent in the session.
+    /// Cache is checked first, if the song is not in the cache, it will be added.
+    /// The cache is skipped entirely when `cache.enabled` is false.
+    /// When no provider finds a video, a [`ServerMessage::NoVideo`] describing the song
+    /// and the colours of its artwork is sent instead, and no video is kept.
     /// # Errors
-    /// This function will return an error containing a boolean indicating if the state should be updated.
-    fn get_prev_item(&mut self) -> Result<&PlayableItem, bool> {
-        let prev = if let Some(prev) = self.prev_state.as_ref() {
-            prev
-        } else {
-            warn!("Previous state was None");
-            return Err(true);
+    /// This function will return an error if sending the message fails.
+    /// # Logging
+    /// This function will log an error if there is an error while adding the song to the database.
+    async fn send_song_video(&mut self, song: &Song) -> Result<()> {
+        self.video = None;
+        let use_cache = self.config.cache.enabled;
+        info!("Checking if song is in database");
+        let timer = metrics::LOOKUP_DURATION
+            .with_label_values(&["cache"])
+            .start_timer();
+        let cached = self.cached_video(song, use_cache).await;
+        timer.observe_duration();
+        if use_cache {
+            let result = if cached.is_some() { "hit" } else { "miss" };
+            metrics::CACHE_LOOKUPS.with_label_values(&[result]).inc();
+        }
+        if let Some(cached) = cached {
+            info!("Song is in database, sending video");
+            let video = SentVideo {
+                youtube_id: cached.youtube_id,
+                source: "cache".to_string(),
+                start_offset: i64::from(cached.start_offset),
+                chapters: Vec::new(),
+            };
+            return self.send_found_video(video, song).await;
+        }
+
+        let timer = metrics::LOOKUP_DURATION
+            .with_label_values(&["youtube"])
+            .start_timer();
+        let vid = self.yt_client.get_song_vid(song).await;
+        timer.observe_duration();
+        let (_, video) = match vid {
+            Ok(vid) => vid,
+            Err(e) => {
+                warn!("No video found for {song}: {e}");
+                let colors = artwork::palette(&self.http, &song.artwork).await;
+                let now_playing = NowPlaying::new(song, colors);
+                self.writer
+                    .send(ServerMessage::no_video(now_playing, &e).to_message())
+                    .await?;
+                return Ok(());
+            }
         };
-        let prev_item = match &prev.item {
-            Some(item) => item,
-            None => {
-                warn!("Previous item was None");
-                return Err(true);
+        if use_cache {
+            info!("Song is not in database, adding to database");
+            let region = self.yt_client.cache_region(&video);
+            let profile = self.yt_client.profile();
+            match self
+                .db_pool
+                .create(
+                    song.clone(),
+                    &video.video_id,
+                    video.channel_id.as_deref(),
+                    region,
+                    &profile,
+                )
+                .await
+            {
+                Ok(_) => info!("Added song to database"),
+                Err(e) => error!("Failed to add song to database: {e}"),
             }
+        }
+        let video = SentVideo {
+            youtube_id: video.video_id,
+            source: video.provider,
+            start_offset: 0,
+            chapters: video.chapters,
         };
-        Ok(prev_item)
+        self.send_found_video(video, song).await
     }
 
-    /// Updates the previous state of this [`SpotifyClient`].
-    fn update_state(&mut self, state: &CurrentlyPlayingContext) {
-        self.prev_state = Some(state.clone());
+    /// Sends `video` at the position of `song` and keeps it as the current video.
+    /// # Errors
+    /// This function will return an error if sending the video fails.
+    async fn send_found_video(&mut self, video: SentVideo, song: &Song) -> Result<()> {
+        self.send_video(video.url(song)).await?;
+        self.video = Some(video);
+        Ok(())
+    }
+
+    /// Returns the cached video for the song, if the cache is enabled and contains it.
+    /// A cached video blocked since it was cached is removed, so a new one is searched for.
+    async fn cached_video(&self, song: &Song, use_cache: bool) -> Option<Songs> {
+        if !use_cache {
+            return None;
+        }
+        // a video can still be searched for when the cache can not be read
+        let cached = self
+            .db_pool
+            .get(song, self.yt_client.region(), &self.yt_client.profile())
+            .await
+            .map_err(|e| error!("Failed to read the cached video of {song}: {e}"))
+            .ok()
+            .flatten()?;
+        if !self
+            .yt_client
+            .is_blocked(&cached.youtube_id, cached.channel_id.as_deref())
+        {
+            return Some(cached);
+        }
+        info!("The cached video of {song} is blocked, searching for another one");
+        if let Err(e) = self.db_pool.delete(cached.id).await {
+            error!("Failed to remove the blocked video of {song} from the cache: {e}");
+        }
+        None
+    }
+
+    /// Sends the video url to the client.
+    /// # Errors
+    /// This function will return an error if there is an error while sending the video
+    /// to the client via the websocket.
+    #[instrument(skip_all)]
+    async fn send_video(&mut self, url: String) -> Result<()> {
+        self.writer.send(Message::text(url)).await?;
+        Ok(())
     }
 }
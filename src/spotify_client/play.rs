@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use spotify_music_vid::{
+    chapters::{self, Chapter},
+    clock::SharedClock,
+    Song,
+};
+use uuid::Uuid;
+
+/// A song jumping back within this many seconds of its start, after playing to within
+/// this many seconds of its end, is playing again rather than seeked
+const REPLAY_WINDOW: i64 = 10;
+
+/// A video sent to the client.
+#[derive(Debug, Clone)]
+pub struct SentVideo {
+    pub youtube_id: String,
+    /// `cache`, or the provider that found the video
+    pub source: String,
+    /// Seconds into the video the song starts at
+    pub start_offset: i64,
+    /// Chapters listed in the description of the video, only read for podcast episodes
+    pub chapters: Vec<Chapter>,
+}
+
+impl SentVideo {
+    /// Returns the embed url of the video at the position of `song`,
+    /// going through the chapter playing when the video lists it.
+    pub fn url(&self, song: &Song) -> String {
+        let progress = chapters::position(&song.chapters, &self.chapters, song.progress)
+            .unwrap_or(song.progress);
+        let start = progress + self.start_offset;
+        Song::get_url_with_duration(&self.youtube_id, &start.to_string())
+    }
+}
+
+/// The song playing in a session, and how long it has been playing.
+#[derive(Debug)]
+pub struct CurrentPlay {
+    pub song: Song,
+    /// The history entry of the play, `None` when it was not recorded
+    pub id: Option<Uuid>,
+    pub started_at: DateTime<Utc>,
+    listened: Duration,
+    /// Seconds skipped by seeking, negative when seeking back
+    skipped: i64,
+    /// When the play was last observed, on the clock of the session
+    last_seen: Duration,
+    playing: bool,
+    clock: SharedClock,
+}
+
+impl CurrentPlay {
+    pub fn new(song: Song, id: Option<Uuid>, playing: bool, clock: SharedClock) -> Self {
+        Self {
+            song,
+            id,
+            started_at: clock.utc(),
+            listened: Duration::ZERO,
+            skipped: 0,
+            last_seen: clock.now(),
+            playing,
+            clock,
+        }
+    }
+
+    /// Updates the listened time after a poll, time spent paused is not counted.
+    pub fn observe(&mut self, playing: bool) {
+        let now = self.clock.now();
+        if self.playing {
+            self.listened += now.saturating_sub(self.last_seen);
+        }
+        self.last_seen = now;
+        self.playing = playing;
+    }
+
+    /// Updates the listened time without changing the playback state.
+    pub fn tick(&mut self) {
+        self.observe(self.playing);
+    }
+
+    /// Moves the current position to `progress` seconds, the listened time is unchanged.
+    pub fn seek(&mut self, progress: i64) {
+        self.skipped += progress - self.progress();
+    }
+
+    /// Returns whether `song` is the song of this play.
+    pub fn is_song(&self, song: &Song) -> bool {
+        self.song.track_id == song.track_id && self.song.name == song.name
+    }
+
+    /// Returns whether `song` is the song of this play starting over after it ended,
+    /// on repeat or restarted by the listener, which is a new play rather than a seek.
+    pub fn is_replay(&self, song: &Song) -> bool {
+        let Some(duration) = self.song.duration else {
+            return false;
+        };
+        self.is_song(song)
+            && song.progress <= REPLAY_WINDOW
+            && self.progress() >= duration - REPLAY_WINDOW
+    }
+
+    /// Returns the whole seconds the song was playing.
+    pub fn listened_secs(&self) -> i32 {
+        i32::try_from(self.listened.as_secs()).unwrap_or(i32::MAX)
+    }
+
+    /// Returns the current position in the song in seconds.
+    pub fn progress(&self) -> i64 {
+        self.song.progress + i64::from(self.listened_secs()) + self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use spotify_music_vid::clock::ManualClock;
+
+    use super::*;
+
+    fn song(progress: i64) -> Song {
+        let mut song = Song::new(
+            "Around the World".to_string(),
+            "Daft Punk".to_string(),
+            progress,
+        );
+        song.track_id = Some("track".to_string());
+        song.duration = Some(240);
+        song
+    }
+
+    #[test]
+    fn starting_over_after_the_end_is_a_replay() {
+        let clock = ManualClock::default();
+        let mut play = CurrentPlay::new(song(200), None, true, clock.shared());
+        clock.advance(Duration::from_secs(42));
+        play.tick();
+        assert!(play.is_replay(&song(1)));
+    }
+
+    #[test]
+    fn seeking_back_before_the_end_is_not_a_replay() {
+        let clock = ManualClock::default();
+        let mut play = CurrentPlay::new(song(60), None, true, clock.shared());
+        clock.advance(Duration::from_secs(30));
+        play.tick();
+        assert!(!play.is_replay(&song(0)));
+    }
+
+    #[test]
+    fn seeking_to_the_middle_after_the_end_is_not_a_replay() {
+        let clock = ManualClock::default();
+        let play = CurrentPlay::new(song(238), None, true, clock.shared());
+        assert!(!play.is_replay(&song(120)));
+    }
+
+    #[test]
+    fn another_song_is_not_a_replay() {
+        let clock = ManualClock::default();
+        let play = CurrentPlay::new(song(238), None, true, clock.shared());
+        let mut other = song(0);
+        other.track_id = Some("other".to_string());
+        assert!(!play.is_replay(&other));
+    }
+
+    #[test]
+    fn time_paused_does_not_reach_the_end() {
+        let clock = ManualClock::default();
+        let mut play = CurrentPlay::new(song(100), None, false, clock.shared());
+        clock.advance(Duration::from_secs(300));
+        play.tick();
+        assert!(!play.is_replay(&song(0)));
+    }
+
+    fn chapter(start: i64, title: &str) -> Chapter {
+        Chapter {
+            start,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn videos_start_at_the_song_position_after_their_offset() {
+        let video = SentVideo {
+            youtube_id: "dQw4w9WgXcQ".to_string(),
+            source: "cache".to_string(),
+            start_offset: 12,
+            chapters: Vec::new(),
+        };
+        assert_eq!(
+            video.url(&song(60)),
+            Song::get_url_with_duration("dQw4w9WgXcQ", "72")
+        );
+    }
+
+    #[test]
+    fn videos_with_chapters_start_at_the_chapter_playing() {
+        let video = SentVideo {
+            youtube_id: "episode".to_string(),
+            source: "youtube".to_string(),
+            start_offset: 0,
+            chapters: vec![chapter(0, "Intro"), chapter(300, "Interview")],
+        };
+        let mut episode = song(130);
+        episode.chapters = vec![chapter(0, "Intro"), chapter(120, "Interview")];
+        assert_eq!(
+            video.url(&episode),
+            Song::get_url_with_duration("episode", "310")
+        );
+    }
+}
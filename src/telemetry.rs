@@ -0,0 +1,58 @@
+//! Export of the tracing spans to an OpenTelemetry collector over OTLP/HTTP.
+//!
+//! Only available with the `otel` cargo feature, without it [`layer`] never exports anything.
+
+use color_eyre::Result;
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::db::config::TelemetryConfig;
+
+/// Returns the layer exporting spans to `telemetry.otlp_endpoint`,
+/// or `None` when no endpoint is configured.
+/// # Errors
+/// This function will return an error if the exporter can not be built.
+#[cfg(feature = "otel")]
+pub fn layer<S>(config: &TelemetryConfig) -> Result<Option<impl Layer<S>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    use opentelemetry::{
+        runtime::Tokio,
+        sdk::{trace, Resource},
+        KeyValue,
+    };
+    use opentelemetry_otlp::WithExportConfig;
+
+    if config.otlp_endpoint.is_empty() {
+        return Ok(None);
+    }
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(&config.otlp_endpoint);
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(Tokio)?;
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Without the `otel` feature spans are never exported.
+/// # Errors
+/// Never fails, the signature matches the exporting version.
+#[cfg(not(feature = "otel"))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn layer<S>(_config: &TelemetryConfig) -> Result<Option<impl Layer<S>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    Ok(None::<tracing_subscriber::layer::Identity>)
+}
+
+/// Sends the spans that are still buffered, called before the process exits.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
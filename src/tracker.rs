@@ -0,0 +1,109 @@
+//! What changed between two polls of a [`PlaybackSource`](crate::source::PlaybackSource).
+
+use std::time::Duration;
+
+use crate::{source::Playback, Song};
+
+/// Progress jumps up to this long are not seeks, the progress is reported in whole seconds
+/// and polls don't happen exactly on time.
+pub const DEFAULT_SEEK_TOLERANCE: Duration = Duration::from_secs(3);
+
+/// The change seen by a poll, see [`StateTracker::observe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Nothing is playing
+    Stopped,
+    /// Another track or episode started, or the first one since the tracker was reset
+    Started,
+    /// The same item continued at `to` seconds instead of around `from`
+    Seeked {
+        from: i64,
+        to: i64,
+    },
+    Paused,
+    Resumed,
+    Unchanged,
+}
+
+/// The playback seen by the previous poll.
+#[derive(Debug)]
+struct Seen {
+    song: Song,
+    is_playing: bool,
+    /// When the poll happened
+    at: Duration,
+}
+
+impl Seen {
+    /// Returns where the item should be at `now` if it kept playing.
+    fn expected_progress(&self, now: Duration) -> i64 {
+        if !self.is_playing {
+            return self.song.progress;
+        }
+        let elapsed = i64::try_from(now.saturating_sub(self.at).as_secs()).unwrap_or(i64::MAX);
+        self.song.progress.saturating_add(elapsed)
+    }
+}
+
+/// Compares every poll with the previous one.
+#[derive(Debug)]
+pub struct StateTracker {
+    prev: Option<Seen>,
+    seek_tolerance: Duration,
+}
+
+impl Default for StateTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEEK_TOLERANCE)
+    }
+}
+
+impl StateTracker {
+    /// Creates a [`StateTracker`] reporting progress jumps longer than `seek_tolerance` as seeks.
+    #[must_use]
+    pub const fn new(seek_tolerance: Duration) -> Self {
+        Self {
+            prev: None,
+            seek_tolerance,
+        }
+    }
+
+    /// Forgets the previous poll, the next item seen is [`Change::Started`] again.
+    pub fn reset(&mut self) {
+        self.prev = None;
+    }
+
+    /// Returns what changed since the previous poll, `playback` is `None` when nothing is playing.
+    /// `now` is the time of the poll, from any origin as long as it is the same for every poll.
+    /// Items that stop and play again are the same item, resumed.
+    pub fn observe(&mut self, playback: Option<&Playback>, now: Duration) -> Change {
+        let Some(playback) = playback else {
+            if let Some(prev) = &mut self.prev {
+                prev.is_playing = false;
+            }
+            return Change::Stopped;
+        };
+        let change = match &self.prev {
+            Some(prev) if prev.song.is_same_item(&playback.song) => {
+                let from = prev.expected_progress(now);
+                let to = playback.song.progress;
+                if from.abs_diff(to) > self.seek_tolerance.as_secs() {
+                    Change::Seeked { from, to }
+                } else if prev.is_playing == playback.is_playing {
+                    Change::Unchanged
+                } else if playback.is_playing {
+                    Change::Resumed
+                } else {
+                    Change::Paused
+                }
+            }
+            _ => Change::Started,
+        };
+        self.prev = Some(Seen {
+            song: playback.song.clone(),
+            is_playing: playback.is_playing,
+            at: now,
+        });
+        change
+    }
+}
@@ -0,0 +1,283 @@
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use rspotify::{
+    model::{AlbumId, IdError, PlayableItem, PlaylistId},
+    prelude::{BaseClient, OAuthClient},
+};
+use spotify_music_vid::{
+    clock::SharedClock,
+    protocol::{WarmProgress, WarmSource},
+    Error, Result, Song,
+};
+use tokio::sync::Mutex;
+use tracing::{error, info, instrument, warn};
+
+use crate::{
+    db::{config::WarmConfig, songs::SongRepository},
+    youtube_client::YoutubeClient,
+};
+
+/// What happened to a single track while warming.
+enum Outcome {
+    Cached,
+    Added,
+    Failed,
+    Skipped,
+}
+
+/// Resolves the videos of many songs ahead of time and stores them in the cache.
+#[derive(Clone)]
+pub struct Warmer {
+    yt_client: YoutubeClient,
+    repo: SongRepository,
+    config: WarmConfig,
+    clock: SharedClock,
+}
+
+impl Warmer {
+    /// Creates a [`Warmer`] leaving `cache.warm.quota_reserve` units of the quota of `yt_client` to playback.
+    /// The searches are spaced with `clock`.
+    pub fn new(
+        yt_client: YoutubeClient,
+        repo: SongRepository,
+        config: WarmConfig,
+        clock: SharedClock,
+    ) -> Self {
+        Self {
+            yt_client: yt_client.with_quota_reserve(config.quota_reserve),
+            repo,
+            config,
+            clock,
+        }
+    }
+
+    /// Resolves every song that is not cached yet, `cache.warm.concurrency` at a time.
+    /// Youtube searches are spaced by `cache.warm.request_interval_ms`, and songs are skipped
+    /// once the remaining quota falls to `cache.warm.quota_reserve`.
+    /// `on_progress` is called after every song and once more when all songs are handled.
+    #[instrument(skip_all, fields(songs = songs.len()))]
+    pub async fn run(
+        &self,
+        songs: Vec<Song>,
+        mut on_progress: impl FnMut(&WarmProgress),
+    ) -> WarmProgress {
+        let mut progress = WarmProgress {
+            total: songs.len(),
+            ..WarmProgress::default()
+        };
+        // the time of the next search
+        let limiter = Mutex::new(self.clock.now());
+
+        let mut outcomes = stream::iter(songs)
+            .map(|song| self.warm_song(song, &limiter))
+            .buffer_unordered(self.config.concurrency.max(1));
+        while let Some(outcome) = outcomes.next().await {
+            match outcome {
+                Outcome::Cached => progress.cached += 1,
+                Outcome::Added => progress.added += 1,
+                Outcome::Failed => progress.failed += 1,
+                Outcome::Skipped => progress.skipped += 1,
+            }
+            on_progress(&progress);
+        }
+
+        progress.finished = true;
+        on_progress(&progress);
+        info!(?progress, "Finished warming the cache");
+        progress
+    }
+
+    async fn warm_song(&self, song: Song, limiter: &Mutex<Duration>) -> Outcome {
+        match self
+            .repo
+            .get(&song, self.yt_client.region(), &self.yt_client.profile())
+            .await
+        {
+            Ok(Some(_)) => return Outcome::Cached,
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to look up {song} in the database: {e}");
+                return Outcome::Failed;
+            }
+        }
+        self.wait_turn(limiter).await;
+
+        let video = match self.yt_client.get_song_vid(&song).await {
+            Ok((_, video)) => video,
+            Err(Error::QuotaExceeded) => {
+                warn!("Skipping {song}, the remaining youtube quota is reserved for playback");
+                return Outcome::Skipped;
+            }
+            Err(e) => {
+                error!("Failed to resolve {song}: {e}");
+                return Outcome::Failed;
+            }
+        };
+        let region = self.yt_client.cache_region(&video);
+        let profile = self.yt_client.profile();
+        match self
+            .repo
+            .create(
+                song,
+                &video.video_id,
+                video.channel_id.as_deref(),
+                region,
+                &profile,
+            )
+            .await
+        {
+            Ok(_) => Outcome::Added,
+            Err(e) => {
+                error!("Failed to add song to database: {e}");
+                Outcome::Failed
+            }
+        }
+    }
+
+    /// Waits until the search of a song is allowed, `cache.warm.request_interval_ms` after
+    /// the previous one. Searches that would have been allowed while waiting are not made up for.
+    async fn wait_turn(&self, limiter: &Mutex<Duration>) {
+        let interval = Duration::from_millis(self.config.request_interval_ms.max(1));
+        let turn = {
+            let mut next = limiter.lock().await;
+            let turn = (*next).max(self.clock.now());
+            *next = turn.saturating_add(interval);
+            turn
+        };
+        self.clock.sleep_until(turn).await;
+    }
+}
+
+/// Number of items requested per page, the maximum allowed by Spotify
+const PAGE_SIZE: u32 = 50;
+
+// The `*_manual` endpoints are used instead of rspotify's paginators because
+// those streams are not `Send` and warming runs in a spawned task.
+
+/// Lists the songs of a playlist or album, following pagination.
+/// # Errors
+/// This function will return an error if the id is invalid, the source is the saved library
+/// or the queue (which need [`collect_saved_songs`] and [`collect_queued_songs`])
+/// or a request to Spotify fails.
+pub async fn collect_songs(spotify: &impl BaseClient, source: &WarmSource) -> Result<Vec<Song>> {
+    let mut songs = Vec::new();
+    let mut offset = 0;
+    match source {
+        WarmSource::Playlist(id) => loop {
+            let id = PlaylistId::from_id_or_uri(id).map_err(|e| invalid_id(id, &e))?;
+            let page = spotify
+                .playlist_items_manual(id, None, None, Some(PAGE_SIZE), Some(offset))
+                .await?;
+            songs.extend(page.items.into_iter().filter_map(|item| match item.track {
+                Some(PlayableItem::Track(track)) => Some(Song::from_track(track, 0)),
+                _ => None,
+            }));
+            if page.next.is_none() {
+                break;
+            }
+            offset += PAGE_SIZE;
+        },
+        WarmSource::Album(id) => loop {
+            let id = AlbumId::from_id_or_uri(id).map_err(|e| invalid_id(id, &e))?;
+            let page = spotify
+                .album_track_manual(id, Some(PAGE_SIZE), Some(offset))
+                .await?;
+            songs.extend(page.items.into_iter().map(Song::from_simplified_track));
+            if page.next.is_none() {
+                break;
+            }
+            offset += PAGE_SIZE;
+        },
+        WarmSource::Saved | WarmSource::Queue => {
+            return Err(Error::Protocol(
+                "The saved library and the queue can only be read with a user token".to_string(),
+            ))
+        }
+    };
+    Ok(songs)
+}
+
+fn invalid_id(id: &str, err: &IdError) -> Error {
+    Error::Protocol(format!("Invalid id {id:?}: {err}"))
+}
+
+/// Lists the songs saved in the user's library, following pagination.
+/// # Errors
+/// This function will return an error if a request to Spotify fails.
+pub async fn collect_saved_songs(spotify: &impl OAuthClient) -> Result<Vec<Song>> {
+    let mut songs = Vec::new();
+    let mut offset = 0;
+    loop {
+        let page = spotify
+            .current_user_saved_tracks_manual(None, Some(PAGE_SIZE), Some(offset))
+            .await?;
+        songs.extend(
+            page.items
+                .into_iter()
+                .map(|saved| Song::from_track(saved.track, 0)),
+        );
+        if page.next.is_none() {
+            break;
+        }
+        offset += PAGE_SIZE;
+    }
+    Ok(songs)
+}
+
+/// Lists the tracks queued to play next, episodes are left out.
+/// # Errors
+/// This function will return an error if the request to Spotify fails.
+pub async fn collect_queued_songs(spotify: &impl OAuthClient) -> Result<Vec<Song>> {
+    let queue = spotify.current_user_queue().await?;
+    Ok(queue
+        .queue
+        .into_iter()
+        .filter_map(|item| match item {
+            PlayableItem::Track(track) => Some(Song::from_track(track, 0)),
+            PlayableItem::Episode(_) => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::FutureExt;
+    use spotify_music_vid::clock::{Clock, ManualClock};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::db::config::YoutubeConfig;
+
+    #[tokio::test]
+    async fn searches_are_spaced_by_the_clock() {
+        let clock = ManualClock::default();
+        let pool = Arc::new(PgPool::connect_lazy("postgres://localhost/unused").unwrap());
+        let config = WarmConfig {
+            request_interval_ms: 1000,
+            ..WarmConfig::default()
+        };
+        let warmer = Warmer::new(
+            YoutubeClient::new(&YoutubeConfig::default(), clock.shared()),
+            SongRepository::new(pool),
+            config,
+            clock.shared(),
+        );
+        let limiter = Mutex::new(clock.now());
+
+        assert!(warmer.wait_turn(&limiter).now_or_never().is_some());
+        let mut second = Box::pin(warmer.wait_turn(&limiter));
+        assert!((&mut second).now_or_never().is_none());
+        clock.advance(Duration::from_millis(999));
+        assert!((&mut second).now_or_never().is_none());
+        clock.advance(Duration::from_millis(1));
+        assert!(second.now_or_never().is_some());
+
+        // the searches are not made up for after a pause
+        clock.advance(Duration::from_secs(10));
+        assert!(warmer.wait_turn(&limiter).now_or_never().is_some());
+        assert!(warmer.wait_turn(&limiter).now_or_never().is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use std::{sync::Mutex, time::Duration};
+
+use spotify_music_vid::clock::SharedClock;
+
+/// Skips a video provider after `threshold` consecutive failures.
+/// Once `cooldown` has passed a single search is let through as a probe,
+/// its outcome closes the breaker again or keeps it open for another `cooldown`.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    clock: SharedClock,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    /// Times are on the clock of the breaker
+    Open {
+        until: Duration,
+    },
+    /// A probe was let through at `since` and has not finished yet
+    HalfOpen {
+        since: Duration,
+    },
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration, clock: SharedClock) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            clock,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Returns whether the provider may be called, moving an open breaker whose cooldown
+    /// has passed to half-open.
+    pub fn allow(&self) -> bool {
+        let mut state = self.lock();
+        let now = self.clock.now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            // a probe that never reported back, e.g. because it was cancelled, is retried
+            State::HalfOpen { since } if now.saturating_sub(since) >= self.cooldown => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn success(&self) {
+        *self.lock() = State::Closed { failures: 0 };
+    }
+
+    pub fn failure(&self) {
+        let mut state = self.lock();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::Open { .. } | State::HalfOpen { .. } => self.threshold,
+        };
+        *state = if failures >= self.threshold {
+            State::Open {
+                until: self.clock.now() + self.cooldown,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    /// Returns whether the provider is currently skipped.
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.lock(), State::Closed { .. })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // the state is always valid, even if a thread panicked while holding the lock
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use spotify_music_vid::clock::ManualClock;
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    fn breaker(clock: &ManualClock) -> CircuitBreaker {
+        CircuitBreaker::new(3, COOLDOWN, clock.shared())
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let clock = ManualClock::default();
+        let breaker = breaker(&clock);
+        breaker.failure();
+        breaker.failure();
+        // a success in between starts the count over
+        breaker.success();
+        breaker.failure();
+        breaker.failure();
+        assert!(breaker.allow());
+        assert!(!breaker.is_open());
+
+        breaker.failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn lets_a_single_probe_through_after_the_cooldown() {
+        let clock = ManualClock::default();
+        let breaker = breaker(&clock);
+        (0..3).for_each(|_| breaker.failure());
+
+        clock.advance(COOLDOWN - Duration::from_secs(1));
+        assert!(!breaker.allow());
+        clock.advance(Duration::from_secs(1));
+        assert!(breaker.allow());
+        // the probe has not reported back yet
+        assert!(!breaker.allow());
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_breaker() {
+        let clock = ManualClock::default();
+        let breaker = breaker(&clock);
+        (0..3).for_each(|_| breaker.failure());
+        clock.advance(COOLDOWN);
+        assert!(breaker.allow());
+
+        breaker.success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn a_failed_probe_opens_the_breaker_for_another_cooldown() {
+        let clock = ManualClock::default();
+        let breaker = breaker(&clock);
+        (0..3).for_each(|_| breaker.failure());
+        clock.advance(COOLDOWN);
+        assert!(breaker.allow());
+
+        breaker.failure();
+        assert!(!breaker.allow());
+        clock.advance(COOLDOWN);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn a_probe_that_never_reports_back_is_retried() {
+        let clock = ManualClock::default();
+        let breaker = breaker(&clock);
+        (0..3).for_each(|_| breaker.failure());
+        clock.advance(COOLDOWN);
+        assert!(breaker.allow());
+
+        clock.advance(COOLDOWN);
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+    }
+}
@@ -0,0 +1,207 @@
+//! Search through an Invidious or Piped instance, used when the youtube data api can't be.
+//!
+//! Both frontends return youtube video ids, so the embed urls are the same as with the data api.
+
+use std::collections::HashMap;
+
+use reqwest::Client;
+use serde::Deserialize;
+use spotify_music_vid::{Error, Result, Song};
+use tracing::{instrument, warn};
+
+use super::search::{merge_candidates, rank_by_duration, Candidate};
+use crate::db::config::{FallbackApi, FallbackConfig};
+
+#[derive(Debug, Clone)]
+pub struct FallbackClient {
+    client: Client,
+    api: FallbackApi,
+    instance_url: String,
+}
+
+/// A result of `GET /api/v1/search` on Invidious
+#[derive(Debug, Deserialize)]
+struct InvidiousResult {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    title: String,
+    #[serde(rename = "videoId")]
+    video_id: Option<String>,
+    #[serde(default)]
+    author: String,
+    #[serde(rename = "authorId")]
+    author_id: Option<String>,
+    #[serde(rename = "lengthSeconds")]
+    length_seconds: Option<i64>,
+}
+
+/// The response of `GET /search` on Piped
+#[derive(Debug, Deserialize)]
+struct PipedResponse {
+    items: Vec<PipedItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PipedItem {
+    /// `/watch?v=<video id>` for videos
+    url: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    title: String,
+    #[serde(rename = "uploaderName", default)]
+    uploader_name: Option<String>,
+    /// `/channel/<channel id>`
+    #[serde(rename = "uploaderUrl", default)]
+    uploader_url: Option<String>,
+    /// Seconds, `-1` for live streams
+    duration: Option<i64>,
+}
+
+/// A video found by either api
+struct FoundVideo {
+    video_id: String,
+    title: String,
+    channel_title: String,
+    channel_id: Option<String>,
+    duration: Option<i64>,
+}
+
+impl FallbackClient {
+    /// Creates a client for the configured instance.
+    pub fn new(client: Client, config: &FallbackConfig) -> Self {
+        Self {
+            client,
+            api: config.api,
+            instance_url: config.instance_url.trim().trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Returns the base url of the instance, which names it in logs and metrics.
+    pub fn instance_url(&self) -> &str {
+        &self.instance_url
+    }
+
+    /// Searches the instance with each query until a video matches both the title and an artist,
+    /// and returns every video found, ranked from best to worst match.
+    /// Invidious only returns videos playable in `region`, Piped uses the region of the instance.
+    /// # Errors
+    /// This function will return an error if the first search fails or if the response is not valid.
+    #[instrument(skip(self))]
+    pub async fn search(
+        &self,
+        song: &Song,
+        queries: &[String],
+        region: Option<&str>,
+    ) -> Result<Vec<Candidate>> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut durations = HashMap::new();
+        for (attempt, query) in queries.iter().enumerate() {
+            if candidates.first().map_or(false, |best| best.confident) {
+                break;
+            }
+            let found = match self.search_once(query, region).await {
+                Ok(found) => found,
+                Err(e) if attempt > 0 => {
+                    warn!("Search for {query:?} failed, keeping the previous results: {e}");
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            let count = i64::try_from(found.len()).unwrap_or(i64::MAX);
+            let scored = found
+                .into_iter()
+                .zip((1..=count).rev())
+                .map(|(video, relevance)| {
+                    if let Some(duration) = video.duration {
+                        durations.insert(video.video_id.clone(), duration);
+                    }
+                    Candidate::new(
+                        video.video_id,
+                        &video.title,
+                        &video.channel_title,
+                        video.channel_id,
+                        song,
+                        relevance,
+                    )
+                })
+                .collect();
+            merge_candidates(&mut candidates, scored);
+        }
+
+        if let Some(duration) = song.episode_duration() {
+            rank_by_duration(&mut candidates, &durations, duration);
+        }
+        Ok(candidates)
+    }
+
+    /// Runs a single search and returns the videos found, most relevant first.
+    /// # Errors
+    /// This function will return an error if the request fails or if the response is not valid.
+    async fn search_once(&self, query: &str, region: Option<&str>) -> Result<Vec<FoundVideo>> {
+        match self.api {
+            FallbackApi::Invidious => {
+                let mut params = vec![("q", query), ("type", "video")];
+                if let Some(region) = region {
+                    params.push(("region", region));
+                }
+                let results: Vec<InvidiousResult> = self.send_req("api/v1/search", &params).await?;
+                Ok(results
+                    .into_iter()
+                    .filter(|result| result.kind == "video")
+                    .filter_map(|result| {
+                        Some(FoundVideo {
+                            video_id: result.video_id?,
+                            title: result.title,
+                            channel_title: result.author,
+                            channel_id: result.author_id,
+                            duration: result.length_seconds,
+                        })
+                    })
+                    .collect())
+            }
+            FallbackApi::Piped => {
+                let params = [("q", query), ("filter", "videos")];
+                let res: PipedResponse = self.send_req("search", &params).await?;
+                Ok(res
+                    .items
+                    .into_iter()
+                    .filter(|item| item.kind == "stream")
+                    .filter_map(|item| {
+                        Some(FoundVideo {
+                            video_id: item.url.strip_prefix("/watch?v=")?.to_string(),
+                            title: item.title,
+                            channel_title: item.uploader_name.unwrap_or_default(),
+                            channel_id: item
+                                .uploader_url
+                                .as_deref()
+                                .and_then(|url| url.strip_prefix("/channel/"))
+                                .map(str::to_string),
+                            duration: item.duration.filter(|duration| *duration > 0),
+                        })
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Sends a request to an endpoint of the instance and parses the JSON response.
+    /// # Errors
+    /// This function will return an error if the request fails or if the response is not valid.
+    async fn send_req<T>(&self, endpoint: &str, params: &[(&str, &str)]) -> Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let res = self
+            .client
+            .get(format!("{}/{endpoint}", self.instance_url))
+            .query(params)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(Error::YoutubeStatus(res.status()));
+        }
+        Ok(res.json().await?)
+    }
+}
@@ -1,96 +1,539 @@
+mod breaker;
+mod fallback;
+mod quota;
+mod rules;
 mod search;
 
-use color_eyre::eyre::Result;
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
 use reqwest::{
-    header::{HeaderMap, ACCEPT},
-    Client, Response,
+    header::{HeaderMap, HeaderValue, ACCEPT},
+    Client, Response, StatusCode,
 };
-use spotify_music_vid::Song;
-use tracing::{error, info, instrument};
+use rspotify::model::Country;
+use spotify_music_vid::{
+    clock::SharedClock,
+    protocol::{VideoKind, VideoPreferences},
+    Error, ItemKind, Result, Song,
+};
+use tokio::time::timeout;
+use tracing::{debug, info, instrument, warn};
 
-use self::search::ListResponse;
+use self::breaker::CircuitBreaker;
+use self::fallback::FallbackClient;
+use self::quota::{is_quota_error, VIDEOS_COST};
+pub use self::quota::{Quota, SEARCH_COST};
+use self::rules::RuleSet;
+pub use self::search::Candidate;
+use self::search::{
+    apply_preferences, attach_chapters, best_candidate, durations, merge_candidates,
+    rank_by_duration, restrict_to_region, ListResponse, Video, VideoListResponse,
+};
+use crate::{
+    db::{
+        config::{Secret, YoutubeConfig},
+        rules::RuleRepository,
+        songs::SongRepository,
+    },
+    metrics::{self, YOUTUBE_HEALTH},
+};
+
+/// Searches tried for a single track at most, each one costs [`SEARCH_COST`] units
+const MAX_QUERIES: usize = 3;
+/// Videos the `videos` endpoint returns at most per request
+const MAX_VIDEO_IDS: usize = 50;
 
+/// Clones share the same http connection pool, [`Quota`], circuit breakers and rules.
 #[derive(Debug, Clone)]
 pub struct YoutubeClient {
     client: Client,
-    api_key: String,
+    api_key: Secret,
+    /// Root of the youtube data api, without a trailing slash
+    api_url: String,
+    quota: Arc<Quota>,
+    /// Units of the quota this client leaves to the others
+    quota_reserve: u64,
+    /// Sent as `regionCode`, videos blocked in this region are never returned
+    region: Option<&'static str>,
+    /// Sent as `relevanceLanguage`
+    language: Option<String>,
+    /// Searched in order until one finds a video, starting with the data api
+    providers: Vec<Provider>,
+    /// Time each provider may take to answer a search
+    timeout: Duration,
+    preferences: VideoPreferences,
+    /// The blocked and allowed channels and videos
+    rules: Arc<RwLock<RuleSet>>,
+}
+
+/// A source of videos searched by [`YoutubeClient::search`].
+#[derive(Debug, Clone)]
+struct Provider {
+    kind: ProviderKind,
+    breaker: Arc<CircuitBreaker>,
+}
+
+#[derive(Debug, Clone)]
+enum ProviderKind {
+    /// The youtube data api, spending the [`Quota`]
+    DataApi,
+    Fallback(FallbackClient),
+}
+
+impl Provider {
+    /// Returns the name of the provider in logs and metrics.
+    fn name(&self) -> &str {
+        match &self.kind {
+            ProviderKind::DataApi => "youtube",
+            ProviderKind::Fallback(fallback) => fallback.instance_url(),
+        }
+    }
+
+    /// Counts a search in `video_provider_searches_total` and updates `video_provider_open`.
+    fn record(&self, outcome: &str) {
+        metrics::PROVIDER_SEARCHES
+            .with_label_values(&[self.name(), outcome])
+            .inc();
+        metrics::PROVIDER_OPEN
+            .with_label_values(&[self.name()])
+            .set(i64::from(self.breaker.is_open()));
+    }
 }
 
 impl YoutubeClient {
-    /// Creates a new [`YoutubeClient`].
+    /// Creates a new [`YoutubeClient`] from the `youtube` section of the [`Config`](crate::db::config::Config).
+    pub fn new(config: &YoutubeConfig, clock: SharedClock) -> Self {
+        let language = Some(config.relevance_language.trim())
+            .filter(|language| !language.is_empty())
+            .map(str::to_string);
+        let client = reqwest::Client::new();
+        let provider = |kind| Provider {
+            kind,
+            breaker: Arc::new(CircuitBreaker::new(
+                config.breaker_threshold,
+                config.breaker_cooldown(),
+                clock.clone(),
+            )),
+        };
+        let mut providers = vec![provider(ProviderKind::DataApi)];
+        providers.extend(config.fallbacks.iter().map(|fallback| {
+            provider(ProviderKind::Fallback(FallbackClient::new(
+                client.clone(),
+                fallback,
+            )))
+        }));
+        Self {
+            client,
+            api_key: config.api_key.clone(),
+            api_url: config.api_url.trim().trim_end_matches('/').to_string(),
+            quota: Arc::new(Quota::new(config.daily_quota, clock.clone())),
+            quota_reserve: 0,
+            region: None,
+            language,
+            providers,
+            timeout: config.timeout(),
+            preferences: VideoPreferences::default(),
+            rules: Arc::default(),
+        }
+    }
+
+    /// Returns a client searching for videos playable in `country`, sharing the quota of this one.
+    pub fn with_region(&self, country: Option<Country>) -> Self {
+        Self {
+            region: country.map(Into::into),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a client that leaves `reserve` units of the shared quota to the other clients,
+    /// the data api is then skipped once only the reserve is left.
+    pub fn with_quota_reserve(&self, reserve: u64) -> Self {
+        Self {
+            quota_reserve: reserve,
+            ..self.clone()
+        }
+    }
+
+    /// Returns a client ranking and excluding videos by `preferences`, sharing the quota of this one.
+    pub fn with_preferences(&self, preferences: VideoPreferences) -> Self {
+        Self {
+            preferences,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the cache profile of the preferences, see [`VideoPreferences::profile`].
+    pub fn profile(&self) -> String {
+        self.preferences.profile()
+    }
+
+    /// Reads the blocked and allowed channels and videos, for this client and its clones.
+    /// Returns the number of rules.
     /// # Errors
-    /// This function will return an error if the environment variable `YOUTUBE_API_KEY` is not set.
-    pub fn new() -> Result<Self> {
-        Ok(Self {
-            client: reqwest::Client::new(),
-            api_key: get_env_var()?,
-        })
+    /// This function will return an error if the rules can not be read, the previous ones are kept.
+    pub async fn reload_rules(&self, repo: &RuleRepository) -> Result<usize> {
+        let rules = repo.list().await?;
+        let count = rules.len();
+        *self.rules.write().unwrap_or_else(PoisonError::into_inner) = RuleSet::new(rules);
+        Ok(count)
     }
 
-    /// Gets the video url for a song given [`Song`].
-    /// This function will search for the song on youtube and return the first result.
+    /// Returns whether a video, or the channel it was uploaded by, is blocked.
+    pub fn is_blocked(&self, video_id: &str, channel_id: Option<&str>) -> bool {
+        self.rules
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .blocks(video_id, channel_id)
+    }
+
+    /// Looks up and stores the channel of the cached videos whose channel is unknown,
+    /// so purging a blocked channel also removes them. Returns the number of songs updated.
     /// # Errors
-    /// This function will return an error if the request fails or if the response is not valid.
+    /// This function will return an error if the cached videos can not be read or updated,
+    /// or if the youtube data api can not be queried.
+    #[instrument(skip_all)]
+    pub async fn resolve_channels(&self, songs: &SongRepository) -> Result<u64> {
+        let video_ids = songs.videos_without_channel().await?;
+        let mut updated = 0;
+        for batch in video_ids.chunks(MAX_VIDEO_IDS) {
+            let ids = batch.join(",");
+            let params = [("part", "contentDetails,snippet"), ("id", ids.as_str())];
+            let res: VideoListResponse = self
+                .send_req("videos", &params, VIDEOS_COST)
+                .await?
+                .json()
+                .await?;
+            for video in res.items {
+                if let Some(channel_id) = video.snippet.and_then(|snippet| snippet.channel_id) {
+                    updated += songs.set_channel(&video.id, &channel_id).await?;
+                }
+            }
+        }
+        info!(updated, "Resolved the channels of cached videos");
+        Ok(updated)
+    }
+
+    /// Returns the region a resolved video should be cached for,
+    /// `None` when it is not region restricted and can be cached for every region.
+    pub fn cache_region(&self, candidate: &Candidate) -> Option<&'static str> {
+        self.region.filter(|_| candidate.region_restricted)
+    }
+
+    /// Returns the region whose cached videos can be played by this client.
+    pub const fn region(&self) -> Option<&'static str> {
+        self.region
+    }
+
+    /// Gets the video url for a song given [`Song`], and the video it points to.
+    /// This function will search for the song on youtube and return the best ranked result.
+    /// # Errors
+    /// This function will return [`Error::NoVideo`] if no provider found a video,
+    /// or the error of the last provider that failed.
     #[instrument(skip(self))]
-    pub async fn get_song_vid(&self, song: &Song) -> Result<(String, String)> {
-        let query = format!("{} {} music video", song.artist, song.name);
-        let res = self.send_req(&query).await?;
-        let res = self.parse_res(res, song).await?;
-        Ok(res)
+    pub async fn get_song_vid(&self, song: &Song) -> Result<(String, Candidate)> {
+        let candidates = self.search(song).await?;
+        let best = best_candidate(candidates).ok_or(Error::NoVideo)?;
+        let url = Song::get_url_with_duration(&best.video_id, &best.start(song).to_string());
+        Ok((url, best))
+    }
+
+    /// Searches the providers in order for a song and returns every video found by the first one
+    /// that finds any, ranked from best to worst match.
+    /// Videos of an excluded kind or a blocked channel are left out,
+    /// and the preferred kind and allowed channels are ranked first.
+    /// Each search is limited to `youtube.timeout_ms`, and a provider failing repeatedly
+    /// is skipped until its circuit breaker lets a probe through again.
+    /// The data api is skipped while the quota is used up.
+    /// # Errors
+    /// This function will return the error of the last provider that failed
+    /// if none of them could be searched.
+    #[instrument(skip(self))]
+    pub async fn search(&self, song: &Song) -> Result<Vec<Candidate>> {
+        let mut answered = false;
+        // whether a provider failed or was skipped for failing, the quota is not a failure
+        let mut failed = false;
+        let mut last_error = None;
+        for provider in &self.providers {
+            let name = provider.name();
+            if !provider.breaker.allow() {
+                debug!("Skipping video provider {name}, its circuit breaker is open");
+                provider.record("skipped");
+                failed = true;
+                last_error = Some(Error::ProviderUnavailable(name.to_string()));
+                continue;
+            }
+            let res = timeout(self.timeout, self.search_provider(provider, song))
+                .await
+                .unwrap_or_else(|_| Err(Error::Timeout(name.to_string())));
+            match res {
+                Ok(mut candidates) => {
+                    for candidate in &mut candidates {
+                        candidate.provider = name.to_string();
+                    }
+                    apply_preferences(&mut candidates, &self.preferences);
+                    self.rules
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .apply(&mut candidates);
+                    provider.breaker.success();
+                    provider.record("ok");
+                    YOUTUBE_HEALTH.success();
+                    if !candidates.is_empty() {
+                        return Ok(candidates);
+                    }
+                    answered = true;
+                    info!("No video found on {name}, trying the next provider");
+                }
+                // the quota is tracked on its own and says nothing about the health of youtube
+                Err(Error::QuotaExceeded) => {
+                    provider.record("quota_exceeded");
+                    last_error = Some(Error::QuotaExceeded);
+                }
+                Err(e) => {
+                    warn!("Search on {name} failed, trying the next provider: {e}");
+                    provider.breaker.failure();
+                    let outcome = match e {
+                        Error::Timeout(_) => "timeout",
+                        _ => "error",
+                    };
+                    provider.record(outcome);
+                    failed = true;
+                    last_error = Some(e);
+                }
+            }
+        }
+        if !answered && failed {
+            YOUTUBE_HEALTH.failure();
+        }
+        match last_error {
+            Some(e) if !answered => Err(e),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Searches a single provider for a song.
+    /// # Errors
+    /// This function will return an error if the search fails,
+    /// or [`Error::QuotaExceeded`] for the data api once the quota is used up.
+    async fn search_provider(&self, provider: &Provider, song: &Song) -> Result<Vec<Candidate>> {
+        match &provider.kind {
+            ProviderKind::DataApi if self.quota.remaining() < SEARCH_COST + self.quota_reserve => {
+                Err(Error::QuotaExceeded)
+            }
+            ProviderKind::DataApi => self.search_api(song).await,
+            ProviderKind::Fallback(fallback) => {
+                fallback
+                    .search(song, &self.search_queries(song), self.region)
+                    .await
+            }
+        }
+    }
+
+    /// Searches the youtube data api for a song.
+    /// The queries of [`YoutubeClient::search_queries`] are tried in order until a video matches both the title
+    /// and an artist, so most songs only cost a single search.
+    /// When a region is set, videos that can't be played there are left out.
+    /// Podcast episodes are also ranked by how close the duration of each video is to the episode's.
+    /// # Errors
+    /// This function will return an error if the first search fails or if the response is not valid.
+    async fn search_api(&self, song: &Song) -> Result<Vec<Candidate>> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for (attempt, query) in self.search_queries(song).iter().enumerate() {
+            if candidates.first().map_or(false, |best| best.confident) {
+                break;
+            }
+            match self.search_once(song, query).await {
+                Ok(found) => merge_candidates(&mut candidates, found),
+                Err(e) if attempt > 0 => {
+                    warn!("Search for {query:?} failed, keeping the previous results: {e}");
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if candidates.is_empty() || (self.region.is_none() && song.episode_duration().is_none()) {
+            return Ok(candidates);
+        }
+        let videos = match self.video_details(&candidates, song).await {
+            Ok(videos) => videos,
+            Err(e) => {
+                warn!("Failed to get the video details, keeping the search ranking: {e}");
+                return Ok(candidates);
+            }
+        };
+        if let Some(region) = self.region {
+            restrict_to_region(&mut candidates, &videos, region);
+        }
+        if let Some(duration) = song.episode_duration() {
+            rank_by_duration(&mut candidates, &durations(&videos), duration);
+            attach_chapters(&mut candidates, &videos);
+        }
+        Ok(candidates)
     }
 
-    /// Sends the request to youtube.
+    /// Runs a single search and scores the results against the song.
     /// # Errors
-    /// This function will return an error if the request fails.
-    async fn send_req(&self, query: &str) -> Result<Response> {
-        let headers = get_headers()?;
+    /// This function will return an error if the request fails or if the response is not valid.
+    async fn search_once(&self, song: &Song, query: &str) -> Result<Vec<Candidate>> {
+        let mut params = vec![("part", "snippet"), ("q", query)];
+        if let Some(region) = self.region {
+            params.push(("regionCode", region));
+        }
+        if let Some(language) = &self.language {
+            params.push(("relevanceLanguage", language));
+        }
+        if let Some(duration) = song.episode_duration() {
+            params.push(("type", "video"));
+            params.push(("videoDuration", duration_filter(duration)));
+        }
+        let res: ListResponse = self
+            .send_req("search", &params, SEARCH_COST)
+            .await?
+            .json()
+            .await?;
+        Ok(res.candidates(song))
+    }
 
+    /// Returns the duration and region restrictions of every candidate, by video id,
+    /// and their description when `song` is a podcast episode.
+    /// # Errors
+    /// This function will return an error if the request fails or if the response is not valid.
+    async fn video_details(
+        &self,
+        candidates: &[Candidate],
+        song: &Song,
+    ) -> Result<HashMap<String, Video>> {
+        let ids = candidates
+            .iter()
+            .map(|candidate| candidate.video_id.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        // the parts don't change the cost of the request
+        let parts = match song.kind {
+            ItemKind::Track => "contentDetails",
+            ItemKind::Episode { .. } => "contentDetails,snippet",
+        };
+        let params = [("part", parts), ("id", ids.as_str())];
+        let res: VideoListResponse = self
+            .send_req("videos", &params, VIDEOS_COST)
+            .await?
+            .json()
+            .await?;
+        Ok(res.by_id())
+    }
+
+    /// Sends a request to an endpoint of the youtube data api, spending `cost` units of the quota.
+    /// # Errors
+    /// This function will return an error if the request fails, or [`Error::QuotaExceeded`]
+    /// if the quota left above the reserve is too low or youtube rejected it for exceeding the quota.
+    #[instrument(skip(self))]
+    async fn send_req(
+        &self,
+        endpoint: &str,
+        params: &[(&str, &str)],
+        cost: u64,
+    ) -> Result<Response> {
+        let headers = get_headers();
+
+        if !self.quota.try_spend(cost, self.quota_reserve) {
+            return Err(Error::QuotaExceeded);
+        }
+        self.record_quota();
         let res = self
             .client
-            .get("https://youtube.googleapis.com/youtube/v3/search")
+            .get(format!("{}/{endpoint}", self.api_url))
             .headers(headers)
-            .query(&[
-                ("part", "snippet"),
-                ("q", query),
-                ("key", self.api_key.as_str()),
-            ])
+            .query(params)
+            .query(&[("key", self.api_key.expose())])
             .send()
             .await;
 
-        Ok(res?)
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                record_request("error");
+                return Err(e.into());
+            }
+        };
+        let status = res.status();
+        if status == StatusCode::FORBIDDEN {
+            let body = res.text().await.unwrap_or_default();
+            if is_quota_error(&body) {
+                warn!("Youtube rejected the request, the quota is exceeded");
+                self.quota.exhaust();
+                self.record_quota();
+                record_request("quota_exceeded");
+                return Err(Error::QuotaExceeded);
+            }
+            warn!("Youtube refused the request: {body}");
+            record_request("error");
+            return Err(Error::YoutubeStatus(status));
+        }
+        if !status.is_success() {
+            record_request("error");
+            return Err(Error::YoutubeStatus(res.status()));
+        }
+        record_request("ok");
+        Ok(res)
     }
 
-    /// Parses the response from youtube.
-    /// # Errors
-    /// This function will return an error if the response is not valid.
-    async fn parse_res(&self, res: Response, song: &Song) -> Result<(String, String)> {
-        let res: ListResponse = res.json().await?;
-        Ok(res.get_vid_url(song))
+    /// Returns the queries to try for a song, from the most to the least specific.
+    /// Tracks are searched with their cleaned title, then with every artist for collaborations,
+    /// then with the title as listed on Spotify, which matters for remixes and live versions.
+    /// The first queries ask for the preferred kind of video.
+    fn search_queries(&self, song: &Song) -> Vec<String> {
+        if song.kind != ItemKind::Track {
+            return vec![format!("{} {}", song.artist, song.name)];
+        }
+        let title = song.clean_name();
+        let kind = match self.preferences.prefer {
+            None | Some(VideoKind::MusicVideo) => "music video",
+            Some(VideoKind::Lyrics) => "lyrics",
+            Some(VideoKind::Audio) => "audio",
+        };
+        let mut queries = vec![format!("{} {title} {kind}", song.artist)];
+        if song.artists.len() > 1 {
+            queries.push(format!("{} {title} {kind}", song.artists.join(" ")));
+        }
+        if title != song.name {
+            queries.push(format!("{} {}", song.artist, song.name));
+        }
+        queries.push(format!("{} {title}", song.artist));
+        queries.truncate(MAX_QUERIES);
+        queries
     }
-}
 
-impl Default for YoutubeClient {
-    fn default() -> Self {
-        match Self::new() {
-            Ok(client) => client,
-            Err(e) => {
-                error!("Failed to create YoutubeClient: {e}");
-                panic!("Failed to create YoutubeClient: {e}");
-            }
-        }
+    fn record_quota(&self) {
+        let remaining = i64::try_from(self.quota.remaining()).unwrap_or(i64::MAX);
+        metrics::YOUTUBE_QUOTA_REMAINING.set(remaining);
     }
 }
 
-fn get_env_var() -> Result<String> {
-    info!("Loading environment variables");
-    // dotenv::dotenv()?;
-    let api_key = std::env::var("YOUTUBE_API_KEY")?;
-    Ok(api_key)
+/// Counts a request in `youtube_requests_total`.
+/// The health used by `/readyz` is updated by [`YoutubeClient::search`], from the whole provider chain.
+fn record_request(outcome: &str) {
+    metrics::YOUTUBE_REQUESTS
+        .with_label_values(&[outcome])
+        .inc();
+}
+
+/// Returns the `videoDuration` search filter matching an episode of `duration` seconds.
+/// Episodes close to the limits of a filter are not filtered, uploads may be a few minutes off.
+const fn duration_filter(duration: i64) -> &'static str {
+    match duration {
+        d if d >= 25 * 60 => "long",
+        d if d >= 5 * 60 && d <= 18 * 60 => "medium",
+        _ => "any",
+    }
 }
 
-fn get_headers() -> Result<HeaderMap> {
+fn get_headers() -> HeaderMap {
     let mut headers = HeaderMap::new();
-    let json = "application/json".parse()?;
-    headers.insert(ACCEPT, json);
-    Ok(headers)
+    headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+    headers
 }
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use spotify_music_vid::clock::SharedClock;
+
+/// Units charged by the youtube data api for a `search.list` call
+pub const SEARCH_COST: u64 = 100;
+/// Units charged by the youtube data api for a `videos.list` call
+pub const VIDEOS_COST: u64 = 1;
+
+/// Reasons given by youtube for refusing a request because the daily quota is used up,
+/// other 403 errors such as an invalid key or a disabled api say nothing about the quota
+const QUOTA_REASONS: [&str; 2] = ["quotaExceeded", "dailyLimitExceeded"];
+
+/// The quota resets at midnight Pacific Time, daylight saving is ignored.
+const RESET_OFFSET_SECS: u64 = 8 * 60 * 60;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Tracks the youtube data api units spent today.
+/// A single instance is shared by every clone of a [`YoutubeClient`](super::YoutubeClient),
+/// the usage is only known to the process and starts at 0 after a restart or in another process.
+#[derive(Debug)]
+pub struct Quota {
+    daily_limit: u64,
+    used: AtomicU64,
+    day: AtomicU64,
+    /// Tells when a new quota day starts
+    clock: SharedClock,
+}
+
+impl Quota {
+    pub fn new(daily_limit: u64, clock: SharedClock) -> Self {
+        Self {
+            daily_limit,
+            used: AtomicU64::new(0),
+            day: AtomicU64::new(0),
+            clock,
+        }
+    }
+
+    /// Returns the number of units left for today.
+    pub fn remaining(&self) -> u64 {
+        self.roll_over();
+        self.daily_limit
+            .saturating_sub(self.used.load(Ordering::Relaxed))
+    }
+
+    /// Spends `units` if more than `reserve` units are left afterwards, returning whether they were spent.
+    /// The check and the spending are a single step, so concurrent requests can't go past the reserve.
+    pub fn try_spend(&self, units: u64, reserve: u64) -> bool {
+        self.roll_over();
+        let limit = self.daily_limit.saturating_sub(reserve);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used + units).filter(|&used| used <= limit)
+            })
+            .is_ok()
+    }
+
+    /// Marks the quota as used up, after youtube rejected a request for exceeding it.
+    pub fn exhaust(&self) {
+        self.roll_over();
+        self.used.store(self.daily_limit, Ordering::Relaxed);
+    }
+
+    /// Resets the counter when a new quota day has started.
+    fn roll_over(&self) {
+        let today = self.current_day();
+        if self.day.swap(today, Ordering::Relaxed) != today {
+            self.used.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of quota days since the Unix epoch.
+    fn current_day(&self) -> u64 {
+        let now = u64::try_from(self.clock.utc().timestamp()).unwrap_or_default();
+        now.saturating_sub(RESET_OFFSET_SECS) / SECS_PER_DAY
+    }
+}
+
+/// Returns whether an error response of the data api refuses the request for exceeding the quota.
+pub fn is_quota_error(body: &str) -> bool {
+    let Ok(body) = serde_json::from_str::<serde_json::Value>(body) else {
+        return false;
+    };
+    body.pointer("/error/errors")
+        .and_then(serde_json::Value::as_array)
+        .map_or(false, |errors| {
+            errors.iter().any(|error| {
+                error["reason"]
+                    .as_str()
+                    .map_or(false, |reason| QUOTA_REASONS.contains(&reason))
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use spotify_music_vid::clock::ManualClock;
+
+    use super::*;
+
+    #[test]
+    fn spending_stops_at_the_reserve() {
+        let quota = Quota::new(300, ManualClock::default().shared());
+        assert!(quota.try_spend(SEARCH_COST, 100));
+        assert!(quota.try_spend(SEARCH_COST, 100));
+        assert!(!quota.try_spend(SEARCH_COST, 100));
+        assert_eq!(quota.remaining(), 100);
+        assert!(quota.try_spend(SEARCH_COST, 0));
+        assert!(!quota.try_spend(VIDEOS_COST, 0));
+    }
+
+    #[test]
+    fn the_quota_resets_at_midnight_pacific_time() {
+        let clock = ManualClock::default();
+        // 07:59 UTC on the second day, still the first quota day in the Pacific
+        clock.set(Duration::from_secs(SECS_PER_DAY + RESET_OFFSET_SECS - 60));
+        let quota = Quota::new(300, clock.shared());
+        quota.exhaust();
+        assert_eq!(quota.remaining(), 0);
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(quota.remaining(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(quota.remaining(), 300);
+    }
+
+    #[test]
+    fn only_quota_reasons_are_quota_errors() {
+        let error = |reason: &str| {
+            serde_json::json!({
+                "error": { "code": 403, "message": "", "errors": [{ "reason": reason }] }
+            })
+            .to_string()
+        };
+        assert!(is_quota_error(&error("quotaExceeded")));
+        assert!(is_quota_error(&error("dailyLimitExceeded")));
+        assert!(!is_quota_error(&error("keyInvalid")));
+        assert!(!is_quota_error(&error("accessNotConfigured")));
+        assert!(!is_quota_error("forbidden"));
+    }
+}
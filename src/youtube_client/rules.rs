@@ -0,0 +1,156 @@
+use std::collections::HashSet;
+
+use super::search::Candidate;
+use crate::db::rules::{RuleAction, RuleKind, VideoRule};
+
+/// Score added to the channels and videos allowed by an admin, enough to outrank a more
+/// relevant video. It stays below the bonus of the kind a listener prefers, so between two
+/// otherwise equal videos the listener's choice wins over the admin's.
+const ALLOWED_BONUS: i64 = 4;
+
+/// The [`VideoRule`]s applied to search results, indexed by target.
+#[derive(Debug, Default)]
+pub struct RuleSet {
+    blocked_channels: HashSet<String>,
+    blocked_videos: HashSet<String>,
+    allowed_channels: HashSet<String>,
+    allowed_videos: HashSet<String>,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<VideoRule>) -> Self {
+        let mut set = Self::default();
+        for rule in rules {
+            let targets = match (rule.action, rule.kind) {
+                (RuleAction::Block, RuleKind::Channel) => &mut set.blocked_channels,
+                (RuleAction::Block, RuleKind::Video) => &mut set.blocked_videos,
+                (RuleAction::Allow, RuleKind::Channel) => &mut set.allowed_channels,
+                (RuleAction::Allow, RuleKind::Video) => &mut set.allowed_videos,
+            };
+            targets.insert(rule.target);
+        }
+        set
+    }
+
+    /// Removes the blocked videos and ranks the allowed ones first.
+    pub fn apply(&self, candidates: &mut Vec<Candidate>) {
+        candidates
+            .retain(|candidate| !self.blocks(&candidate.video_id, candidate.channel_id.as_deref()));
+        let mut reranked = false;
+        for candidate in candidates.iter_mut().filter(|c| self.allows(c)) {
+            candidate.score += ALLOWED_BONUS;
+            reranked = true;
+        }
+        if reranked {
+            candidates.sort_by(|a, b| b.score.cmp(&a.score));
+        }
+    }
+
+    /// Returns whether a video, or the channel it was uploaded by, is blocked.
+    pub fn blocks(&self, video_id: &str, channel_id: Option<&str>) -> bool {
+        self.blocked_videos.contains(video_id)
+            || channel_id.map_or(false, |id| self.blocked_channels.contains(id))
+    }
+
+    fn allows(&self, candidate: &Candidate) -> bool {
+        self.allowed_videos.contains(&candidate.video_id)
+            || candidate
+                .channel_id
+                .as_ref()
+                .map_or(false, |id| self.allowed_channels.contains(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use spotify_music_vid::Song;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn rule(kind: RuleKind, target: &str, action: RuleAction) -> VideoRule {
+        VideoRule {
+            id: Uuid::new_v4(),
+            kind,
+            target: target.to_string(),
+            action,
+            note: None,
+        }
+    }
+
+    fn candidate(video_id: &str, channel_id: Option<&str>, score: i64) -> Candidate {
+        let song = Song::new("Around the World".to_string(), "Daft Punk".to_string(), 0);
+        let mut candidate = Candidate::new(
+            video_id.to_string(),
+            "An upload",
+            "Someone",
+            channel_id.map(str::to_string),
+            &song,
+            0,
+        );
+        candidate.score = score;
+        candidate
+    }
+
+    fn ranked(candidates: &[Candidate]) -> Vec<(&str, i64)> {
+        candidates
+            .iter()
+            .map(|candidate| (candidate.video_id.as_str(), candidate.score))
+            .collect()
+    }
+
+    #[test]
+    fn removes_blocked_videos_and_the_videos_of_blocked_channels() {
+        let rules = RuleSet::new(vec![
+            rule(RuleKind::Video, "blocked-video", RuleAction::Block),
+            rule(RuleKind::Channel, "UCblocked", RuleAction::Block),
+        ]);
+        let mut candidates = vec![
+            candidate("blocked-video", Some("UCfine"), 10),
+            candidate("reupload", Some("UCblocked"), 9),
+            candidate("unknown-channel", None, 8),
+            candidate("fine", Some("UCfine"), 7),
+        ];
+        rules.apply(&mut candidates);
+        assert_eq!(ranked(&candidates), [("unknown-channel", 8), ("fine", 7)]);
+    }
+
+    #[test]
+    fn ranks_allowed_videos_and_channels_first() {
+        let rules = RuleSet::new(vec![
+            rule(RuleKind::Video, "allowed-video", RuleAction::Allow),
+            rule(RuleKind::Channel, "UCofficial", RuleAction::Allow),
+        ]);
+        let mut candidates = vec![
+            candidate("relevant", Some("UCother"), 10),
+            candidate("allowed-video", Some("UCother"), 8),
+            candidate("official", Some("UCofficial"), 7),
+        ];
+        rules.apply(&mut candidates);
+        assert_eq!(
+            ranked(&candidates),
+            [("allowed-video", 12), ("official", 11), ("relevant", 10)]
+        );
+    }
+
+    #[test]
+    fn keeps_the_ranking_without_rules() {
+        let mut candidates = vec![
+            candidate("first", Some("UCone"), 3),
+            candidate("second", None, 5),
+        ];
+        RuleSet::default().apply(&mut candidates);
+        assert_eq!(ranked(&candidates), [("first", 3), ("second", 5)]);
+    }
+
+    #[test]
+    fn blocks_by_video_or_channel() {
+        let rules = RuleSet::new(vec![
+            rule(RuleKind::Channel, "UCblocked", RuleAction::Block),
+            rule(RuleKind::Channel, "UCallowed", RuleAction::Allow),
+        ]);
+        assert!(rules.blocks("any", Some("UCblocked")));
+        assert!(!rules.blocks("any", Some("UCallowed")));
+        assert!(!rules.blocks("any", None));
+    }
+}
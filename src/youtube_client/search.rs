@@ -1,5 +1,12 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
-use spotify_music_vid::Song;
+use spotify_music_vid::{
+    chapters::{self, Chapter},
+    normalize::{fold, match_forms, title_forms},
+    protocol::{VideoKind, VideoPreferences},
+    ItemKind, Song,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListResponse {
@@ -69,25 +76,484 @@ pub struct PageInfo {
     pub(crate) results_per_page: i64,
 }
 
+/// The response of `videos.list`, only the parts requested for ranking are read.
+#[derive(Debug, Deserialize)]
+pub struct VideoListResponse {
+    pub(crate) items: Vec<Video>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Video {
+    pub(crate) id: String,
+    #[serde(rename = "contentDetails")]
+    pub(crate) content_details: ContentDetails,
+    /// Only requested for podcast episodes and to find the channel of cached videos
+    pub(crate) snippet: Option<VideoSnippet>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VideoSnippet {
+    /// The full description, search results only have the start of it
+    pub(crate) description: String,
+    #[serde(rename = "channelId")]
+    pub(crate) channel_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ContentDetails {
+    /// ISO 8601 duration such as `PT1H2M3S`
+    pub(crate) duration: String,
+    #[serde(rename = "regionRestriction")]
+    pub(crate) region_restriction: Option<RegionRestriction>,
+}
+
+/// Regions as ISO 3166-1 alpha-2 codes, youtube sets either list.
+#[derive(Debug, Deserialize)]
+pub struct RegionRestriction {
+    /// The video can only be played in these regions
+    pub(crate) allowed: Option<Vec<String>>,
+    /// The video can be played everywhere but in these regions
+    pub(crate) blocked: Option<Vec<String>>,
+}
+
+/// A video returned by a search, scored against the [`Song`] that was looked up.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub video_id: String,
+    pub title: String,
+    pub channel_title: String,
+    /// Unknown for some fallback instances
+    pub channel_id: Option<String>,
+    pub score: i64,
+    /// Whether the video title contains the song title and one of its artists
+    pub confident: bool,
+    /// Whether youtube only allows the video in some regions
+    pub region_restricted: bool,
+    pub kind: VideoKind,
+    /// The provider that found the video, `youtube` or the url of a fallback instance
+    pub provider: String,
+    /// Chapters listed in the description of the video, only read for podcast episodes
+    pub chapters: Vec<Chapter>,
+}
+
+/// Words in a video title that usually mean it is not the original recording
+const PENALISED_WORDS: [&str; 5] = ["cover", "reaction", "live", "karaoke", "remix"];
+
+impl Candidate {
+    /// Scores a search result of the youtube data api for a song.
+    /// `relevance` is the rank youtube gave the result, higher being more relevant.
+    fn from_item(item: &Item, song: &Song, relevance: i64) -> Option<Self> {
+        let video_id = item.id.video_id.clone()?;
+        Some(Self::new(
+            video_id,
+            &item.snippet.title,
+            &item.snippet.channel_title,
+            Some(item.snippet.channel_id.clone()),
+            song,
+            relevance,
+        ))
+    }
+
+    /// Scores a video with the given title, uploaded by `channel_title`, for a song.
+    /// `relevance` is the rank the search gave the video, higher being more relevant.
+    pub fn new(
+        video_id: String,
+        video_title: &str,
+        channel_title: &str,
+        channel_id: Option<String>,
+        song: &Song,
+        relevance: i64,
+    ) -> Self {
+        // titles are compared folded and transliterated, so "Beyoncé" matches "Beyonce"
+        // and "夜に駆ける (Yoru ni Kakeru)" matches either name
+        let title = fold(video_title);
+        let titles = title_forms(video_title);
+        let channels = title_forms(channel_title);
+        let original_name = fold(&song.name);
+
+        let mut score = relevance;
+        let title_matches = contains_any(&titles, &match_forms(&song.clean_name()));
+        if title_matches {
+            score += 3;
+        }
+        let credited = song
+            .artists
+            .iter()
+            .map(|artist| match_forms(artist))
+            .filter(|forms| contains_any(&titles, forms) || contains_any(&channels, forms))
+            .count();
+        if credited > 0 {
+            // every featured artist found is a hint that this is the collaboration
+            score += 2 + i64::try_from(credited - 1).unwrap_or_default();
+        }
+        let confident = title_matches && credited > 0;
+        // podcast uploads are neither "official" videos nor covers
+        let mut candidate = Self {
+            video_id,
+            title: video_title.to_string(),
+            channel_title: channel_title.to_string(),
+            channel_id,
+            score,
+            confident,
+            region_restricted: false,
+            kind: classify(&title, channel_title),
+            provider: String::new(),
+            chapters: Vec::new(),
+        };
+        if song.kind != ItemKind::Track {
+            return candidate;
+        }
+        if title.contains("official") {
+            candidate.score += 2;
+        }
+        for word in PENALISED_WORDS {
+            if title.contains(word) && !original_name.contains(word) {
+                candidate.score -= 3;
+            }
+        }
+        candidate
+    }
+
+    /// Returns where to start the video to be at the position of `song`,
+    /// going through the chapter playing when the video lists it, or at the same time otherwise.
+    #[must_use]
+    pub fn start(&self, song: &Song) -> i64 {
+        chapters::position(&song.chapters, &self.chapters, song.progress).unwrap_or(song.progress)
+    }
+}
+
+/// Returns the kind of a video from its folded title and its channel.
+fn classify(title: &str, channel_title: &str) -> VideoKind {
+    // youtube music publishes the audio of every release on "<Artist> - Topic" channels
+    if channel_title.ends_with(" - Topic") || title.contains("official audio") {
+        VideoKind::Audio
+    } else if title.contains("lyric") || title.contains("letra") {
+        VideoKind::Lyrics
+    } else {
+        VideoKind::MusicVideo
+    }
+}
+
+/// Score added to the videos of the kind a listener prefers, enough to outrank a more
+/// relevant video of another kind.
+const PREFERRED_BONUS: i64 = 5;
+
+/// Removes the videos of an excluded kind and ranks the preferred kind first.
+pub fn apply_preferences(candidates: &mut Vec<Candidate>, preferences: &VideoPreferences) {
+    candidates.retain(|candidate| !preferences.exclude.contains(&candidate.kind));
+    if let Some(prefer) = preferences.prefer {
+        for candidate in candidates.iter_mut().filter(|c| c.kind == prefer) {
+            candidate.score += PREFERRED_BONUS;
+        }
+        candidates.sort_by(|a, b| b.score.cmp(&a.score));
+    }
+}
+
+/// Returns whether one of `haystacks` contains one of `needles`.
+fn contains_any(haystacks: &[String], needles: &[String]) -> bool {
+    needles
+        .iter()
+        .any(|needle| haystacks.iter().any(|haystack| haystack.contains(needle)))
+}
+
 impl ListResponse {
-    /// Returns the video id of the first item in the response
-    /// If no video id is found, it returns a default video id
-    fn get_video_id(&self) -> String {
-        if let Some(video_id) = &self.items[0].id.video_id {
-            return video_id.clone();
+    /// Returns the videos in the response ranked from best to worst match for the song.
+    /// Results that are not videos (channels, playlists) are skipped.
+    pub fn candidates(&self, song: &Song) -> Vec<Candidate> {
+        let count = i64::try_from(self.items.len()).unwrap_or(i64::MAX);
+        let mut candidates: Vec<Candidate> = self
+            .items
+            .iter()
+            .zip((1..=count).rev())
+            .filter_map(|(item, relevance)| Candidate::from_item(item, song, relevance))
+            .collect();
+        // stable sort keeps youtube's order between equally scored results
+        candidates.sort_by(|a, b| b.score.cmp(&a.score));
+        candidates
+    }
+}
+
+impl VideoListResponse {
+    /// Returns the videos by id.
+    pub fn by_id(self) -> HashMap<String, Video> {
+        self.items
+            .into_iter()
+            .map(|video| (video.id.clone(), video))
+            .collect()
+    }
+}
+
+/// Returns the duration in seconds of every video whose duration is known, by video id.
+pub fn durations(videos: &HashMap<String, Video>) -> HashMap<String, i64> {
+    videos
+        .iter()
+        .filter_map(|(id, video)| Some((id.clone(), video.duration()?)))
+        .collect()
+}
+
+impl Video {
+    /// Returns the duration of the video in seconds.
+    pub fn duration(&self) -> Option<i64> {
+        parse_duration(&self.content_details.duration)
+    }
+
+    /// Returns whether the video can be played in `region`.
+    pub fn playable_in(&self, region: &str) -> bool {
+        match &self.content_details.region_restriction {
+            Some(RegionRestriction {
+                allowed: Some(allowed),
+                ..
+            }) => allowed.iter().any(|allowed| allowed == region),
+            Some(RegionRestriction {
+                blocked: Some(blocked),
+                ..
+            }) => !blocked.iter().any(|blocked| blocked == region),
+            _ => true,
         }
-        tracing::error!("No video id found");
-        "CJtvnepMVAU".to_string()
     }
+}
 
-    /// Returns the url of the video and the video id
-    /// The url is used to embed the video
-    pub fn get_vid_url(&self, song: &Song) -> (String, String) {
-        let video_id = self.get_video_id();
-        let duration = song.progress;
-        (
-            format!("https://www.youtube.com/embed/{video_id}?start={duration}&autoplay=1&enablejsapi=1"),
-            video_id,
-        )
+/// Adds the results of another search, a video found by both keeps its best score.
+pub fn merge_candidates(candidates: &mut Vec<Candidate>, found: Vec<Candidate>) {
+    for candidate in found {
+        match candidates
+            .iter_mut()
+            .find(|known| known.video_id == candidate.video_id)
+        {
+            Some(known) if known.score < candidate.score => *known = candidate,
+            Some(_) => (),
+            None => candidates.push(candidate),
+        }
+    }
+    candidates.sort_by(|a, b| b.score.cmp(&a.score));
+}
+
+/// Removes the videos that can't be played in `region` and flags the region restricted ones.
+pub fn restrict_to_region(
+    candidates: &mut Vec<Candidate>,
+    videos: &HashMap<String, Video>,
+    region: &str,
+) {
+    candidates.retain_mut(|candidate| match videos.get(&candidate.video_id) {
+        Some(video) => {
+            candidate.region_restricted = video.content_details.region_restriction.is_some();
+            video.playable_in(region)
+        }
+        None => true,
+    });
+}
+
+/// Favours the videos lasting about as long as the episode and demotes clips and trailers,
+/// full uploads often add or cut a few minutes of ads.
+/// `durations` are in seconds by video id.
+pub fn rank_by_duration(
+    candidates: &mut [Candidate],
+    durations: &HashMap<String, i64>,
+    expected: i64,
+) {
+    let tolerance = (expected / 10).max(2 * 60);
+    for candidate in candidates.iter_mut() {
+        let Some(&duration) = durations.get(&candidate.video_id) else {
+            continue;
+        };
+        let difference = (duration - expected).abs();
+        if difference <= tolerance {
+            candidate.score += 6;
+        } else if difference > expected / 2 {
+            candidate.score -= 4;
+        }
+    }
+    candidates.sort_by(|a, b| b.score.cmp(&a.score));
+}
+
+/// Reads the chapters of every candidate from the description of its video.
+pub fn attach_chapters(candidates: &mut [Candidate], videos: &HashMap<String, Video>) {
+    for candidate in candidates.iter_mut() {
+        if let Some(snippet) = videos
+            .get(&candidate.video_id)
+            .and_then(|video| video.snippet.as_ref())
+        {
+            candidate.chapters = chapters::parse(&snippet.description);
+        }
+    }
+}
+
+/// Returns the best ranked video, `None` if no video was found.
+pub fn best_candidate(candidates: Vec<Candidate>) -> Option<Candidate> {
+    candidates.into_iter().next()
+}
+
+/// Parses an ISO 8601 duration as returned by youtube, e.g. `PT1H2M3S`, into seconds.
+/// Returns `None` if the duration is malformed or too long to count in seconds.
+fn parse_duration(iso: &str) -> Option<i64> {
+    let mut total: i64 = 0;
+    let mut number: i64 = 0;
+    let mut in_time = false;
+    for c in iso.strip_prefix('P')?.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            number = number.checked_mul(10)?.checked_add(i64::from(digit))?;
+            continue;
+        }
+        let unit = match (c, in_time) {
+            ('T', false) => {
+                in_time = true;
+                continue;
+            }
+            ('W', false) => 7 * 24 * 60 * 60,
+            ('D', false) => 24 * 60 * 60,
+            ('H', true) => 60 * 60,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return None,
+        };
+        total = total.checked_add(number.checked_mul(unit)?)?;
+        number = 0;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn episode(progress: i64, description: &str) -> Song {
+        let mut song = Song::new("Episode 42".to_string(), "The Show".to_string(), progress);
+        song.kind = ItemKind::Episode { duration: 60 * 60 };
+        song.duration = Some(60 * 60);
+        song.chapters = chapters::parse(description);
+        song
+    }
+
+    fn candidate(video_id: &str, score: i64) -> Candidate {
+        let mut candidate = Candidate::new(
+            video_id.to_string(),
+            "An unrelated upload",
+            "Someone",
+            None,
+            &episode(0, ""),
+            0,
+        );
+        candidate.score = score;
+        candidate
+    }
+
+    #[test]
+    fn parses_iso_durations() {
+        assert_eq!(parse_duration("PT1H2M3S"), Some(60 * 60 + 2 * 60 + 3));
+        assert_eq!(parse_duration("PT45S"), Some(45));
+        assert_eq!(parse_duration("P1DT1M"), Some(24 * 60 * 60 + 60));
+        assert_eq!(parse_duration("P1W"), Some(7 * 24 * 60 * 60));
+        assert_eq!(parse_duration("P0D"), Some(0));
+    }
+
+    #[test]
+    fn rejects_malformed_and_overflowing_durations() {
+        assert_eq!(parse_duration("1H2M"), None);
+        assert_eq!(parse_duration("PT1X"), None);
+        // minutes are only valid after the `T`
+        assert_eq!(parse_duration("P1H"), None);
+        assert_eq!(parse_duration("PT99999999999999999999S"), None);
+        assert_eq!(parse_duration(&format!("P{}W", i64::MAX / 2)), None);
+    }
+
+    #[test]
+    fn ranks_videos_lasting_as_long_as_the_episode_first() {
+        let mut candidates = vec![
+            candidate("trailer", 10),
+            candidate("unknown", 8),
+            candidate("full-upload", 5),
+        ];
+        let durations = HashMap::from([
+            ("trailer".to_string(), 90),
+            ("full-upload".to_string(), 60 * 60 + 5 * 60),
+        ]);
+        rank_by_duration(&mut candidates, &durations, 60 * 60);
+
+        let ranked: Vec<_> = candidates
+            .iter()
+            .map(|candidate| (candidate.video_id.as_str(), candidate.score))
+            .collect();
+        assert_eq!(
+            ranked,
+            [("full-upload", 11), ("unknown", 8), ("trailer", 6)]
+        );
+    }
+
+    #[test]
+    fn starts_at_the_chapter_playing_in_the_episode() {
+        let song = episode(
+            20 * 60 + 30,
+            "0:00 Intro\n5:00 Sponsor\n10:00 Interview\n20:00 Questions",
+        );
+        let mut upload = candidate("upload", 0);
+        // the upload cut the sponsor read
+        upload.chapters = chapters::parse("0:00 Intro\n5:00 Interview\n15:00 Questions");
+        assert_eq!(upload.start(&song), 15 * 60 + 30);
+
+        // without chapters the video is expected to follow the episode
+        assert_eq!(candidate("plain", 0).start(&song), 20 * 60 + 30);
+    }
+
+    #[test]
+    fn classifies_videos_by_title_and_channel() {
+        let classified = |title: &str, channel: &str| classify(&fold(title), channel);
+        assert_eq!(
+            classified("Daft Punk - Around the World", "Daft Punk - Topic"),
+            VideoKind::Audio
+        );
+        assert_eq!(
+            classified("Around the World (Official Audio)", "Daft Punk"),
+            VideoKind::Audio
+        );
+        assert_eq!(
+            classified("Around the World [Lyrics]", "Lyric Channel"),
+            VideoKind::Lyrics
+        );
+        assert_eq!(
+            classified("Around the World (Letra)", "Someone"),
+            VideoKind::Lyrics
+        );
+        assert_eq!(
+            classified("Around the World (Official Music Video)", "Daft Punk"),
+            VideoKind::MusicVideo
+        );
+    }
+
+    fn kinds(candidates: &[Candidate]) -> Vec<(&str, i64)> {
+        candidates
+            .iter()
+            .map(|candidate| (candidate.video_id.as_str(), candidate.score))
+            .collect()
+    }
+
+    fn of_kind(video_id: &str, kind: VideoKind, score: i64) -> Candidate {
+        let mut candidate = candidate(video_id, score);
+        candidate.kind = kind;
+        candidate
+    }
+
+    #[test]
+    fn excluded_kinds_are_removed_and_the_preferred_one_ranked_first() {
+        let mut candidates = vec![
+            of_kind("video", VideoKind::MusicVideo, 10),
+            of_kind("audio", VideoKind::Audio, 9),
+            of_kind("lyrics", VideoKind::Lyrics, 6),
+        ];
+        let preferences = VideoPreferences {
+            prefer: Some(VideoKind::Lyrics),
+            exclude: vec![VideoKind::Audio],
+        };
+        apply_preferences(&mut candidates, &preferences);
+        assert_eq!(kinds(&candidates), [("lyrics", 11), ("video", 10)]);
+    }
+
+    #[test]
+    fn default_preferences_keep_the_ranking() {
+        let mut candidates = vec![
+            of_kind("video", VideoKind::MusicVideo, 10),
+            of_kind("audio", VideoKind::Audio, 9),
+        ];
+        apply_preferences(&mut candidates, &VideoPreferences::default());
+        assert_eq!(kinds(&candidates), [("video", 10), ("audio", 9)]);
     }
 }
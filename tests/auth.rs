@@ -0,0 +1,122 @@
+//! The Spotify authorization against a local stand-in of the accounts service.
+
+mod common;
+
+use common::{has_param, spotify_token, FakeServer};
+use rspotify::{
+    model::AlbumId,
+    prelude::{BaseClient, OAuthClient},
+};
+use serde_json::json;
+use spotify_music_vid::{get_auth, get_client_creds};
+
+fn auth(accounts: &FakeServer) -> spotify_music_vid::auth::SpotifyAuth {
+    get_auth(
+        "auth-client",
+        "auth-secret",
+        "http://localhost:8080/callback",
+        &format!("{}/v1", accounts.url()),
+        &format!("{}/", accounts.url()),
+    )
+}
+
+#[tokio::test]
+async fn users_authorize_the_app_on_the_configured_accounts_service() {
+    let accounts = FakeServer::start();
+    let url = auth(&accounts).authorize_url().unwrap();
+
+    let (root, query) = url.split_once('?').unwrap();
+    assert_eq!(root, format!("{}/authorize", accounts.url()));
+    assert!(has_param(query, "client_id", "auth-client"));
+    assert!(has_param(query, "response_type", "code"));
+    assert!(has_param(
+        query,
+        "redirect_uri",
+        "http://localhost:8080/callback"
+    ));
+    assert!(has_param(query, "show_dialog", "true"));
+}
+
+#[tokio::test]
+async fn codes_are_exchanged_and_tokens_refreshed_on_the_configured_accounts_service() {
+    let accounts = FakeServer::start();
+    accounts.reply("/api/token", 200, &spotify_token());
+    let auth = auth(&accounts);
+
+    auth.request_token("auth-code").await.unwrap();
+    auth.refresh_token().await.unwrap();
+
+    let requests = accounts.requests("/api/token");
+    assert_eq!(requests.len(), 2);
+    assert!(has_param(
+        &requests[0].body,
+        "grant_type",
+        "authorization_code"
+    ));
+    assert!(has_param(&requests[0].body, "code", "auth-code"));
+    assert!(has_param(&requests[1].body, "grant_type", "refresh_token"));
+    assert!(has_param(
+        &requests[1].body,
+        "refresh_token",
+        "e2e-refresh-token"
+    ));
+    for request in &requests {
+        assert!(request
+            .authorization
+            .as_deref()
+            .unwrap()
+            .starts_with("Basic "));
+    }
+    let token = auth.get_token();
+    let token = token.lock().await.unwrap();
+    let token = token.as_ref().unwrap();
+    assert_eq!(token.access_token, "e2e-access-token");
+    assert!(token.expires_at.is_some());
+}
+
+#[tokio::test]
+async fn the_app_authenticates_on_the_configured_accounts_service() {
+    let spotify = FakeServer::start();
+    spotify.reply("/api/token", 200, &spotify_token());
+    spotify.reply(
+        "/v1/albums/4aawyAB9vmqN3uQ7FjRGTy/tracks",
+        200,
+        &json!({
+            "href": "",
+            "items": [],
+            "limit": 50,
+            "next": null,
+            "offset": 0,
+            "previous": null,
+            "total": 0
+        }),
+    );
+    let client = get_client_creds(
+        "auth-client",
+        "auth-secret",
+        &format!("{}/v1", spotify.url()),
+        &spotify.url(),
+    );
+
+    client.request_token().await.unwrap();
+    let id = AlbumId::from_id("4aawyAB9vmqN3uQ7FjRGTy").unwrap();
+    client.album_track_manual(id, None, None).await.unwrap();
+
+    let requests = spotify.requests("/api/token");
+    assert_eq!(requests.len(), 1);
+    assert!(has_param(
+        &requests[0].body,
+        "grant_type",
+        "client_credentials"
+    ));
+    assert!(requests[0]
+        .authorization
+        .as_deref()
+        .unwrap()
+        .starts_with("Basic "));
+    let requests = spotify.requests("/v1/albums/4aawyAB9vmqN3uQ7FjRGTy/tracks");
+    assert_eq!(
+        requests[0].authorization.as_deref(),
+        Some("Bearer e2e-access-token")
+    );
+}
@@ -0,0 +1,48 @@
+use spotify_music_vid::chapters::{parse, position, Chapter};
+
+fn chapter(start: i64, title: &str) -> Chapter {
+    Chapter {
+        start,
+        title: title.to_string(),
+    }
+}
+
+#[test]
+fn reads_the_chapters_of_a_description() {
+    let description = "In this episode:\n\
+        0:00 Intro\n\
+        (4:05) - Sponsor\n\
+        12:34 | The interview\n\
+        1:02:03 Questions\n\
+        Follow us at example.com";
+    assert_eq!(
+        parse(description),
+        [
+            chapter(0, "Intro"),
+            chapter(4 * 60 + 5, "Sponsor"),
+            chapter(12 * 60 + 34, "The interview"),
+            chapter(60 * 60 + 2 * 60 + 3, "Questions"),
+        ]
+    );
+}
+
+#[test]
+fn ignores_timestamps_that_are_not_a_chapter_list() {
+    // a single timestamp, a list not starting at 0:00, and one out of order
+    assert_eq!(parse("Best moment at 0:00 Intro"), []);
+    assert_eq!(parse("0:00 Intro"), []);
+    assert_eq!(parse("1:00 Intro\n2:00 Interview"), []);
+    assert_eq!(parse("0:00 Intro\n9:00 Interview\n3:00 Questions"), []);
+    assert_eq!(parse("0:00 Intro\n1:75 Interview"), []);
+}
+
+#[test]
+fn carries_the_position_over_through_the_same_chapter() {
+    let episode = parse("0:00 Intro\n2:00 Ad break\n4:00 The Interview");
+    let video = parse("0:00 Intro\n1:00 The interview!");
+    assert_eq!(position(&episode, &video, 4 * 60 + 10), Some(60 + 10));
+    assert_eq!(position(&episode, &video, 30), Some(30));
+    // the ad break was cut from the video
+    assert_eq!(position(&episode, &video, 2 * 60 + 30), None);
+    assert_eq!(position(&[], &video, 30), None);
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use futures_util::FutureExt;
+use spotify_music_vid::clock::{Clock, ManualClock};
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+#[test]
+fn sleeps_end_once_the_clock_reaches_their_deadline() {
+    let clock = ManualClock::default();
+    let mut sleep = clock.sleep(secs(5));
+    assert_eq!(clock.next_deadline(), Some(secs(5)));
+    assert!((&mut sleep).now_or_never().is_none());
+
+    clock.advance(secs(4));
+    assert!((&mut sleep).now_or_never().is_none());
+    clock.advance(secs(1));
+    assert!(sleep.now_or_never().is_some());
+    assert_eq!(clock.next_deadline(), None);
+}
+
+#[test]
+fn sleeps_already_due_end_immediately() {
+    let clock = ManualClock::default();
+    clock.set(secs(10));
+    assert!(clock.sleep_until(secs(3)).now_or_never().is_some());
+    assert!(clock.sleep(Duration::ZERO).now_or_never().is_some());
+}
+
+#[test]
+fn time_never_goes_back() {
+    let clock = ManualClock::default();
+    clock.set(secs(10));
+    clock.set(secs(3));
+    assert_eq!(clock.now(), secs(10));
+}
+
+#[test]
+fn the_date_follows_the_time() {
+    let start = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+    let clock = ManualClock::new(start);
+    clock.advance(secs(90));
+    assert_eq!(
+        clock.utc(),
+        Utc.with_ymd_and_hms(2026, 10, 18, 12, 1, 30).unwrap()
+    );
+}
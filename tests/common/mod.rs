@@ -0,0 +1,385 @@
+//! Local stand-ins of Spotify and youtube answering scripted responses, and the server under test.
+// every test binary including this module only uses part of it
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{SocketAddr, TcpListener},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use warp::{
+    http::{HeaderMap, Method, StatusCode},
+    hyper::body::Bytes,
+    path::FullPath,
+    Filter,
+};
+
+/// Time the server has to answer before a test fails
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request received by a [`FakeServer`].
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub query: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+struct Reply {
+    status: StatusCode,
+    body: String,
+}
+
+#[derive(Default)]
+struct State {
+    replies: HashMap<String, VecDeque<Reply>>,
+    requests: HashMap<String, Vec<Request>>,
+}
+
+/// An http server answering the replies scripted for each path, in order.
+/// The last reply of a path is repeated, paths without replies answer 404.
+pub struct FakeServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeServer {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        let route = warp::method()
+            .and(warp::path::full())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method,
+                      path: FullPath,
+                      query: String,
+                      headers: HeaderMap,
+                      body: Bytes| {
+                    let authorization = headers
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string);
+                    let request = Request {
+                        method,
+                        query,
+                        authorization,
+                        body: String::from_utf8_lossy(&body).into_owned(),
+                    };
+                    let reply = shared.lock().unwrap().answer(path.as_str(), request);
+                    warp::reply::with_status(
+                        warp::reply::with_header(reply.body, "content-type", "application/json"),
+                        reply.status,
+                    )
+                },
+            );
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        Self { addr, state }
+    }
+
+    /// Returns the root url of the server, without a trailing slash.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Queues a reply to the requests to `path`, a `null` body is sent as an empty body.
+    pub fn reply(&self, path: &str, status: u16, body: &Value) -> &Self {
+        let body = if body.is_null() {
+            String::new()
+        } else {
+            body.to_string()
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .entry(path.to_string())
+            .or_default()
+            .push_back(Reply {
+                status: StatusCode::from_u16(status).unwrap(),
+                body,
+            });
+        self
+    }
+
+    /// Returns the requests received on `path`, oldest first.
+    pub fn requests(&self, path: &str) -> Vec<Request> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .get(path)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl State {
+    fn answer(&mut self, path: &str, request: Request) -> Reply {
+        self.requests
+            .entry(path.to_string())
+            .or_default()
+            .push(request);
+        let reply = match self.replies.get_mut(path) {
+            Some(replies) if replies.len() > 1 => replies.pop_front(),
+            Some(replies) => replies.front().cloned(),
+            None => None,
+        };
+        reply.unwrap_or_else(|| Reply {
+            status: StatusCode::NOT_FOUND,
+            body: json!({ "error": format!("no reply scripted for {path}") }).to_string(),
+        })
+    }
+}
+
+/// The token returned by the accounts service.
+pub fn spotify_token() -> Value {
+    json!({
+        "access_token": "e2e-access-token",
+        "token_type": "Bearer",
+        "expires_in": 3600,
+        "refresh_token": "e2e-refresh-token",
+        "scope": "user-read-currently-playing user-read-playback-state user-library-read user-read-private"
+    })
+}
+
+/// The response of `me`.
+pub fn spotify_user(id: &str, country: &str) -> Value {
+    json!({
+        "country": country,
+        "display_name": id,
+        "email": null,
+        "external_urls": {},
+        "explicit_content": null,
+        "followers": null,
+        "href": format!("https://api.spotify.com/v1/users/{id}"),
+        "id": id,
+        "images": [],
+        "product": "premium"
+    })
+}
+
+/// A track of Daft Punk, as Spotify describes it.
+fn track(track_id: &str, title: &str) -> Value {
+    let artist = json!({ "external_urls": {}, "href": null, "id": null, "name": "Daft Punk" });
+    json!({
+        "album": {
+            "album_type": "album",
+            "artists": [artist],
+            "external_urls": {},
+            "href": null,
+            "id": null,
+            "images": [],
+            "name": "Homework"
+        },
+        "artists": [artist],
+        "disc_number": 1,
+        "duration_ms": 429_000,
+        "explicit": false,
+        "external_ids": {},
+        "external_urls": {},
+        "href": null,
+        "id": track_id,
+        "is_local": false,
+        "name": title,
+        "popularity": 50,
+        "preview_url": null,
+        "track_number": 1
+    })
+}
+
+/// The response of `me/player/currently-playing` while a track of Daft Punk is playing.
+pub fn currently_playing(track_id: &str, title: &str, progress_secs: u64) -> Value {
+    json!({
+        "context": null,
+        "timestamp": 1_792_339_200_000_u64,
+        "progress_ms": progress_secs * 1000,
+        "is_playing": true,
+        "item": track(track_id, title),
+        "currently_playing_type": "track",
+        "actions": { "disallows": { "resuming": true } }
+    })
+}
+
+/// The response of `me/player/queue`, nothing playing and tracks of Daft Punk as `(id, title)` queued.
+pub fn queue(tracks: &[(&str, &str)]) -> Value {
+    let queue: Vec<Value> = tracks
+        .iter()
+        .map(|(track_id, title)| track(track_id, title))
+        .collect();
+    json!({ "currently_playing": null, "queue": queue })
+}
+
+/// The response of the youtube `search` endpoint, videos as `(id, title, channel)`.
+pub fn youtube_search(videos: &[(&str, &str, &str)]) -> Value {
+    let thumbnail =
+        json!({ "url": "https://i.ytimg.com/vi/default.jpg", "width": 120, "height": 90 });
+    let items: Vec<Value> = videos
+        .iter()
+        .map(|(id, title, channel)| {
+            json!({
+                "kind": "youtube#searchResult",
+                "etag": "etag",
+                "id": { "kind": "youtube#video", "videoId": id },
+                "snippet": {
+                    "publishedAt": "2009-10-25T06:57:33Z",
+                    "channelId": format!("channel-{channel}"),
+                    "title": title,
+                    "description": "",
+                    "thumbnails": { "default": thumbnail, "medium": thumbnail, "high": thumbnail },
+                    "channelTitle": channel,
+                    "liveBroadcastContent": "none",
+                    "publishTime": "2009-10-25T06:57:33Z"
+                }
+            })
+        })
+        .collect();
+    json!({
+        "kind": "youtube#searchListResponse",
+        "etag": "etag",
+        "nextPageToken": "",
+        "regionCode": "US",
+        "pageInfo": { "totalResults": items.len(), "resultsPerPage": items.len() },
+        "items": items
+    })
+}
+
+/// The response of the youtube `videos` endpoint, videos as `(id, ISO 8601 duration)`.
+pub fn youtube_videos(videos: &[(&str, &str)]) -> Value {
+    let items: Vec<Value> = videos
+        .iter()
+        .map(|(id, duration)| json!({ "id": id, "contentDetails": { "duration": duration } }))
+        .collect();
+    json!({ "items": items })
+}
+
+/// The server binary, stopped when dropped.
+pub struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    /// Migrates the database and starts the server against the fake services.
+    pub fn start(database_url: &str, spotify: &FakeServer, youtube: &FakeServer) -> Self {
+        Self::start_with(database_url, spotify, youtube, &[])
+    }
+
+    /// Like [`Server::start`], with `env` overriding the settings of the tests.
+    pub fn start_with(
+        database_url: &str,
+        spotify: &FakeServer,
+        youtube: &FakeServer,
+        env: &[(&str, &str)],
+    ) -> Self {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let command = |args: &[&str]| {
+            let mut command = Command::new(env!("CARGO_BIN_EXE_spotify-music-vid"));
+            command
+                .args(args)
+                // away from a config.toml in the working directory
+                .current_dir(std::env::temp_dir())
+                .env_remove("CONFIG_FILE")
+                .env("DATABASE_URL", database_url)
+                .env("HOST", "127.0.0.1")
+                .env("PORT", port.to_string())
+                .env("SPOTIFY_CLIENT_ID", "e2e-client")
+                .env("SPOTIFY_CLIENT_SECRET", "e2e-secret")
+                .env("YOUTUBE_API_KEY", "e2e-key")
+                .env("SPOTIFY__API_URL", format!("{}/v1", spotify.url()))
+                .env("SPOTIFY__ACCOUNTS_URL", spotify.url())
+                .env("YOUTUBE__API_URL", youtube.url())
+                .env("POLLING__INTERVAL_MS", "100")
+                .env("CACHE__ENABLED", "false")
+                .env("LASTFM__API_KEY", "")
+                .env("RUST_LOG", "error")
+                .envs(env.iter().copied())
+                .stdout(Stdio::null());
+            command
+        };
+        let status = command(&["migrate"]).status().unwrap();
+        assert!(status.success(), "the migrations failed");
+        let child = command(&["serve"]).spawn().unwrap();
+        Self { child, port }
+    }
+
+    /// Sends a `GET` to `path` once the server listens, returning the status and the json body.
+    pub async fn get(&self, path: &str) -> (u16, Value) {
+        let url = format!("http://127.0.0.1:{}{path}", self.port);
+        for _ in 0..100 {
+            if let Ok(response) = reqwest::get(&url).await {
+                let status = response.status().as_u16();
+                return (status, response.json().await.unwrap());
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("the server is not listening on {url}");
+    }
+
+    /// Connects a client to `/ws`, waiting for the server to listen.
+    pub async fn connect(&self) -> Client {
+        let url = format!("ws://127.0.0.1:{}/ws", self.port);
+        for _ in 0..100 {
+            if let Ok((socket, _)) = connect_async(&url).await {
+                return Client(socket);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("the server is not listening on {url}");
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A websocket client of the server.
+pub struct Client(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+impl Client {
+    pub async fn send(&mut self, text: &str) {
+        self.0.send(Message::Text(text.to_string())).await.unwrap();
+    }
+
+    /// Returns the next text message, failing the test if none comes in time.
+    pub async fn next_text(&mut self) -> String {
+        loop {
+            let message = timeout(TIMEOUT, self.0.next())
+                .await
+                .expect("no message from the server in time")
+                .expect("the server closed the connection")
+                .unwrap();
+            if let Message::Text(text) = message {
+                return text;
+            }
+        }
+    }
+}
+
+/// Returns the database of the end to end tests, they fail without `DATABASE_URL`.
+pub fn database_url() -> String {
+    std::env::var("DATABASE_URL").expect("DATABASE_URL must be set to run the end to end tests")
+}
+
+/// Returns whether the query or form has `name=value`.
+pub fn has_param(query: &str, name: &str, value: &str) -> bool {
+    url::form_urlencoded::parse(query.as_bytes()).any(|(n, v)| n == name && v == value)
+}
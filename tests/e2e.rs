@@ -0,0 +1,251 @@
+//! End to end tests of `/ws` against local stand-ins of Spotify and youtube.
+//! They need a Postgres database in `DATABASE_URL`, and fail without one.
+
+mod common;
+
+use common::{
+    currently_playing, database_url, has_param, queue, spotify_token, spotify_user, youtube_search,
+    youtube_videos, FakeServer, Server,
+};
+use serde_json::{json, Value};
+
+const CURRENTLY_PLAYING: &str = "/v1/me/player/currently-playing";
+const QUEUE: &str = "/v1/me/player/queue";
+
+/// Starts the fake Spotify with a user in the US, the server still has to be told what plays.
+fn spotify() -> FakeServer {
+    let spotify = FakeServer::start();
+    spotify.reply("/api/token", 200, &spotify_token()).reply(
+        "/v1/me/",
+        200,
+        &spotify_user("e2e-user", "US"),
+    );
+    spotify
+}
+
+/// Authorizes the session and checks the code was exchanged.
+async fn authorize(client: &mut common::Client, spotify: &FakeServer) {
+    let auth_url = client.next_text().await;
+    assert!(
+        auth_url.starts_with(&format!("{}/authorize?", spotify.url())),
+        "{auth_url}"
+    );
+    let (_, query) = auth_url.split_once('?').unwrap();
+    assert!(has_param(query, "client_id", "e2e-client"));
+    client.send("e2e-code").await;
+}
+
+#[tokio::test]
+async fn sends_the_video_of_the_playing_track_and_follows_seeks() {
+    let database_url = database_url();
+    let spotify = spotify();
+    spotify
+        .reply(
+            CURRENTLY_PLAYING,
+            200,
+            &currently_playing("4uLU6hMCjMI75M1A2tKUQC", "Around the World", 42),
+        )
+        .reply(
+            CURRENTLY_PLAYING,
+            200,
+            &currently_playing("4uLU6hMCjMI75M1A2tKUQC", "Around the World", 120),
+        );
+    let youtube = FakeServer::start();
+    youtube
+        .reply(
+            "/search",
+            200,
+            &youtube_search(&[
+                ("cover-video", "Around the World (cover)", "Some Band"),
+                (
+                    "dQw4w9WgXcQ",
+                    "Daft Punk - Around the World (Official Music Video)",
+                    "Daft Punk",
+                ),
+            ]),
+        )
+        .reply(
+            "/videos",
+            200,
+            &youtube_videos(&[("cover-video", "PT7M9S"), ("dQw4w9WgXcQ", "PT7M9S")]),
+        );
+    let server = Server::start(&database_url, &spotify, &youtube);
+
+    let mut client = server.connect().await;
+    authorize(&mut client, &spotify).await;
+    assert_eq!(
+        client.next_text().await,
+        "https://www.youtube.com/embed/dQw4w9WgXcQ?start=42&autoplay=1&enablejsapi=1"
+    );
+    // the next polls find the track 78 seconds further
+    assert_eq!(
+        client.next_text().await,
+        "https://www.youtube.com/embed/dQw4w9WgXcQ?start=120&autoplay=1&enablejsapi=1"
+    );
+
+    let token = &spotify.requests("/api/token")[0];
+    assert!(has_param(&token.body, "code", "e2e-code"));
+    assert!(has_param(&token.body, "grant_type", "authorization_code"));
+    assert!(token
+        .authorization
+        .as_deref()
+        .unwrap()
+        .starts_with("Basic "));
+    let poll = &spotify.requests(CURRENTLY_PLAYING)[0];
+    assert_eq!(
+        poll.authorization.as_deref(),
+        Some("Bearer e2e-access-token")
+    );
+    assert!(has_param(&poll.query, "market", "US"));
+    let search = &youtube.requests("/search")[0];
+    assert!(has_param(&search.query, "key", "e2e-key"));
+    assert!(has_param(&search.query, "regionCode", "US"));
+}
+
+#[tokio::test]
+async fn describes_the_track_when_no_video_is_found() {
+    let database_url = database_url();
+    let spotify = spotify();
+    spotify.reply(
+        CURRENTLY_PLAYING,
+        200,
+        &currently_playing("0DiWol3AO6WpXZgp0goxAV", "One More Time", 10),
+    );
+    let youtube = FakeServer::start();
+    youtube.reply("/search", 200, &youtube_search(&[]));
+    let server = Server::start(&database_url, &spotify, &youtube);
+
+    let mut client = server.connect().await;
+    authorize(&mut client, &spotify).await;
+    let message: Value = serde_json::from_str(&client.next_text().await).unwrap();
+    assert_eq!(message["type"], "no_video");
+    assert_eq!(message["code"], "no_video");
+    assert_eq!(message["title"], json!("One More Time"));
+    assert_eq!(message["artist"], json!("Daft Punk"));
+    assert_eq!(message["artists"], json!(["Daft Punk"]));
+    assert_eq!(message["progress"], json!(10));
+}
+
+#[tokio::test]
+async fn reports_youtube_failures_to_the_client() {
+    let database_url = database_url();
+    let spotify = spotify();
+    spotify.reply(
+        CURRENTLY_PLAYING,
+        200,
+        &currently_playing("4uLU6hMCjMI75M1A2tKUQC", "Around the World", 0),
+    );
+    let youtube = FakeServer::start();
+    youtube.reply("/search", 500, &json!({ "error": "backend error" }));
+    let server = Server::start(&database_url, &spotify, &youtube);
+
+    let mut client = server.connect().await;
+    authorize(&mut client, &spotify).await;
+    let message: Value = serde_json::from_str(&client.next_text().await).unwrap();
+    assert_eq!(message["code"], "youtube", "{message}");
+}
+
+#[tokio::test]
+async fn stays_ready_while_the_spotify_account_of_a_listener_fails() {
+    let database_url = database_url();
+    let spotify = spotify();
+    spotify.reply(CURRENTLY_PLAYING, 401, &json!({ "error": "revoked" }));
+    let youtube = FakeServer::start();
+    let server = Server::start(&database_url, &spotify, &youtube);
+
+    let mut client = server.connect().await;
+    authorize(&mut client, &spotify).await;
+    while spotify.requests(CURRENTLY_PLAYING).is_empty() {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    let (status, body) = server.get("/readyz").await;
+    assert_eq!(status, 200, "{body}");
+    assert_eq!(body["spotify"], "failing");
+}
+
+#[tokio::test]
+async fn warms_the_cache_with_the_queued_tracks() {
+    let database_url = database_url();
+    let spotify = spotify();
+    spotify.reply(CURRENTLY_PLAYING, 204, &Value::Null).reply(
+        QUEUE,
+        200,
+        &queue(&[
+            ("0DiWol3AO6WpXZgp0goxAV", "One More Time"),
+            ("2VEZx7NWsZ1D0eJ4uv5Fym", "Da Funk"),
+        ]),
+    );
+    let youtube = FakeServer::start();
+    youtube
+        .reply(
+            "/search",
+            200,
+            &youtube_search(&[("queued-video", "Daft Punk (Official Video)", "Daft Punk")]),
+        )
+        .reply(
+            "/videos",
+            200,
+            &youtube_videos(&[("queued-video", "PT5M20S")]),
+        );
+    let server = Server::start_with(
+        &database_url,
+        &spotify,
+        &youtube,
+        &[
+            ("CACHE__ENABLED", "true"),
+            ("CACHE__WARM__REQUEST_INTERVAL_MS", "0"),
+        ],
+    );
+
+    let mut client = server.connect().await;
+    authorize(&mut client, &spotify).await;
+    client.send(r#"{"type": "warm", "source": "queue"}"#).await;
+    let progress = loop {
+        let message: Value = serde_json::from_str(&client.next_text().await).unwrap();
+        assert_eq!(message["type"], "warm_progress", "{message}");
+        if message["finished"] == json!(true) {
+            break message;
+        }
+    };
+    assert_eq!(progress["total"], json!(2));
+    assert_eq!(progress["failed"], json!(0));
+    // the tracks stay cached from earlier runs
+    let resolved = progress["cached"].as_u64().unwrap() + progress["added"].as_u64().unwrap();
+    assert_eq!(resolved, 2, "{progress}");
+    let request = &spotify.requests(QUEUE)[0];
+    assert_eq!(
+        request.authorization.as_deref(),
+        Some("Bearer e2e-access-token")
+    );
+}
+
+#[tokio::test]
+async fn stays_ready_while_the_youtube_quota_is_used_up() {
+    let database_url = database_url();
+    let spotify = spotify();
+    spotify.reply(
+        CURRENTLY_PLAYING,
+        200,
+        &currently_playing("4uLU6hMCjMI75M1A2tKUQC", "Around the World", 0),
+    );
+    let youtube = FakeServer::start();
+    youtube.reply(
+        "/search",
+        403,
+        &json!({ "error": { "code": 403, "message": "", "errors": [{ "reason": "quotaExceeded" }] } }),
+    );
+    let server = Server::start_with(
+        &database_url,
+        &spotify,
+        &youtube,
+        &[("SERVER__READINESS_WINDOW_SECS", "0")],
+    );
+
+    let mut client = server.connect().await;
+    authorize(&mut client, &spotify).await;
+    let message: Value = serde_json::from_str(&client.next_text().await).unwrap();
+    assert_eq!(message["code"], "quota_exceeded", "{message}");
+    let (status, body) = server.get("/readyz").await;
+    assert_eq!(status, 200, "{body}");
+    assert_eq!(body["youtube"], "unknown");
+}
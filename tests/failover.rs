@@ -0,0 +1,221 @@
+//! Failover from the youtube data api to the Invidious and Piped instances,
+//! against local stand-ins of the three, through the `resolve` command.
+
+#[allow(dead_code)]
+mod common;
+
+use std::{fs, process::Command};
+
+use common::{youtube_search, FakeServer};
+use serde_json::{json, Value};
+
+const QUERY: &str = "Daft Punk - Around the World";
+
+/// A video of `GET /api/v1/search` on Invidious.
+fn invidious_video(video_id: &str, title: &str) -> Value {
+    json!({
+        "type": "video",
+        "title": title,
+        "videoId": video_id,
+        "author": "Daft Punk",
+        "authorId": "UCdaftpunk",
+        "lengthSeconds": 429,
+    })
+}
+
+/// The response of `GET /search` on Piped.
+fn piped_search(video_id: &str, title: &str) -> Value {
+    json!({
+        "items": [{
+            "url": format!("/watch?v={video_id}"),
+            "type": "stream",
+            "title": title,
+            "uploaderName": "Daft Punk",
+            "uploaderUrl": "/channel/UCdaftpunk",
+            "duration": 429,
+        }]
+    })
+}
+
+/// The data api and the instances searched after it, in order.
+struct Providers {
+    youtube: FakeServer,
+    invidious: FakeServer,
+    piped: FakeServer,
+}
+
+impl Providers {
+    fn start() -> Self {
+        Self {
+            youtube: FakeServer::start(),
+            invidious: FakeServer::start(),
+            piped: FakeServer::start(),
+        }
+    }
+
+    /// Runs `resolve` against the providers and returns what it printed.
+    async fn resolve(&self) -> String {
+        let fallbacks = format!(
+            "[[youtube.fallbacks]]\napi = \"invidious\"\ninstance_url = \"{}\"\n\n\
+             [[youtube.fallbacks]]\napi = \"piped\"\ninstance_url = \"{}\"\n",
+            self.invidious.url(),
+            self.piped.url()
+        );
+        self.resolve_with(&fallbacks).await
+    }
+
+    /// Runs `resolve` with `config_toml` as the config file and returns what it printed.
+    async fn resolve_with(&self, config_toml: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("failover-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(&config, config_toml).unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_spotify-music-vid"));
+        command
+            .args(["resolve", QUERY])
+            .current_dir(&dir)
+            .env("CONFIG_FILE", &config)
+            .env_remove("DATABASE_URL")
+            .env("YOUTUBE_API_KEY", "failover-key")
+            .env("YOUTUBE__API_URL", self.youtube.url())
+            .env("RUST_LOG", "error");
+        let output = tokio::task::spawn_blocking(move || command.output())
+            .await
+            .unwrap()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_the_data_api_is_searched_while_it_answers() {
+    let providers = Providers::start();
+    providers.youtube.reply(
+        "/search",
+        200,
+        &youtube_search(&[("primary-id", "Daft Punk - Around the World", "Daft Punk")]),
+    );
+
+    let output = providers.resolve().await;
+    assert!(output.starts_with("Found on youtube\n"), "{output}");
+    assert!(output.contains("primary-id"), "{output}");
+    assert!(providers.invidious.requests("/api/v1/search").is_empty());
+    assert!(providers.piped.requests("/search").is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_over_to_invidious_when_the_data_api_fails() {
+    let providers = Providers::start();
+    providers
+        .youtube
+        .reply("/search", 500, &json!({ "error": "backend error" }));
+    providers.invidious.reply(
+        "/api/v1/search",
+        200,
+        &json!([invidious_video(
+            "invidious-id",
+            "Daft Punk - Around the World"
+        )]),
+    );
+
+    let output = providers.resolve().await;
+    assert!(
+        output.starts_with(&format!("Found on {}\n", providers.invidious.url())),
+        "{output}"
+    );
+    assert!(output.contains("invidious-id"), "{output}");
+    let search = &providers.invidious.requests("/api/v1/search")[0];
+    assert!(common::has_param(&search.query, "type", "video"));
+    assert!(providers.piped.requests("/search").is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_over_to_piped_when_invidious_fails_too() {
+    let providers = Providers::start();
+    let quota = json!({
+        "error": { "code": 403, "errors": [{ "reason": "quotaExceeded" }] }
+    });
+    providers.youtube.reply("/search", 403, &quota);
+    providers
+        .invidious
+        .reply("/api/v1/search", 502, &json!({ "error": "bad gateway" }));
+    providers.piped.reply(
+        "/search",
+        200,
+        &piped_search("piped-id", "Daft Punk - Around the World"),
+    );
+
+    let output = providers.resolve().await;
+    assert!(
+        output.starts_with(&format!("Found on {}\n", providers.piped.url())),
+        "{output}"
+    );
+    assert!(output.contains("piped-id"), "{output}");
+    assert!(output.contains("UCdaftpunk"), "{output}");
+    assert!(!providers.invidious.requests("/api/v1/search").is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tries_the_next_provider_when_one_finds_nothing() {
+    let providers = Providers::start();
+    providers
+        .youtube
+        .reply("/search", 200, &youtube_search(&[]));
+    providers.invidious.reply("/api/v1/search", 200, &json!([]));
+    providers.piped.reply(
+        "/search",
+        200,
+        &piped_search("piped-id", "Daft Punk - Around the World"),
+    );
+
+    let output = providers.resolve().await;
+    assert!(
+        output.starts_with(&format!("Found on {}\n", providers.piped.url())),
+        "{output}"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_no_video_when_every_provider_finds_nothing() {
+    let providers = Providers::start();
+    providers
+        .youtube
+        .reply("/search", 200, &youtube_search(&[]));
+    providers.invidious.reply("/api/v1/search", 200, &json!([]));
+    providers
+        .piped
+        .reply("/search", 200, &json!({ "items": [] }));
+
+    let output = providers.resolve().await;
+    assert!(output.starts_with("No videos found for"), "{output}");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reads_the_single_fallback_of_older_configs() {
+    let providers = Providers::start();
+    providers
+        .youtube
+        .reply("/search", 500, &json!({ "error": "backend error" }));
+    providers.piped.reply(
+        "/search",
+        200,
+        &piped_search("piped-id", "Daft Punk - Around the World"),
+    );
+
+    let config = format!(
+        "[youtube.fallback]\napi = \"piped\"\ninstance_url = \"{}\"\n",
+        providers.piped.url()
+    );
+    let output = providers.resolve_with(&config).await;
+    assert!(
+        output.starts_with(&format!("Found on {}\n", providers.piped.url())),
+        "{output}"
+    );
+}
@@ -0,0 +1,105 @@
+use spotify_music_vid::normalize::{clean_title, fold, match_forms, romanize, title_forms};
+
+fn clean(title: &str) -> String {
+    clean_title(
+        title,
+        &["Daft Punk".to_string(), "Pharrell Williams".to_string()],
+    )
+}
+
+#[test]
+fn strips_release_annotations() {
+    assert_eq!(clean("Get Lucky (Radio Edit)"), "Get Lucky");
+    assert_eq!(clean("Get Lucky [Remastered 2011]"), "Get Lucky");
+    assert_eq!(clean("Get Lucky (2013 Remaster)"), "Get Lucky");
+    assert_eq!(clean("Get Lucky (Remastered 2013 Version)"), "Get Lucky");
+    assert_eq!(clean("Get Lucky - Remastered 2011"), "Get Lucky");
+    assert_eq!(clean("Get Lucky - 2009 Digital Remaster"), "Get Lucky");
+    assert_eq!(clean("Get Lucky - Single Version"), "Get Lucky");
+    assert_eq!(clean("Get Lucky (Clean)"), "Get Lucky");
+}
+
+#[test]
+fn keeps_annotations_that_are_part_of_the_song() {
+    assert_eq!(
+        clean("Rather Be (Clean Bandit Remix)"),
+        "Rather Be (Clean Bandit Remix)"
+    );
+    assert_eq!(
+        clean("Rather Be - Clean Bandit Remix"),
+        "Rather Be - Clean Bandit Remix"
+    );
+    assert_eq!(
+        clean("Get Lucky (Edit Kid Remix)"),
+        "Get Lucky (Edit Kid Remix)"
+    );
+    assert_eq!(clean("Get Lucky - Live"), "Get Lucky - Live");
+    assert_eq!(clean("Remastered"), "Remastered");
+}
+
+#[test]
+fn strips_featured_artists() {
+    assert_eq!(clean("Get Lucky (feat. Pharrell Williams)"), "Get Lucky");
+    assert_eq!(clean("Get Lucky [ft. Pharrell Williams]"), "Get Lucky");
+    assert_eq!(clean("Get Lucky featuring Pharrell Williams"), "Get Lucky");
+    assert_eq!(
+        clean("Get Lucky (with Pharrell Williams & Nile Rodgers)"),
+        "Get Lucky"
+    );
+}
+
+#[test]
+fn keeps_with_when_it_names_no_credited_artist() {
+    assert_eq!(clean("Stay (With Me)"), "Stay (With Me)");
+    assert_eq!(clean("Dance (With Somebody)"), "Dance (With Somebody)");
+}
+
+#[test]
+fn folds_case_accents_and_punctuation() {
+    assert_eq!(fold("Beyoncé"), "beyonce");
+    assert_eq!(fold("  Déjà-Vu!! (Live) "), "deja vu live");
+    assert_eq!(fold("ＡＢＣ"), "abc");
+    assert_eq!(fold("夜に駆ける"), "夜に駆ける");
+}
+
+#[test]
+fn romanizes_other_scripts() {
+    assert_eq!(romanize("Москва"), "moskva");
+    assert_eq!(romanize("よるにかける"), "yorunikakeru");
+    // kanji would be read as Chinese
+    assert_eq!(romanize("夜に駆ける"), "");
+    assert_eq!(romanize("紅蓮華"), "hong lian hua");
+}
+
+#[test]
+fn accented_titles_keep_their_annotation() {
+    assert_eq!(match_forms("Déjà Vu (Remix)"), ["deja vu remix"]);
+    assert_eq!(match_forms("Crème (Acoustic)"), ["creme acoustic"]);
+}
+
+#[test]
+fn names_in_two_scripts_match_either_name() {
+    assert_eq!(
+        match_forms("夜に駆ける (Yoru ni Kakeru)"),
+        ["夜に駆ける", "yoru ni kakeru"]
+    );
+    assert_eq!(match_forms("Кино (Kino)"), ["кино", "kino"]);
+    assert_eq!(
+        match_forms("Gangnam Style (강남스타일)"),
+        // hangul is folded to its decomposed jamo
+        [
+            "gangnam style".to_string(),
+            fold("강남스타일"),
+            "gangnamseutail".to_string()
+        ]
+    );
+}
+
+#[test]
+fn kana_match_romaji_titles() {
+    let forms = match_forms("よるにかける");
+    let titles = title_forms("YOASOBI - Yoru ni Kakeru (Official Video)");
+    assert!(forms
+        .iter()
+        .any(|form| titles.iter().any(|title| title.contains(form.as_str()))));
+}
@@ -0,0 +1,189 @@
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use spotify_music_vid::{
+    clock::{Clock, ManualClock},
+    poller::Poller,
+    replay::ReplaySource,
+    schedule::{Backoff, PollSchedule, TRACK_CHANGE_DELAY},
+    source::{Playback, PlaybackSource},
+    tracker::{Change, StateTracker},
+    Error, Result, Song,
+};
+
+/// Returns the scripted playbacks in order, then nothing playing.
+struct ScriptedSource(VecDeque<Result<Playback>>);
+
+impl PlaybackSource for ScriptedSource {
+    fn name(&self) -> &'static str {
+        "scripted"
+    }
+
+    fn current(&mut self) -> BoxFuture<'_, Result<Playback>> {
+        let next = self.0.pop_front().unwrap_or(Err(Error::NothingPlaying));
+        futures_util::future::ready(next).boxed()
+    }
+}
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+fn playing(progress: i64, duration: Option<i64>) -> Playback {
+    let mut song = Song::new("Da Funk".to_string(), "Daft Punk".to_string(), progress);
+    song.duration = duration;
+    Playback {
+        song,
+        is_playing: true,
+    }
+}
+
+fn failure() -> Result<Playback> {
+    Err(Error::Player("the player went away".to_string()))
+}
+
+fn poller(
+    source: impl PlaybackSource + 'static,
+    interval: Duration,
+    clock: &ManualClock,
+) -> Poller {
+    let schedule = PollSchedule::new(interval, Backoff::new(secs(5), secs(60)));
+    Poller::new(
+        Box::new(source),
+        schedule,
+        StateTracker::default(),
+        clock.shared(),
+    )
+}
+
+#[test]
+fn backoff_doubles_up_to_the_maximum() {
+    let backoff = Backoff::new(secs(5), secs(60));
+    let delays: Vec<_> = (1..=6).map(|failures| backoff.delay(failures)).collect();
+    assert_eq!(
+        delays,
+        [secs(5), secs(10), secs(20), secs(40), secs(60), secs(60)]
+    );
+    assert_eq!(backoff.delay(u32::MAX), secs(60));
+}
+
+#[tokio::test]
+async fn backs_off_while_the_source_fails() {
+    let clock = ManualClock::default();
+    let script = [
+        failure(),
+        failure(),
+        failure(),
+        Ok(playing(10, None)),
+        failure(),
+    ];
+    let mut poller = poller(ScriptedSource(script.into()), secs(1), &clock);
+
+    let mut delays = Vec::new();
+    for _ in 0..5 {
+        clock.set(poller.next_poll());
+        let _ = poller.poll().await;
+        delays.push(poller.delay());
+    }
+    // the successful poll starts the backoff over
+    assert_eq!(delays, [secs(5), secs(10), secs(20), secs(1), secs(5)]);
+    assert_eq!(poller.next_poll(), secs(36 + 5));
+}
+
+#[tokio::test]
+async fn does_not_back_off_while_an_ad_plays() {
+    let clock = ManualClock::default();
+    let script = [
+        Ok(playing(10, None)),
+        Err(Error::UnsupportedItem),
+        Err(Error::UnsupportedItem),
+        Ok(playing(11, None)),
+    ];
+    let mut poller = poller(ScriptedSource(script.into()), secs(1), &clock);
+
+    let mut polls = Vec::new();
+    for _ in 0..4 {
+        clock.set(poller.next_poll());
+        let (playback, change) = poller.poll().await.unwrap();
+        polls.push((playback.is_some(), change, poller.delay()));
+    }
+    assert_eq!(
+        polls,
+        [
+            (true, Change::Started, secs(1)),
+            (false, Change::Stopped, secs(1)),
+            (false, Change::Stopped, secs(1)),
+            (true, Change::Resumed, secs(1)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn polls_when_the_track_ends_before_the_interval() {
+    let clock = ManualClock::default();
+    let mut paused = playing(190, Some(200));
+    paused.is_playing = false;
+    let script = [
+        Ok(playing(100, Some(200))),
+        Ok(playing(190, Some(200))),
+        Ok(paused),
+        Ok(playing(190, None)),
+    ];
+    let mut poller = poller(ScriptedSource(script.into()), secs(30), &clock);
+
+    let mut delays = Vec::new();
+    for _ in 0..4 {
+        poller.poll().await.unwrap();
+        delays.push(poller.delay());
+    }
+    assert_eq!(
+        delays,
+        [secs(30), secs(10) + TRACK_CHANGE_DELAY, secs(30), secs(30)]
+    );
+}
+
+#[tokio::test]
+async fn waits_for_the_clock_to_reach_the_next_poll() {
+    let clock = ManualClock::default();
+    let mut poller = poller(ScriptedSource(VecDeque::new()), secs(1), &clock);
+    assert!(poller.wait().now_or_never().is_some());
+
+    poller.poll().await.unwrap();
+    let mut wait = poller.wait();
+    clock.advance(Duration::from_millis(999));
+    assert!((&mut wait).now_or_never().is_none());
+    clock.advance(Duration::from_millis(1));
+    assert!(wait.now_or_never().is_some());
+}
+
+#[tokio::test]
+async fn follows_a_replayed_session_on_its_own_schedule() {
+    let clock = ManualClock::default();
+    let session = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/session.jsonl");
+    let source = ReplaySource::open(&session, clock.shared()).unwrap();
+    let mut poller = poller(source, secs(1), &clock);
+
+    let mut changes = Vec::new();
+    while clock.now() <= secs(10) {
+        poller.wait().await;
+        let (_, change) = poller.poll().await.unwrap();
+        changes.push(change);
+        clock.set(poller.next_poll());
+    }
+    assert_eq!(
+        changes,
+        [
+            Change::Stopped,
+            Change::Started,
+            Change::Unchanged,
+            Change::Paused,
+            Change::Unchanged,
+            Change::Resumed,
+            Change::Seeked { from: 12, to: 60 },
+            Change::Unchanged,
+            Change::Started,
+            Change::Stopped,
+            Change::Resumed,
+        ]
+    );
+}
@@ -0,0 +1,89 @@
+use spotify_music_vid::{
+    protocol::{ServerMessage, VideoKind, VideoPreferences},
+    Error, ErrorCode,
+};
+
+fn preferences(prefer: Option<VideoKind>, exclude: &[VideoKind]) -> VideoPreferences {
+    VideoPreferences {
+        prefer,
+        exclude: exclude.to_vec(),
+    }
+}
+
+#[test]
+fn kinds_round_trip_through_their_names() {
+    for kind in VideoKind::ALL {
+        assert_eq!(VideoKind::parse(kind.as_str()), Some(kind));
+    }
+    assert_eq!(VideoKind::parse("concert"), None);
+}
+
+#[test]
+fn normalizing_sorts_and_deduplicates_the_exclusions() {
+    let normalized = preferences(
+        Some(VideoKind::Lyrics),
+        &[VideoKind::Audio, VideoKind::MusicVideo, VideoKind::Audio],
+    )
+    .normalized()
+    .unwrap();
+    assert_eq!(
+        normalized,
+        preferences(
+            Some(VideoKind::Lyrics),
+            &[VideoKind::MusicVideo, VideoKind::Audio]
+        )
+    );
+}
+
+#[test]
+fn rejects_contradicting_preferences() {
+    let preferred_and_excluded =
+        preferences(Some(VideoKind::Audio), &[VideoKind::Audio]).normalized();
+    assert_eq!(
+        preferred_and_excluded.unwrap_err().code(),
+        ErrorCode::Protocol
+    );
+
+    let everything_excluded = preferences(None, &VideoKind::ALL).normalized();
+    assert_eq!(everything_excluded.unwrap_err().code(), ErrorCode::Protocol);
+}
+
+#[test]
+fn users_with_the_same_preferences_share_a_profile() {
+    assert_eq!(VideoPreferences::default().profile(), "default");
+    assert_eq!(
+        preferences(Some(VideoKind::Lyrics), &[]).profile(),
+        "lyrics"
+    );
+    assert_eq!(
+        preferences(None, &[VideoKind::Audio]).profile(),
+        "any-no-audio"
+    );
+    let normalized = |exclude: &[VideoKind]| {
+        preferences(Some(VideoKind::MusicVideo), exclude)
+            .normalized()
+            .unwrap()
+            .profile()
+    };
+    assert_eq!(
+        normalized(&[VideoKind::Audio, VideoKind::Lyrics]),
+        normalized(&[VideoKind::Lyrics, VideoKind::Audio, VideoKind::Lyrics])
+    );
+    assert_eq!(
+        normalized(&[VideoKind::Audio, VideoKind::Lyrics]),
+        "music_video-no-lyrics-no-audio"
+    );
+}
+
+#[test]
+fn database_errors_are_not_described_to_the_client() {
+    let err = Error::Storage(sqlx::Error::Protocol(
+        "relation \"preferences\" does not exist".to_string(),
+    ));
+    let message = serde_json::to_value(ServerMessage::error(&err)).unwrap();
+    assert_eq!(message["type"], "error");
+    assert_eq!(message["code"], "storage");
+    let text = message["message"].as_str().unwrap();
+    assert!(!text.contains("preferences"), "{text}");
+    assert!(err.to_string().contains("does not exist"));
+}
@@ -0,0 +1,127 @@
+use std::{path::PathBuf, time::Duration};
+
+use spotify_music_vid::{
+    clock::ManualClock,
+    replay::{read_recording, Recorder, ReplaySource},
+    source::PlaybackSource,
+    tracker::{Change, StateTracker},
+    Error,
+};
+
+/// A session polled every second: a track plays, pauses, resumes and is seeked,
+/// then another track plays and stops for a poll.
+fn session() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/session.jsonl")
+}
+
+#[tokio::test]
+async fn replays_track_changes_seeks_and_pauses() {
+    let clock = ManualClock::default();
+    let mut source = ReplaySource::open(&session(), clock.shared()).unwrap();
+    let times: Vec<_> = source
+        .snapshots()
+        .iter()
+        .map(|snapshot| Duration::from_millis(snapshot.at_ms))
+        .collect();
+    let mut tracker = StateTracker::default();
+
+    let mut changes = Vec::new();
+    for at in times {
+        clock.set(at);
+        let playback = match source.current().await {
+            Ok(playback) => Some(playback),
+            Err(Error::NothingPlaying) => None,
+            Err(e) => panic!("unexpected error: {e}"),
+        };
+        let title = playback.as_ref().map(|playback| playback.song.name.clone());
+        changes.push((tracker.observe(playback.as_ref(), at), title));
+    }
+
+    let around = Some("Around the World".to_string());
+    let one_more = Some("One More Time".to_string());
+    assert_eq!(
+        changes,
+        [
+            (Change::Stopped, None),
+            (Change::Started, around.clone()),
+            (Change::Unchanged, around.clone()),
+            (Change::Paused, around.clone()),
+            (Change::Unchanged, around.clone()),
+            (Change::Resumed, around.clone()),
+            (Change::Seeked { from: 12, to: 60 }, around.clone()),
+            (Change::Unchanged, around),
+            (Change::Started, one_more.clone()),
+            (Change::Stopped, None),
+            (Change::Resumed, one_more),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn replays_the_last_snapshot_before_the_clock() {
+    let clock = ManualClock::default();
+    let mut source = ReplaySource::open(&session(), clock.shared()).unwrap();
+
+    assert!(matches!(source.current().await, Err(Error::NothingPlaying)));
+    clock.advance(Duration::from_millis(6500));
+    let playback = source.current().await.unwrap();
+    assert_eq!(playback.song.name, "Around the World");
+    assert_eq!(playback.song.progress, 60);
+    assert_eq!(playback.song.duration, Some(429));
+    assert!(playback.is_playing);
+    clock.advance(Duration::from_secs(60));
+    let playback = source.current().await.unwrap();
+    assert_eq!(playback.song.name, "One More Time");
+    assert_eq!(playback.song.progress, 1);
+}
+
+#[tokio::test]
+async fn recordings_can_be_read_back() {
+    let snapshots = read_recording(&session()).unwrap();
+    let path = std::env::temp_dir().join(format!("recording-{}.jsonl", std::process::id()));
+    let clock = ManualClock::default();
+    clock.advance(Duration::from_secs(5));
+    let mut recorder = Recorder::create(&path, clock.shared()).await.unwrap();
+    for snapshot in &snapshots {
+        clock.set(Duration::from_secs(5) + Duration::from_millis(snapshot.at_ms));
+        recorder.record(snapshot.context.as_ref()).await.unwrap();
+    }
+    drop(recorder);
+
+    let recorded = read_recording(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(recorded.len(), snapshots.len());
+    for (recorded, snapshot) in recorded.iter().zip(&snapshots) {
+        assert_eq!(recorded.at_ms, snapshot.at_ms);
+        assert_eq!(recorded.context, snapshot.context);
+    }
+}
+
+#[test]
+fn invalid_recordings_report_the_line() {
+    let path = std::env::temp_dir().join(format!("invalid-{}.jsonl", std::process::id()));
+    std::fs::write(&path, "{\"at_ms\": 0, \"context\": null}\n\nnot json\n").unwrap();
+    let err = read_recording(&path).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(err, Error::Recording(_)));
+    assert!(err.to_string().contains("line 3"), "{err}");
+}
+
+#[tokio::test]
+async fn ads_are_reported_as_unsupported_items() {
+    let path = std::env::temp_dir().join(format!("ad-{}.jsonl", std::process::id()));
+    std::fs::write(
+        &path,
+        "{\"at_ms\": 0, \"context\": {\"context\": null, \"timestamp\": 1792339201000, \
+         \"progress_ms\": 5000, \"is_playing\": true, \"item\": null, \
+         \"currently_playing_type\": \"ad\", \"actions\": {\"disallows\": {}}}}\n",
+    )
+    .unwrap();
+    let mut source = ReplaySource::open(&path, ManualClock::default().shared()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(
+        source.current().await,
+        Err(Error::UnsupportedItem)
+    ));
+}
@@ -0,0 +1,109 @@
+//! Purging the cached songs of a blocked channel, against the database of the tests
+//! and a local stand-in of the youtube data api.
+
+#[allow(dead_code)]
+mod common;
+
+use std::{fs, process::Command};
+
+use common::FakeServer;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// Runs the binary against the database of the tests and `youtube`, returning what it printed.
+async fn run(youtube: &FakeServer, args: &[&str]) -> String {
+    let database_url =
+        std::env::var("DATABASE_URL").expect("DATABASE_URL must point to the test database");
+    let mut command = Command::new(env!("CARGO_BIN_EXE_spotify-music-vid"));
+    command
+        .args(args)
+        // away from a config.toml in the working directory
+        .current_dir(std::env::temp_dir())
+        .env_remove("CONFIG_FILE")
+        .env("DATABASE_URL", database_url)
+        .env("SPOTIFY_CLIENT_ID", "test-client")
+        .env("SPOTIFY_CLIENT_SECRET", "test-secret")
+        .env("YOUTUBE_API_KEY", "test-key")
+        .env("YOUTUBE__API_URL", youtube.url())
+        .env("RUST_LOG", "error");
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The response of the youtube `videos` endpoint with the snippet, videos as `(id, channel)`.
+fn video_channels(videos: &[(&str, &str)]) -> Value {
+    let items: Vec<Value> = videos
+        .iter()
+        .map(|(id, channel_id)| {
+            json!({
+                "id": id,
+                "contentDetails": { "duration": "PT3M" },
+                "snippet": { "description": "", "channelId": channel_id },
+            })
+        })
+        .collect();
+    json!({ "items": items })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn purging_a_channel_removes_the_songs_cached_without_their_channel() {
+    let youtube = FakeServer::start();
+    run(&youtube, &["migrate"]).await;
+
+    let artist = format!("Rules {}", Uuid::new_v4());
+    let channel = format!("UC{}", Uuid::new_v4().simple());
+    let blocked = format!("blocked-{}", Uuid::new_v4());
+    let kept = format!("kept-{}", Uuid::new_v4());
+    // exports of older versions have no channel
+    let entries = [("Reupload", &blocked), ("Official", &kept)]
+        .map(|(title, youtube_id)| {
+            json!({
+                "title": title,
+                "artist": artist,
+                "track_id": null,
+                "youtube_id": youtube_id,
+                "manual_override": false,
+            })
+            .to_string()
+        })
+        .join("\n");
+    let path = std::env::temp_dir().join(format!("rules-{}.jsonl", Uuid::new_v4()));
+    fs::write(&path, entries).unwrap();
+    run(&youtube, &["cache", "import", path.to_str().unwrap()]).await;
+    fs::remove_file(&path).unwrap();
+
+    youtube.reply(
+        "/videos",
+        200,
+        &video_channels(&[(&blocked, &channel), (&kept, "UCofficial")]),
+    );
+    let output = run(
+        &youtube,
+        &["rules", "block", "--channel", &channel, "--purge"],
+    )
+    .await;
+    assert!(output.contains("Purged 1 cached songs"), "{output}");
+    let lookup = &youtube.requests("/videos")[0];
+    assert!(common::has_param(
+        &lookup.query,
+        "part",
+        "contentDetails,snippet"
+    ));
+
+    let cached: Vec<String> = run(&youtube, &["cache", "export"])
+        .await
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .filter(|entry| entry["artist"] == artist.as_str())
+        .map(|entry| entry["youtube_id"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(cached, [kept]);
+}
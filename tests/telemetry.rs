@@ -0,0 +1,51 @@
+//! Export of the spans to a local stand-in of an OpenTelemetry collector.
+//! Only built with the `otel` feature: `cargo test --features otel`.
+#![cfg(feature = "otel")]
+
+#[allow(dead_code)]
+mod common;
+
+use std::process::Command;
+
+use common::{youtube_search, FakeServer};
+use serde_json::Value;
+
+const TRACES: &str = "/v1/traces";
+
+#[tokio::test(flavor = "multi_thread")]
+async fn exports_the_spans_of_a_command() {
+    let collector = FakeServer::start();
+    collector.reply(TRACES, 200, &Value::Null);
+    let youtube = FakeServer::start();
+    youtube.reply("/search", 200, &youtube_search(&[]));
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_spotify-music-vid"));
+    command
+        .args(["resolve", "Daft Punk - Around the World"])
+        // away from a config.toml in the working directory
+        .current_dir(std::env::temp_dir())
+        .env_remove("CONFIG_FILE")
+        .env_remove("DATABASE_URL")
+        .env("YOUTUBE_API_KEY", "otel-key")
+        .env("YOUTUBE__API_URL", youtube.url())
+        .env(
+            "TELEMETRY__OTLP_ENDPOINT",
+            format!("{}{TRACES}", collector.url()),
+        )
+        // the spans below the level are neither logged nor exported
+        .env("RUST_LOG", "info");
+    let output = tokio::task::spawn_blocking(move || command.output())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // the buffered spans are sent when the command exits
+    let exports = collector.requests(TRACES);
+    assert!(!exports.is_empty(), "no spans were exported");
+    assert!(exports.iter().all(|export| !export.body.is_empty()));
+}
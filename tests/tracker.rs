@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use spotify_music_vid::{
+    source::Playback,
+    tracker::{Change, StateTracker},
+    ItemKind, Song,
+};
+
+fn playing(name: &str, progress: i64) -> Playback {
+    Playback {
+        song: Song::new(name.to_string(), "Daft Punk".to_string(), progress),
+        is_playing: true,
+    }
+}
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+#[test]
+fn progress_drift_within_the_tolerance_is_not_a_seek() {
+    let mut tracker = StateTracker::new(secs(3));
+    assert_eq!(
+        tracker.observe(Some(&playing("Da Funk", 10)), secs(0)),
+        Change::Started
+    );
+    // the poll came late, the track kept playing
+    assert_eq!(
+        tracker.observe(Some(&playing("Da Funk", 17)), secs(5)),
+        Change::Unchanged
+    );
+    assert_eq!(
+        tracker.observe(Some(&playing("Da Funk", 22)), secs(6)),
+        Change::Seeked { from: 18, to: 22 }
+    );
+    assert_eq!(
+        tracker.observe(Some(&playing("Da Funk", 0)), secs(7)),
+        Change::Seeked { from: 23, to: 0 }
+    );
+}
+
+#[test]
+fn paused_items_are_not_expected_to_progress() {
+    let mut tracker = StateTracker::default();
+    let mut paused = playing("Da Funk", 30);
+    paused.is_playing = false;
+    tracker.observe(Some(&paused), secs(0));
+    assert_eq!(tracker.observe(Some(&paused), secs(60)), Change::Unchanged);
+    assert_eq!(
+        tracker.observe(Some(&playing("Da Funk", 30)), secs(61)),
+        Change::Resumed
+    );
+}
+
+#[test]
+fn reset_starts_the_item_again() {
+    let mut tracker = StateTracker::default();
+    tracker.observe(Some(&playing("Da Funk", 10)), secs(0));
+    tracker.reset();
+    assert_eq!(
+        tracker.observe(Some(&playing("Da Funk", 11)), secs(1)),
+        Change::Started
+    );
+}
+
+#[test]
+fn episodes_are_compared_by_id() {
+    let episode = |id: &str| {
+        let mut song = Song::new("Episode 1".to_string(), "A Show".to_string(), 0);
+        song.track_id = Some(id.to_string());
+        song.kind = ItemKind::Episode { duration: 3600 };
+        Playback {
+            song,
+            is_playing: true,
+        }
+    };
+    let mut tracker = StateTracker::default();
+    tracker.observe(Some(&episode("a")), secs(0));
+    assert_eq!(
+        tracker.observe(Some(&episode("a")), secs(1)),
+        Change::Unchanged
+    );
+    assert_eq!(
+        tracker.observe(Some(&episode("b")), secs(2)),
+        Change::Started
+    );
+}
@@ -0,0 +1,190 @@
+//! `cache import` against the database, under every conflict policy.
+
+use std::{fs, process::Command};
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// Runs the binary against the database of the tests, returning what it printed.
+fn run(args: &[&str]) -> String {
+    let database_url =
+        std::env::var("DATABASE_URL").expect("DATABASE_URL must point to the test database");
+    let output = Command::new(env!("CARGO_BIN_EXE_spotify-music-vid"))
+        .args(args)
+        // away from a config.toml in the working directory
+        .current_dir(std::env::temp_dir())
+        .env_remove("CONFIG_FILE")
+        .env("DATABASE_URL", database_url)
+        // the cache commands only need the database
+        .env_remove("SPOTIFY_CLIENT_ID")
+        .env_remove("SPOTIFY_CLIENT_SECRET")
+        .env_remove("YOUTUBE_API_KEY")
+        .env("RUST_LOG", "error")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{args:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A cache of its own for every test, the songs are by an artist no other test uses.
+struct Cache {
+    artist: String,
+}
+
+impl Cache {
+    fn new() -> Self {
+        run(&["migrate"]);
+        Self {
+            artist: format!("Import {}", Uuid::new_v4()),
+        }
+    }
+
+    fn entry(&self, title: &str, youtube_id: &str, manual_override: bool) -> Value {
+        json!({
+            "title": title,
+            "artist": self.artist,
+            "track_id": null,
+            "youtube_id": youtube_id,
+            "manual_override": manual_override,
+        })
+    }
+
+    /// Imports `entries` and returns the summary line.
+    fn import(&self, policy: &str, entries: &[Value]) -> String {
+        let path = std::env::temp_dir().join(format!("import-{}.jsonl", Uuid::new_v4()));
+        let lines: Vec<_> = entries.iter().map(Value::to_string).collect();
+        fs::write(&path, lines.join("\n")).unwrap();
+        let summary = run(&[
+            "cache",
+            "import",
+            path.to_str().unwrap(),
+            "--policy",
+            policy,
+        ]);
+        fs::remove_file(&path).unwrap();
+        summary.trim().to_string()
+    }
+
+    /// Returns the title and video of every cached song of the artist, ordered by title.
+    fn songs(&self) -> Vec<(String, String)> {
+        let mut songs: Vec<_> = run(&["cache", "export"])
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|entry| entry["artist"] == self.artist.as_str())
+            .map(|entry| {
+                (
+                    entry["title"].as_str().unwrap().to_string(),
+                    entry["youtube_id"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        songs.sort();
+        songs
+    }
+}
+
+fn song(title: &str, youtube_id: &str) -> (String, String) {
+    (title.to_string(), youtube_id.to_string())
+}
+
+#[test]
+fn skip_keeps_the_cached_songs() {
+    let cache = Cache::new();
+    let cached = [
+        cache.entry("One", "one-a", false),
+        cache.entry("Two", "two-a", true),
+    ];
+    assert_eq!(
+        cache.import("skip", &cached),
+        "Imported songs: 2 added, 0 updated, 0 skipped"
+    );
+
+    let incoming = [
+        cache.entry("One", "one-b", true),
+        cache.entry("Two", "two-b", false),
+    ];
+    assert_eq!(
+        cache.import("skip", &incoming),
+        "Imported songs: 0 added, 0 updated, 2 skipped"
+    );
+    assert_eq!(cache.songs(), [song("One", "one-a"), song("Two", "two-a")]);
+}
+
+#[test]
+fn overwrite_replaces_the_cached_songs() {
+    let cache = Cache::new();
+    cache.import(
+        "skip",
+        &[
+            cache.entry("One", "one-a", false),
+            cache.entry("Two", "two-a", true),
+        ],
+    );
+
+    let incoming = [
+        cache.entry("One", "one-b", false),
+        cache.entry("Two", "two-b", false),
+        cache.entry("Three", "three-b", false),
+    ];
+    assert_eq!(
+        cache.import("overwrite", &incoming),
+        "Imported songs: 1 added, 2 updated, 0 skipped"
+    );
+    assert_eq!(
+        cache.songs(),
+        [
+            song("One", "one-b"),
+            song("Three", "three-b"),
+            song("Two", "two-b")
+        ]
+    );
+}
+
+#[test]
+fn prefer_override_keeps_the_videos_picked_by_hand() {
+    let cache = Cache::new();
+    cache.import(
+        "skip",
+        &[
+            cache.entry("Searched", "searched-a", false),
+            cache.entry("Picked", "picked-a", true),
+            cache.entry("Picked twice", "twice-a", true),
+        ],
+    );
+
+    let incoming = [
+        cache.entry("Searched", "searched-b", false),
+        cache.entry("Picked", "picked-b", false),
+        cache.entry("Picked twice", "twice-b", true),
+    ];
+    assert_eq!(
+        cache.import("prefer-override", &incoming),
+        "Imported songs: 0 added, 2 updated, 1 skipped"
+    );
+    assert_eq!(
+        cache.songs(),
+        [
+            song("Picked", "picked-a"),
+            song("Picked twice", "twice-b"),
+            song("Searched", "searched-b")
+        ]
+    );
+}
+
+#[test]
+fn videos_of_other_regions_are_kept_alongside() {
+    let cache = Cache::new();
+    cache.import("skip", &[cache.entry("One", "one-a", false)]);
+
+    let mut regional = cache.entry("One", "one-de", false);
+    regional["region"] = json!("DE");
+    assert_eq!(
+        cache.import("overwrite", &[regional]),
+        "Imported songs: 1 added, 0 updated, 0 skipped"
+    );
+    assert_eq!(cache.songs(), [song("One", "one-a"), song("One", "one-de")]);
+}